@@ -1,8 +1,97 @@
-use std::{error::Error, fs, path::Path};
+use std::{error::Error, fs, io, path::PathBuf};
 
-use log::debug;
+use log::{debug, warn};
 
-use crate::config::{ZfsBackupConfig, ZfsBaseConfig};
+use crate::config::{ZfsBackupConfig, ZfsBackupConfigEntry, ZfsBaseConfig};
+use crate::key_template::{backup_type, BACKUP_TYPE_TAG};
+use crate::s3_utils::StorageClass;
+
+/// Comment describing the storage classes backups under a rule end up in, and when they need to be
+/// restored before they can be downloaded.
+fn storage_class_comment(config_entry: &ZfsBackupConfigEntry) -> String {
+    let mut comment = format!(
+        "          # Uploaded as {}",
+        config_entry.storage_class.to_string()
+    );
+    let final_class = match config_entry.transition() {
+        Some((storage_class, transition_after_days)) => {
+            comment.push_str(&format!(
+                ", moved to {} after {} days",
+                storage_class.to_string(),
+                transition_after_days
+            ));
+            storage_class
+        }
+        None => config_entry.storage_class,
+    };
+    if final_class.needs_restore() {
+        comment.push_str(&format!(
+            ". {} objects have to be restored with restore-object before they can be downloaded",
+            final_class.to_string()
+        ));
+    }
+    comment.push_str(".\n");
+    comment
+}
+
+/// Rule for the backups under `prefix`, and tagged with `type_tag` as `backup_type` if set.
+fn lifecycle_rule(
+    name: &str,
+    prefix: &str,
+    type_tag: Option<&str>,
+    config_entry: &ZfsBackupConfigEntry,
+) -> String {
+    if config_entry.storage_class == StorageClass::IntelligentTiering
+        && config_entry.transition_to.is_some()
+    {
+        warn!(
+            "Ignoring transition_to of {} backups, they're uploaded as INTELLIGENT_TIERING which S3 tiers itself",
+            type_tag.unwrap_or(prefix)
+        );
+    }
+    let expire_in_days = config_entry.expire_after_days();
+    let transition = config_entry.transition();
+    if expire_in_days.is_none() && transition.is_none() {
+        return String::new();
+    }
+    let id = if expire_in_days.is_some() {
+        "Delete"
+    } else {
+        "Transition"
+    };
+    let mut rule = storage_class_comment(config_entry);
+    rule.push_str(&format!("          - Id: {}{}\n", id, name));
+    if !prefix.is_empty() {
+        rule.push_str(&format!("            Prefix: '{}'\n", prefix));
+    }
+    if let Some(type_tag) = type_tag {
+        rule.push_str(&format!(
+            "            TagFilters:
+              - Key: {}
+                Value: {}
+",
+            BACKUP_TYPE_TAG, type_tag
+        ));
+    }
+    rule.push_str("            Status: Enabled\n");
+    if let Some(expire_in_days) = expire_in_days {
+        rule.push_str(&format!(
+            "            ExpirationInDays: {}\n",
+            expire_in_days
+        ));
+    }
+    if let Some((storage_class, transition_after_days)) = transition {
+        rule.push_str(&format!(
+            "            Transitions:
+              - StorageClass: {}
+                TransitionInDays: {}
+",
+            storage_class.to_string(),
+            transition_after_days
+        ));
+    }
+    rule
+}
 
 fn create_for_bucket(config_entry: &ZfsBackupConfig) -> String {
     let template = "  $RESOURCE:
@@ -15,49 +104,94 @@ fn create_for_bucket(config_entry: &ZfsBackupConfig) -> String {
         BlockPublicPolicy: true
         IgnorePublicAcls: true
         RestrictPublicBuckets: true
-      LifecycleConfiguration:
-        Rules:
-          - Id: DeleteFull
-            Prefix: 'full/'
-            Status: Enabled
-            ExpirationInDays: $EXPIRE_IN_DAYS_FULL
-          - Id: DeleteIncremental
-            Prefix: 'incremental/'
-            Status: Enabled
-            ExpirationInDays: $EXPIRE_IN_DAYS_INC
-          - Id: AbortIncompleteMultipartUpload
-            Status: Enabled
-            AbortIncompleteMultipartUpload:
-              DaysAfterInitiation: 7
-"
-    .to_string();
-    //@fixme : we currently don't support automatically moving to a different storage tier.
+$VERSIONING$LIFECYCLE_CONFIGURATION"
+        .to_string();
     let resource_name =
         titlecase::titlecase(&config_entry.bucket.replace("-", " ")).replace(" ", "");
     let template = template.replace("$BUCKET", &config_entry.bucket);
     let template = template.replace("$RESOURCE", &resource_name);
-    let template = template.replace(
-        "$EXPIRE_IN_DAYS_FULL",
-        &config_entry.full.expire_in_days.to_string(),
+    let key_prefix = config_entry.key_prefix();
+    let key_template = config_entry.key_template();
+    // With dates before `{type}` every type shares the prefix, the backups are told apart by tag.
+    let type_tag = |backup_type: &str| {
+        if key_template.needs_type_tag() {
+            Some(backup_type.to_string())
+        } else {
+            None
+        }
+    };
+    let full_type = backup_type(false, 0);
+    let mut lifecycle_rules = lifecycle_rule(
+        "Full",
+        &format!("{}{}", key_prefix, key_template.type_prefix(&full_type)),
+        type_tag(&full_type).as_deref(),
+        &config_entry.full,
     );
-    let template = template.replace(
-        "$EXPIRE_IN_DAYS_INC",
-        &config_entry.incremental.expire_in_days.to_string(),
-    );
-    template
+    for (tier, entry) in config_entry.incremental_tiers().into_iter().enumerate() {
+        let name = match tier {
+            0 => "Incremental".to_string(),
+            tier => format!("Incremental{}", tier),
+        };
+        let incremental_type = backup_type(true, tier);
+        lifecycle_rules.push_str(&lifecycle_rule(
+            &name,
+            &format!(
+                "{}{}",
+                key_prefix,
+                key_template.type_prefix(&incremental_type)
+            ),
+            type_tag(&incremental_type).as_deref(),
+            entry,
+        ));
+    }
+    if let Some(abort_after_days) = config_entry.abort_incomplete_uploads_after_days() {
+        lifecycle_rules.push_str(&format!(
+            "          - Id: AbortIncompleteMultipartUpload
+            Status: Enabled
+            AbortIncompleteMultipartUpload:
+              DaysAfterInitiation: {}
+",
+            abort_after_days
+        ));
+    }
+    let template = match config_entry.noncurrent_version_expire_after_days() {
+        Some(noncurrent_expire_in_days) => {
+            lifecycle_rules.push_str(&format!(
+                "          - Id: DeleteNoncurrentVersions
+            Status: Enabled
+            NoncurrentVersionExpirationInDays: {}
+",
+                noncurrent_expire_in_days
+            ));
+            template.replace(
+                "$VERSIONING",
+                "      VersioningConfiguration:\n        Status: Enabled\n",
+            )
+        }
+        None => template.replace("$VERSIONING", ""),
+    };
+    // CloudFormation rejects a lifecycle configuration without rules.
+    if lifecycle_rules.is_empty() {
+        template.replace("$LIFECYCLE_CONFIGURATION", "")
+    } else {
+        template.replace(
+            "$LIFECYCLE_CONFIGURATION",
+            &format!(
+                "      LifecycleConfiguration:\n        Rules:\n{}",
+                lifecycle_rules
+            ),
+        )
+    }
 }
 
-pub fn generate_cloudformation(config: &ZfsBaseConfig) -> Result<(), Box<dyn Error>> {
-    if Path::new("cloudformation_zfsbackup.yaml").exists() {
-        panic!("Cowardly not creating cloudformation_zfsbackup.yaml, as the file already exists");
-    }
+pub fn create_cloudformation(config: &ZfsBaseConfig) -> String {
     let mut cloudformation = "AWSTemplateFormatVersion: '2010-09-09'
 Description: ZFS backup config
 Resources:
 "
     .to_string();
-    for config in &config.configs {
-        cloudformation.push_str(&create_for_bucket(&config));
+    for config in config.unique_buckets() {
+        cloudformation.push_str(&create_for_bucket(config));
     }
     cloudformation.push_str(
         "  CustomUser:
@@ -71,26 +205,102 @@ Resources:
               - Effect: Allow
                 Action:
                   - s3:PutObject
+                  - s3:GetObject
+                  - s3:DeleteObject
                   - s3:GetObjectTagging
                   - s3:PutObjectTagging
                   - s3:ListBucket
                   - s3:AbortMultipartUpload
+                  - s3:ListBucketMultipartUploads
                   - s3:ListMultipartUploadParts
                 Resource:
 ",
     );
-    for config in &config.configs {
-      cloudformation.push_str(&format!(
-        "                  - !Join ['', ['arn:aws:s3:::', '{}' ]]\n",
-        &config.bucket
-    ));
+    for config in config.unique_buckets() {
+        cloudformation.push_str(&format!(
+            "                  - !Join ['', ['arn:aws:s3:::', '{}' ]]\n",
+            &config.bucket
+        ));
         cloudformation.push_str(&format!(
             "                  - !Join ['', ['arn:aws:s3:::', '{}/*' ]]\n",
             &config.bucket
         ));
     }
-    debug!("Writing cloudformation file...");
-    fs::write("cloudformation_zfsbackup.yaml", cloudformation)?;
-    println!("cloudformation_zfsbackup.yaml written");
+    let kms_keys = kms_key_arns(config);
+    if !kms_keys.is_empty() {
+        cloudformation.push_str(
+            "              - Effect: Allow
+                Action:
+                  - kms:GenerateDataKey
+                  - kms:Decrypt
+                Resource:
+",
+        );
+        for key in kms_keys {
+            cloudformation.push_str(&format!("                  - {}\n", key));
+        }
+    }
+    cloudformation
+}
+
+/// ARNs of the KMS keys configured for SSE, uploads need to generate data keys with them.
+///
+/// Configs without `sse_kms_key_id` use the AWS managed key, which needs no extra permissions.
+fn kms_key_arns(config: &ZfsBaseConfig) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for config in &config.configs {
+        let key_id = match &config.sse_kms_key_id {
+            Some(key_id) => key_id,
+            None => continue,
+        };
+        let arn = if key_id.starts_with("arn:") {
+            format!("'{}'", key_id)
+        } else {
+            if key_id.starts_with("alias/") {
+                warn!(
+                    "IAM policies can't refer to KMS keys by alias, use the key id or ARN instead of {}",
+                    key_id
+                );
+            }
+            let region = config.region.as_deref().unwrap_or("${AWS::Region}");
+            format!(
+                "!Sub 'arn:aws:kms:{}:${{AWS::AccountId}}:key/{}'",
+                region, key_id
+            )
+        };
+        if !result.contains(&arn) {
+            result.push(arn);
+        }
+    }
+    result
+}
+
+pub const DEFAULT_CLOUDFORMATION_PATH: &str = "cloudformation_zfsbackup.yaml";
+
+/// Writes the cloudformation template to `output`, or stdout when None.
+pub fn generate_cloudformation(
+    config: &ZfsBaseConfig,
+    output: Option<PathBuf>,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(config);
+    match output {
+        Some(path) => {
+            if path.exists() && !force {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "Cowardly not creating {}, as the file already exists (use --force to overwrite)",
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+            debug!("Writing cloudformation file...");
+            fs::write(&path, cloudformation)?;
+            println!("{} written", path.display());
+        }
+        None => print!("{}", cloudformation),
+    }
     Ok(())
 }
@@ -1,10 +1,23 @@
-use std::{error::Error, fmt, process::{Child, ChildStdout, Command, ExitStatus, Stdio}};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read};
 use std::str;
-use std::io::{self, Read};
+use std::thread::{self, JoinHandle};
+use std::{
+    error::Error,
+    fmt,
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
+};
+
+/// Lines of stderr kept to explain why a command failed.
+pub const STDERR_TAIL_LINES: usize = 10;
 
 pub trait CommandStreamActions<T: Read> {
     fn stdout(&mut self) -> T;
     fn wait(&mut self) -> io::Result<ExitStatus>;
+    /// The last lines the command wrote to stderr, call after `wait`.
+    fn stderr(&mut self) -> Option<String> {
+        None
+    }
 }
 
 impl CommandStreamActions<ChildStdout> for Child {
@@ -16,57 +29,137 @@ impl CommandStreamActions<ChildStdout> for Child {
     }
 }
 
+/// A running command with stdout piped, stderr is read on a thread so the command never blocks
+/// on a full stderr pipe while we're reading stdout.
+pub struct SpawnedCommand {
+    child: Child,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl SpawnedCommand {
+    fn new(mut child: Child) -> SpawnedCommand {
+        let stderr = child.stderr.take().map(|stderr| {
+            thread::spawn(move || {
+                let mut lines: VecDeque<String> = VecDeque::with_capacity(STDERR_TAIL_LINES);
+                for line in BufReader::new(stderr).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if lines.len() == STDERR_TAIL_LINES {
+                        lines.pop_front();
+                    }
+                    lines.push_back(line);
+                }
+                Vec::from(lines).join("\n")
+            })
+        });
+        SpawnedCommand { child, stderr }
+    }
+}
+
+impl CommandStreamActions<ChildStdout> for SpawnedCommand {
+    fn stdout(&mut self) -> ChildStdout {
+        self.child.stdout.take().unwrap()
+    }
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.child.wait()
+    }
+    fn stderr(&mut self) -> Option<String> {
+        self.stderr
+            .take()
+            .and_then(|stderr| stderr.join().ok())
+            .filter(|stderr| !stderr.is_empty())
+    }
+}
+
+/// Last `STDERR_TAIL_LINES` lines of `stderr`.
+pub fn stderr_tail(stderr: &str) -> String {
+    let lines: Vec<&str> = stderr.trim_end().lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
 #[derive(Debug)]
-struct ExecuteError(ExitStatus);
+struct ExecuteError(ExitStatus, String);
 impl fmt::Display for ExecuteError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Command exited with error code: {}", self.0)
+        if self.1.is_empty() {
+            write!(f, "Command exited with error code: {}", self.0)
+        } else {
+            write!(
+                f,
+                "Command exited with error code: {}, stderr:\n{}",
+                self.0, self.1
+            )
+        }
     }
 }
 impl Error for ExecuteError {}
 
+#[derive(Debug)]
+struct ParseCommandError(String);
+impl fmt::Display for ParseCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unable to parse command: {}", self.0)
+    }
+}
+impl Error for ParseCommandError {}
+
 pub struct ExecutorCommand(pub String);
 
 pub trait Executor {
     fn execute(&self) -> Result<String, Box<dyn Error>>;
     fn execute_by_line(&self) -> Result<Vec<String>, Box<dyn Error>>;
-    fn spawn(&self) -> Result<Child, Box<dyn Error>>;
+    fn spawn(&self) -> Result<SpawnedCommand, Box<dyn Error>>;
 }
 
 impl ExecutorCommand {
-    fn create_cmd(&self) -> Box<Command> {
-        let cmd:Vec<&str> = self.0.split(" ").collect();
-        let program = cmd[0];
-        let mut arguments = cmd.clone();
-        arguments.remove(0);
-        let mut command = Box::new(Command::new(program.to_string()));
-        command.args(arguments);
-        command
+    /// Splits the command into arguments with shell quoting rules, the command isn't run through a shell.
+    fn create_cmd(&self) -> Result<Box<Command>, Box<dyn Error>> {
+        let cmd = match shlex::split(&self.0) {
+            Some(cmd) if !cmd.is_empty() => cmd,
+            _ => return Err(Box::new(ParseCommandError(self.0.clone()))),
+        };
+        let mut command = Box::new(Command::new(&cmd[0]));
+        command.args(&cmd[1..]);
+        Ok(command)
     }
-}
 
+    /// The arguments the command is split into.
+    pub fn arguments(&self) -> Option<Vec<String>> {
+        shlex::split(&self.0)
+    }
+}
 
 impl Executor for ExecutorCommand {
     fn execute(&self) -> Result<String, Box<dyn Error>> {
-        let output = self.create_cmd().as_mut().output()?;
+        let output = self.create_cmd()?.as_mut().output()?;
         if output.status.success() {
             let content = str::from_utf8(&output.stdout)?;
             Ok(content.to_string())
         } else {
-            //could add let stderr = str::from_utf8(&self.stderr).unwrap(); here.
-            Err(Box::new(ExecuteError(output.status)))
+            let stderr = stderr_tail(&String::from_utf8_lossy(&output.stderr));
+            Err(Box::new(ExecuteError(output.status, stderr)))
         }
     }
 
     fn execute_by_line(&self) -> Result<Vec<String>, Box<dyn Error>> {
-        let result:Vec<String> = self.execute()?.split("\n")
-        .map(|x| x.trim().to_string())
-        .filter(|x| x.len() > 0)
-        .collect();
+        let result: Vec<String> = self
+            .execute()?
+            .split("\n")
+            .map(|x| x.trim().to_string())
+            .filter(|x| x.len() > 0)
+            .collect();
         Ok(result)
     }
 
-    fn spawn(&self) -> Result<Child, Box<dyn Error>> {
-        Ok(self.create_cmd().as_mut().stdout(Stdio::piped()).spawn()?)
+    fn spawn(&self) -> Result<SpawnedCommand, Box<dyn Error>> {
+        let child = self
+            .create_cmd()?
+            .as_mut()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        Ok(SpawnedCommand::new(child))
     }
 }
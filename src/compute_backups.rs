@@ -1,37 +1,94 @@
-use std::{collections::HashSet, fmt};
-use std::{error::Error, iter::FromIterator, process::Child};
+use std::error::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
-use crate::cmd_execute::Executor;
+use crate::cmd_execute::{Executor, SpawnedCommand};
 use crate::{
     cmd_execute::ExecutorCommand,
-    config::ZfsBackupConfig,
+    config::{IncrementalBase, ZfsBackupConfig, ZfsBackupConfigEntry},
+    key_template::{backup_type, KeyTemplate},
     s3_utils::{S3Key, StorageClass},
     zfs_utils::{LocalZfsState, ZfsSnapshot},
 };
-use chrono::{Duration, Local};
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
 use log::{debug, warn};
+use regex::Regex;
+use thiserror::Error;
 
 #[derive(Debug, Eq, PartialEq, Hash)]
 pub struct S3Backup {
     pub snapshot: ZfsSnapshot,
     pub parent: Option<String>,
+    /// Key of the backup of `parent`.
+    pub parent_key: Option<String>,
     pub storage_class: StorageClass,
     pub bucket: String,
+    pub zfs_command: String,
+    pub send_flags: String,
+    /// Prepended to the key, empty for none.
+    pub key_prefix: String,
+    pub key_template: KeyTemplate,
+    /// Incremental tier, 0 for `incremental` and for full backups.
+    pub tier: usize,
 }
 
 impl S3Backup {
+    /// `full`, `incremental` or `incremental-<tier>`.
+    pub fn backup_type(&self) -> String {
+        backup_type(self.parent.is_some(), self.tier)
+    }
+
     pub fn key(&self) -> String {
-        let mut key: String = match &self.parent {
-            Some(_) => "incremental/".to_string(),
-            None => "full/".to_string(),
-        };
-        key.push_str(&self.snapshot.name.replace("@", "_AT_"));
-        key
+        format!(
+            "{}{}",
+            self.key_prefix,
+            self.key_template
+                .render(&self.backup_type(), &self.snapshot)
+        )
+    }
+
+    /// Key used before `@` was percent-encoded, when it was replaced by `_AT_`.
+    pub fn legacy_key(&self) -> String {
+        format!(
+            "{}{}",
+            self.key_prefix,
+            self.key_template
+                .render_legacy(&self.backup_type(), &self.snapshot)
+        )
+    }
+
+    /// Whether `keys` holds this backup, under its current or legacy key.
+    pub fn is_in(&self, keys: &HashSet<&str>) -> bool {
+        keys.contains(self.key().as_str()) || keys.contains(self.legacy_key().as_str())
+    }
+
+    /// The remote object holding this backup, under its current or legacy key.
+    pub fn find_remote<'a>(&self, remote_by_key: &HashMap<&str, &'a S3Key>) -> Option<&'a S3Key> {
+        remote_by_key
+            .get(self.key().as_str())
+            .or_else(|| remote_by_key.get(self.legacy_key().as_str()))
+            .copied()
+    }
+
+    /// Storage class to upload with, glacier classes bill small objects as 128KB so those go to STANDARD.
+    pub fn upload_storage_class(&self, estimated_size: usize) -> StorageClass {
+        if estimated_size > 128000 {
+            self.storage_class
+        } else {
+            StorageClass::STANDARD
+        }
+    }
+
+    /// Reverses `key()` and `legacy_key()` of the default key template, see `KeyTemplate::parse`.
+    pub fn parse_key(key: &str) -> Option<(String, bool)> {
+        KeyTemplate::default().parse(key)
     }
 }
 pub trait S3BackupCommand {
     fn backup_cmd(&self, dryrun: bool) -> String;
-    fn backup(&self, dryrun: bool) -> Result<Child, Box<dyn Error>>;
+    fn backup(&self, dryrun: bool) -> Result<SpawnedCommand, Box<dyn Error>>;
     fn get_estimated_size(&self) -> Result<usize, Box<dyn Error>>;
 }
 
@@ -40,32 +97,61 @@ impl S3BackupCommand for S3Backup {
         let dryrun_char = if dryrun { "vn" } else { "" };
         match &self.parent {
             Some(parent) => format!(
-                "zfs send -Pw{} -i {} {}",
-                dryrun_char, parent, self.snapshot.name
+                "{} send -P{}{} -i {} {}",
+                self.zfs_command, self.send_flags, dryrun_char, parent, self.snapshot.name
+            ),
+            None => format!(
+                "{} send -P{}{} {}",
+                self.zfs_command, self.send_flags, dryrun_char, self.snapshot.name
             ),
-            None => format!("zfs send -Pw{} {}", dryrun_char, self.snapshot.name),
         }
     }
-    fn backup(&self, dryrun: bool) -> Result<Child, Box<dyn Error>> {
+    fn backup(&self, dryrun: bool) -> Result<SpawnedCommand, Box<dyn Error>> {
         Ok(ExecutorCommand(self.backup_cmd(dryrun)).spawn()?)
     }
     fn get_estimated_size(&self) -> Result<usize, Box<dyn Error>> {
-        let estimated_size = ExecutorCommand(self.backup_cmd(true))
-            .execute()?
-            .split("\t")
-            .last()
-            .expect(&format!(
-                "Failed to get estimated size with {}",
-                self.backup_cmd(true)
-            ))
-            .to_string();
-        Ok(estimated_size.trim().parse::<usize>().expect(&format!(
-            "Failed to parse estimated size : '{}'",
-            estimated_size
-        )))
+        let output = ExecutorCommand(self.backup_cmd(true)).execute()?;
+        parse_estimated_size(&output)
+            .map_err(|err| format!("{} (running '{}')", err, self.backup_cmd(true)).into())
     }
 }
 
+#[derive(Error, Debug, PartialEq)]
+#[error("Unable to find the size in zfs send output '{output}'")]
+pub struct EstimatedSizeError {
+    pub output: String,
+}
+
+/// Whether the `creation_date` tag of an uploaded backup is the creation time of `snapshot`. A
+/// different time means the snapshot was destroyed and recreated with the same name since. Backups
+/// without a readable tag are assumed to match.
+pub fn creation_date_matches(creation_date: Option<&str>, snapshot: &ZfsSnapshot) -> bool {
+    match creation_date.and_then(|date| DateTime::parse_from_rfc3339(date).ok()) {
+        Some(creation_date) => creation_date == snapshot.creation,
+        None => true,
+    }
+}
+
+/// Remote objects smaller than this fraction of the estimated size are assumed to be truncated.
+const MIN_REMOTE_SIZE_FRACTION: f64 = 0.5;
+
+/// Whether an uploaded backup of `remote_size` bytes is implausibly small for a send estimated at
+/// `estimated_size` bytes. Estimates aren't exact, so only a large difference counts.
+pub fn is_truncated(remote_size: i64, estimated_size: usize) -> bool {
+    (remote_size as f64) < estimated_size as f64 * MIN_REMOTE_SIZE_FRACTION
+}
+
+/// Parses the `size<TAB>bytes` line of `zfs send -nvP` output.
+pub fn parse_estimated_size(output: &str) -> Result<usize, EstimatedSizeError> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("size\t"))
+        .and_then(|size| size.trim().parse::<usize>().ok())
+        .ok_or_else(|| EstimatedSizeError {
+            output: output.to_string(),
+        })
+}
+
 impl fmt::Display for S3Backup {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -77,28 +163,100 @@ impl fmt::Display for S3Backup {
     }
 }
 
+/// What the keys of a config's backups are built from, compiled once per `get_pending_actions`.
+struct BackupKeys<'a> {
+    /// `snapshot_regex` of every incremental tier.
+    tier_regexes: &'a [Regex],
+    key_template: &'a KeyTemplate,
+}
+
+/// Key of the backup of `parent`, in the first tier its name matches like in
+/// `get_pending_actions`. The backup of a bookmark is the one of the snapshot with the same name.
+fn parent_key(parent: &ZfsSnapshot, config: &ZfsBackupConfig, keys: &BackupKeys) -> String {
+    let parent = ZfsSnapshot {
+        name: parent.name.replacen('#', "@", 1),
+        creation: parent.creation,
+    };
+    let backup_type = match keys
+        .tier_regexes
+        .iter()
+        .position(|regex| regex.is_match(&parent.name))
+    {
+        Some(tier) => backup_type(true, tier),
+        None => backup_type(false, 0),
+    };
+    format!(
+        "{}{}",
+        config.key_prefix(),
+        keys.key_template.render(&backup_type, &parent)
+    )
+}
+
+/// Whether sending `snapshot` with `entry` sends encrypted data, zfs only sends that with `-w` or
+/// decrypted. Recursive sends include the encrypted children of the dataset.
+fn sends_encrypted(
+    snapshot: &ZfsSnapshot,
+    entry: &ZfsBackupConfigEntry,
+    local_state: &LocalZfsState,
+) -> bool {
+    let dataset = snapshot.name.split('@').next().unwrap_or_default();
+    let children = format!("{}/", dataset);
+    local_state
+        .encrypted
+        .iter()
+        .any(|x| x == dataset || (entry.recursive && x.starts_with(&children)))
+}
+
 trait S3BackupActions {
-    fn new(name: &ZfsSnapshot, parent: Option<&ZfsSnapshot>, config: &ZfsBackupConfig) -> S3Backup;
+    fn new(
+        name: &ZfsSnapshot,
+        parent: Option<&ZfsSnapshot>,
+        tier: usize,
+        config: &ZfsBackupConfig,
+        keys: &BackupKeys,
+        local_state: &LocalZfsState,
+    ) -> S3Backup;
 }
 impl S3BackupActions for S3Backup {
     fn new(
         snapshot: &ZfsSnapshot,
         parent: Option<&ZfsSnapshot>,
+        tier: usize,
         config: &ZfsBackupConfig,
+        keys: &BackupKeys,
+        local_state: &LocalZfsState,
     ) -> S3Backup {
-        let storage_class = {
+        let config_entry = {
             if parent.is_some() {
-                config.incremental.storage_class
+                config.incremental_tiers()[tier]
             } else {
-                config.full.storage_class
+                &config.full
             }
         };
+        let mut send_flags = config_entry.send_flags();
+        if !send_flags.contains('w') && sends_encrypted(snapshot, config_entry, local_state) {
+            debug!(
+                "    {} has encrypted data, sending it with -w although the config doesn't",
+                snapshot.name
+            );
+            send_flags.insert(0, 'w');
+        }
 
         S3Backup {
             snapshot: snapshot.to_owned(),
             parent: parent.map(|x| x.name.to_owned()),
-            storage_class: storage_class,
-            bucket: config.bucket.to_owned()
+            parent_key: parent.map(|x| parent_key(x, config, keys)),
+            storage_class: config_entry.storage_class,
+            bucket: config.bucket.to_owned(),
+            zfs_command: local_state.zfs_command.to_owned(),
+            send_flags: if config_entry.recursive {
+                format!("{}R", send_flags)
+            } else {
+                send_flags
+            },
+            key_prefix: config.key_prefix().to_owned(),
+            key_template: keys.key_template.clone(),
+            tier,
         }
     }
 }
@@ -109,57 +267,419 @@ pub trait FilterExistingFiles {
 
 impl FilterExistingFiles for Vec<S3Backup> {
     fn filter_existing_backups(self, existing: &HashSet<S3Key>) -> Vec<S3Backup> {
-        let existing_keys: HashSet<String> =
-            HashSet::from_iter(existing.into_iter().map(|x| x.key.clone()));
+        let existing_keys: HashSet<&str> = existing.iter().map(|x| x.key.as_str()).collect();
         self.into_iter()
-            .filter(|x| !existing_keys.contains(&x.key()))
+            .filter(|x| !x.is_in(&existing_keys))
             .collect()
     }
 }
 
+/// Time since `snapshot` was created. Snapshots created after `now`, because of clock skew or a
+/// dataset received from a machine with a clock ahead of this one, count as just created.
+pub fn snapshot_age(snapshot: &ZfsSnapshot, now: DateTime<Local>) -> Duration {
+    let age = now.signed_duration_since(snapshot.creation);
+    if age < Duration::zero() {
+        warn!(
+            "{} was created {}s in the future, check the clock of this machine. Treating it as just created",
+            snapshot.name,
+            -age.num_seconds()
+        );
+        return Duration::zero();
+    }
+    age
+}
+
+fn is_expired(snapshot: &ZfsSnapshot, config_entry: &ZfsBackupConfigEntry) -> bool {
+    match config_entry.expire_after_days() {
+        Some(expire_in_days) => {
+            snapshot_age(snapshot, Local::now()) > Duration::days(expire_in_days + 1)
+        }
+        None => false,
+    }
+}
+
+/// Snapshots and the bookmarks of snapshots that no longer exist, oldest first. True marks bookmarks.
+fn with_bookmarks<'a>(
+    snapshots: &'a [ZfsSnapshot],
+    bookmarks: &'a [ZfsSnapshot],
+) -> Vec<(&'a ZfsSnapshot, bool)> {
+    let snapshot_names: HashSet<&str> = snapshots
+        .iter()
+        .filter_map(|x| x.name.split_once('@').map(|(_, name)| name))
+        .collect();
+    let mut result: Vec<(&ZfsSnapshot, bool)> = snapshots.iter().map(|x| (x, false)).collect();
+    result.extend(
+        bookmarks
+            .iter()
+            .filter(|x| match x.name.split_once('#') {
+                Some((_, name)) => !snapshot_names.contains(name),
+                None => false,
+            })
+            .map(|x| (x, true)),
+    );
+    result.sort_by_key(|(x, _)| x.creation);
+    result
+}
+
+fn has_selected_ancestor(pool: &str, selected_pools: &HashSet<&str>) -> bool {
+    let mut dataset = pool;
+    while let Some((parent, _)) = dataset.rsplit_once('/') {
+        if selected_pools.contains(parent) {
+            return true;
+        }
+        dataset = parent;
+    }
+    false
+}
+
+/// Datasets matched by `pool_regex` and not by `exclude_regex`.
+pub fn get_selected_pools<'a>(
+    local_state: &'a LocalZfsState,
+    config: &ZfsBackupConfig,
+) -> HashSet<&'a str> {
+    let pool_regex = config.pool_regex_re();
+    let exclude_regex = config.exclude_regex_re();
+    if !local_state
+        .pools
+        .keys()
+        .any(|pool| pool_regex.is_match(pool))
+    {
+        warn!(
+            "pool_regex '{}' doesn't match any dataset",
+            config.pool_regex
+        );
+    }
+    local_state
+        .pools
+        .keys()
+        .filter(|pool| {
+            if !pool_regex.is_match(pool) {
+                return false;
+            }
+            if exclude_regex.as_ref().is_some_and(|re| re.is_match(pool)) {
+                debug!("Pool '{}' is excluded", pool);
+                return false;
+            }
+            true
+        })
+        .map(|pool| pool.as_str())
+        .collect()
+}
+
+/// Selected datasets that get backups of their own, sorted. Children of a selected dataset are
+/// left out when full and all incremental backups are recursive, their parent's stream has them.
+pub fn get_backed_up_datasets<'a>(
+    local_state: &'a LocalZfsState,
+    config: &ZfsBackupConfig,
+) -> Vec<&'a str> {
+    let selected_pools = get_selected_pools(local_state, config);
+    let mut result: Vec<&str> = selected_pools
+        .iter()
+        .filter(|pool| {
+            !(config.full.recursive
+                && config
+                    .incremental_tiers()
+                    .iter()
+                    .all(|entry| entry.recursive)
+                && has_selected_ancestor(pool, &selected_pools))
+        })
+        .copied()
+        .collect();
+    result.sort_unstable();
+    result
+}
+
+/// Whether a full or `tier` incremental backup of `snapshot` is in `remote_keys`. Bookmarks count
+/// as backed up when their snapshot is.
+fn is_backed_up(
+    snapshot: &ZfsSnapshot,
+    tier: usize,
+    config: &ZfsBackupConfig,
+    keys: &BackupKeys,
+    local_state: &LocalZfsState,
+    remote_keys: &HashSet<&str>,
+) -> bool {
+    let snapshot = ZfsSnapshot {
+        name: snapshot.name.replacen('#', "@", 1),
+        creation: snapshot.creation,
+    };
+    let mut backup = S3Backup::new(&snapshot, None, tier, config, keys, local_state);
+    if backup.is_in(remote_keys) {
+        return true;
+    }
+    backup.parent = Some(String::new());
+    backup.is_in(remote_keys)
+}
+
+/// Makes `snapshot` the remote base of `level` and the levels below it, the newest incrementals
+/// before it no longer need a backup.
+fn set_remote_base<'a>(
+    remote_bases: &mut [Option<&'a ZfsSnapshot>],
+    newest_incrementals: &mut [Option<&'a ZfsSnapshot>],
+    level: usize,
+    snapshot: &'a ZfsSnapshot,
+) {
+    for base in &mut remote_bases[level..] {
+        *base = Some(snapshot);
+    }
+    for newest in &mut newest_incrementals[level..] {
+        *newest = None;
+    }
+}
+
 pub fn get_pending_actions(local_state: &LocalZfsState, config: &ZfsBackupConfig) -> Vec<S3Backup> {
+    get_pending_actions_since(local_state, config, None)
+}
+
+/// Like `get_pending_actions`, also skipping snapshots created before `since`. Incrementals are only
+/// sent against snapshots at or after `since`, those before the first full one are skipped.
+pub fn get_pending_actions_since(
+    local_state: &LocalZfsState,
+    config: &ZfsBackupConfig,
+    since: Option<DateTime<Local>>,
+) -> Vec<S3Backup> {
+    get_pending_actions_with_remote(local_state, config, since, &HashSet::new())
+}
+
+/// Like `get_pending_actions_since`, with `remote_keys` the keys already in the bucket.
+///
+/// Snapshots matching `full` are backed up in full. Those matching `incremental` are sent against
+/// the newest full or incremental snapshot, those of `incremental_tiers` against the newest
+/// snapshot of a tier above their own. Snapshots matching several tiers belong to the first
+/// incremental tier they match.
+///
+/// With `incremental_base: LastRemote` only the newest snapshot of each incremental tier is backed
+/// up, sent against the newest snapshot (or bookmark) of its tier or above that is in `remote_keys`,
+/// has a full backup pending or is the newest one of a tier above.
+///
+/// Snapshots skipped for being before `since` are only the parent of the incrementals after it when
+/// they are in `remote_keys`, an incremental against a backup that doesn't exist can't be restored.
+pub fn get_pending_actions_with_remote(
+    local_state: &LocalZfsState,
+    config: &ZfsBackupConfig,
+    since: Option<DateTime<Local>>,
+    remote_keys: &HashSet<&str>,
+) -> Vec<S3Backup> {
+    let last_remote = config.incremental_base == IncrementalBase::LastRemote;
+    let before_since =
+        |snapshot: &ZfsSnapshot| since.is_some_and(|since| snapshot.creation < since);
     let mut pending_backups: Vec<S3Backup> = Vec::new();
-    for pool in local_state.pools.keys() {
-        if !config.pool_regex_re().is_match(pool) {
+    let tiers = config.incremental_tiers();
+    let tier_regexes: Vec<Regex> = tiers
+        .iter()
+        .map(|entry| entry.snapshot_regex_re())
+        .collect();
+    let full_regex = config.full.snapshot_regex_re();
+    let keys = BackupKeys {
+        tier_regexes: &tier_regexes,
+        key_template: config.key_template(),
+    };
+    // Levels order the tiers from the top, 0 for full and tier + 1 for the incremental tiers.
+    let levels = tiers.len() + 1;
+    let level_of = |name: &str| match tier_regexes.iter().position(|regex| regex.is_match(name)) {
+        Some(tier) => Some(tier + 1),
+        None if full_regex.is_match(name) => Some(0),
+        None => None,
+    };
+    let selected_pools = get_selected_pools(local_state, config);
+    let mut pools: Vec<&String> = local_state.pools.keys().collect();
+    pools.sort();
+    for pool in pools {
+        if !selected_pools.contains(pool.as_str()) {
             continue;
         }
         debug!("Pool '{}' is active", pool);
+        // Recursive sends of a selected parent dataset already contain this one.
+        let in_parent_stream = has_selected_ancestor(pool, &selected_pools);
         let snapshots = local_state.pools.get(pool).unwrap();
-        let mut last_entry: Option<&ZfsSnapshot> = None;
-        for snapshot in snapshots {
-            if config
-                .incremental
-                .snapshot_regex_re()
-                .is_match(&snapshot.name)
-            {
-                if last_entry.is_none() {
-                    warn!(
-                        "\tWARN : can't incremental snapshot {}, no parent available",
-                        snapshot
-                    )
-                } else {
-                    if Local::now().signed_duration_since(snapshot.creation)
-                        > Duration::days(config.incremental.expire_in_days + 1)
-                    {
-                        debug!("    snapshot incremental {} - skipped, too old", snapshot);
-                    } else {
-                        debug!("    snapshot incremental {}", snapshot);
-                        pending_backups.push(S3Backup::new(snapshot, last_entry, config));
+        let bookmarks = match local_state.bookmarks.get(pool) {
+            Some(bookmarks) if config.use_bookmarks => bookmarks.as_slice(),
+            _ => &[],
+        };
+        // Per level, the newest snapshot of that level or above.
+        let mut last_entries: Vec<Option<&ZfsSnapshot>> = vec![None; levels];
+        // With LastRemote, per level the newest backed up snapshot of that level or above, and the
+        // newest snapshot of the level after it.
+        let mut remote_bases: Vec<Option<&ZfsSnapshot>> = vec![None; levels];
+        let mut newest_incrementals: Vec<Option<&ZfsSnapshot>> = vec![None; levels];
+        let mut without_parent: Vec<&ZfsSnapshot> = Vec::new();
+        for (snapshot, is_bookmark) in with_bookmarks(snapshots, bookmarks) {
+            let level = match level_of(&snapshot.name.replacen('#', "@", 1)) {
+                Some(level) => level,
+                None => continue,
+            };
+            let tier = level.saturating_sub(1);
+            let backed_up = last_remote
+                && is_backed_up(snapshot, tier, config, &keys, local_state, remote_keys);
+            if backed_up {
+                set_remote_base(&mut remote_bases, &mut newest_incrementals, level, snapshot);
+            }
+            // `incremental` chains off its own snapshots, the tiers below it off the tiers above.
+            let parent = last_entries[if level > 1 { level - 1 } else { level }];
+            let can_be_parent = !before_since(snapshot)
+                || is_backed_up(snapshot, tier, config, &keys, local_state, remote_keys);
+            if can_be_parent && (is_bookmark || level == 0 || parent.is_some()) {
+                for entry in &mut last_entries[level..] {
+                    *entry = Some(snapshot);
+                }
+            }
+            if is_bookmark {
+                // Bookmarks are never uploaded, but can be the parent of the next incremental.
+                continue;
+            }
+            let (kind, entry) = match level {
+                0 => ("full", &config.full),
+                _ => ("incremental", tiers[tier]),
+            };
+            let skip_reason = if is_expired(snapshot, entry) {
+                Some("skipped, too old")
+            } else if before_since(snapshot) {
+                Some("skipped, before --since")
+            } else if entry.recursive && in_parent_stream {
+                Some("part of a recursive parent")
+            } else {
+                None
+            };
+            if level == 0 {
+                match skip_reason {
+                    Some(reason) => debug!("    snapshot full {} - {}", snapshot, reason),
+                    None => {
+                        debug!("    snapshot full {}", snapshot);
+                        pending_backups.push(S3Backup::new(
+                            snapshot,
+                            None,
+                            0,
+                            config,
+                            &keys,
+                            local_state,
+                        ));
+                        set_remote_base(
+                            &mut remote_bases,
+                            &mut newest_incrementals,
+                            level,
+                            snapshot,
+                        );
                     }
-                    last_entry = Some(&snapshot);
                 }
-            } else if config.full.snapshot_regex_re().is_match(&snapshot.name) {
-                if Local::now().signed_duration_since(snapshot.creation)
-                    > Duration::days(config.full.expire_in_days + 1)
-                {
-                    debug!("    snapshot full {} - skipped, too old", snapshot);
-                } else {
-                    debug!("    snapshot full {}", snapshot);
-                    pending_backups.push(S3Backup::new(snapshot, None, config));
+            } else if last_remote {
+                if backed_up {
+                    continue;
+                }
+                match skip_reason {
+                    Some(reason) => debug!("    snapshot {} {} - {}", kind, snapshot, reason),
+                    None => {
+                        if let Some(skipped) = newest_incrementals[level].replace(snapshot) {
+                            debug!(
+                                "    snapshot {} {} - skipped, not the newest",
+                                kind, skipped
+                            );
+                        }
+                    }
+                }
+            } else if parent.is_none() {
+                if !is_expired(snapshot, entry) && !before_since(snapshot) {
+                    debug!("    snapshot {} {} - skipped, no parent", kind, snapshot);
+                    without_parent.push(snapshot);
+                }
+            } else {
+                match skip_reason {
+                    Some(reason) => debug!("    snapshot {} {} - {}", kind, snapshot, reason),
+                    None => {
+                        debug!("    snapshot {} {}", kind, snapshot);
+                        pending_backups.push(S3Backup::new(
+                            snapshot,
+                            parent,
+                            tier,
+                            config,
+                            &keys,
+                            local_state,
+                        ));
+                    }
                 }
-                last_entry = Some(&snapshot);
             }
         }
+        // The newest snapshot of a tier is superseded by a newer one of a tier above it.
+        let mut chosen: Vec<&ZfsSnapshot> = Vec::new();
+        for level in 1..levels {
+            let snapshot = match newest_incrementals[level] {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            if chosen.iter().any(|x| x.creation > snapshot.creation) {
+                debug!(
+                    "    snapshot incremental {} - skipped, a tier above has a newer one",
+                    snapshot
+                );
+                continue;
+            }
+            let base = chosen
+                .iter()
+                .copied()
+                .chain(remote_bases[level])
+                .max_by_key(|x| x.creation);
+            match base {
+                Some(base) => {
+                    debug!("    snapshot incremental {} - against {}", snapshot, base);
+                    pending_backups.push(S3Backup::new(
+                        snapshot,
+                        Some(base),
+                        level - 1,
+                        config,
+                        &keys,
+                        local_state,
+                    ));
+                    chosen.push(snapshot);
+                }
+                None => without_parent.push(snapshot),
+            }
+        }
+        if let Some(first) = without_parent.first() {
+            warn!(
+                "Skipping {} incremental snapshot(s) of {} starting at {}, there is no earlier full snapshot to send them against. Take a snapshot matching '{}' to start backing up this dataset.",
+                without_parent.len(),
+                pool,
+                first.name,
+                config.full.snapshot_regex
+            );
+        }
     }
     pending_backups
 }
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid --since '{0}', expected a date (2021-01-31), a RFC 3339 time or a duration like 30d, 12h or 2w")]
+pub struct InvalidSinceError(pub String);
+
+/// Parses an absolute date or time, or a duration (`30d`, `12h`, `2w`) before `now`.
+pub fn parse_since(
+    value: &str,
+    now: DateTime<Local>,
+) -> Result<DateTime<Local>, InvalidSinceError> {
+    let error = || InvalidSinceError(value.to_string());
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Local));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Local
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0).ok_or_else(error)?)
+            .earliest()
+            .ok_or_else(error);
+    }
+    if value.len() < 2 || !value.is_char_boundary(value.len() - 1) {
+        return Err(error());
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: i64 = amount
+        .parse()
+        .ok()
+        .filter(|amount| *amount >= 0)
+        .ok_or_else(error)?;
+    let duration = match unit {
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        "w" => Duration::weeks(amount),
+        _ => return Err(error()),
+    };
+    Ok(now - duration)
+}
@@ -1,48 +1,646 @@
-use std::{error::Error, fs, path::Path};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use crate::key_template::{KeyTemplate, BACKUP_TYPE_TAG};
 use crate::s3_utils;
-use log::debug;
+use crate::{pricing::PricingConfig, zfs_utils::DEFAULT_ZFS_COMMAND};
+use log::{debug, warn};
 use regex::Regex;
-use s3_utils::StorageClass;
+use rusoto_core::{region::ParseRegionError, Region};
+use rusoto_s3::Tag;
+use s3_utils::{
+    StorageClass, UploadOptions, BACKUP_TAG_KEYS, DEFAULT_MAX_RETRIES, MAX_TAGS,
+    MAX_TAG_KEY_LENGTH, MAX_TAG_VALUE_LENGTH,
+};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid regex '{pattern}' in {field}: {source}")]
+    InvalidRegex {
+        field: String,
+        pattern: String,
+        source: regex::Error,
+    },
+    #[error("configs[{first}] and configs[{second}] both use bucket {bucket}, but with different {setting}")]
+    ConflictingBucket {
+        bucket: String,
+        first: usize,
+        second: usize,
+        setting: &'static str,
+    },
+    #[error("Invalid {field}: {reason}")]
+    InvalidValue { field: String, reason: &'static str },
+    #[error("Failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+}
+
+const DEFAULT_NONCURRENT_VERSION_EXPIRE_IN_DAYS: i64 = 30;
+const DEFAULT_ABORT_INCOMPLETE_UPLOADS_AFTER_DAYS: i64 = 7;
+/// Raw sends, so encrypted datasets stay encrypted.
+const DEFAULT_SEND_FLAGS: &str = "w";
+const DEFAULT_HTTP_READ_BUF_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_HTTP_POOL_IDLE_SECS: u64 = 5;
+
+fn validate_regex(field: String, pattern: &str) -> Result<(), ConfigError> {
+    match Regex::new(pattern) {
+        Ok(_) => Ok(()),
+        Err(source) => Err(ConfigError::InvalidRegex {
+            field,
+            pattern: pattern.to_string(),
+            source,
+        }),
+    }
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ZfsBackupConfigEntry {
     pub snapshot_regex: String,
     pub storage_class: StorageClass,
-    pub expire_in_days: i64
+    pub expire_in_days: i64,
+    pub transition_to: Option<StorageClass>,
+    pub transition_after_days: Option<i64>,
+    pub send_flags: Option<String>,
+    /// Send with `-w`, so encrypted datasets stay encrypted but the receiving side can't change
+    /// compression or other properties. Unset, `send_flags` decides, and those include `w` by
+    /// default.
+    pub raw: Option<bool>,
+    #[serde(default)]
+    pub recursive: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ZfsBackupConfig {
     pub pool_regex: String,
+    pub exclude_regex: Option<String>,
     pub incremental: ZfsBackupConfigEntry,
+    /// Tiers of incrementals below `incremental`, for example dailies below weeklies. Snapshots are
+    /// sent against the newest snapshot of their own tier or a tier above.
+    #[serde(default)]
+    pub incremental_tiers: Vec<ZfsBackupConfigEntry>,
     pub full: ZfsBackupConfigEntry,
     pub bucket: String,
+    pub region: Option<String>,
+    /// URL of an S3 compatible service to use instead of AWS.
+    pub endpoint: Option<String>,
+    /// Prepended to every key, to share a bucket between hosts, e.g. `host1/`.
+    pub key_prefix: Option<String>,
+    /// Layout of keys below `key_prefix`, e.g. `{type}/{year}/{month}/{name}`. Defaults to
+    /// `{type}/{name}`.
+    pub key_template: Option<String>,
+    /// `key_template` parsed when the config is loaded.
+    #[serde(skip)]
+    pub parsed_key_template: KeyTemplate,
+    #[serde(default)]
+    pub resume_uploads: bool,
+    pub part_size_mb: Option<usize>,
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    #[serde(default)]
+    pub versioning: bool,
+    #[serde(default)]
+    pub use_bookmarks: bool,
+    /// Upload backups again when the snapshot was recreated since, costs a tag request per backup.
+    #[serde(default)]
+    pub verify_creation_date: bool,
+    #[serde(default)]
+    pub incremental_base: IncrementalBase,
+    pub noncurrent_version_expire_in_days: Option<i64>,
+    /// Days before unfinished multipart uploads are removed by the lifecycle rule, <= 0 to keep them.
+    pub abort_incomplete_uploads_after_days: Option<i64>,
+    /// Added to the tags of every backup, e.g. `CostCenter: backups` for cost allocation.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ZfsBaseConfig {
     pub configs: Vec<ZfsBackupConfig>,
+    pub max_retries: Option<u32>,
+    pub upload_concurrency: Option<usize>,
+    pub max_upload_bytes_per_sec: Option<u64>,
+    pub file_concurrency: Option<usize>,
+    pub zfs_command: Option<String>,
+    pub pricing: Option<PricingConfig>,
+    pub request_timeout_secs: Option<u64>,
+    pub size_check: Option<SizeCheck>,
+    pub buffered_parts: Option<usize>,
+    pub max_memory_mb: Option<usize>,
+    /// Profile in ~/.aws/credentials to use, `--profile` takes precedence.
+    pub aws_profile: Option<String>,
+    /// Role to assume for every request, for example to back up to a bucket in another account.
+    pub assume_role_arn: Option<String>,
+    pub external_id: Option<String>,
+    /// URL sync POSTs a json summary to when an upload failed.
+    pub notify_webhook: Option<String>,
+    /// Also notify `notify_webhook` after runs without failures.
+    #[serde(default)]
+    pub notify_on_success: bool,
+    /// Size of the buffer responses are read into.
+    pub http_read_buf_bytes: Option<usize>,
+    /// Seconds unused connections are kept open for the next request.
+    pub http_pool_idle_secs: Option<u64>,
+}
+
+/// Which snapshot incremental backups are sent against.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum IncrementalBase {
+    /// The previous local snapshot matching either regex, every incremental snapshot is uploaded.
+    #[default]
+    LastLocal,
+    /// The newest snapshot already backed up, only the newest incremental snapshot is uploaded.
+    LastRemote,
+}
+
+/// What sync does when an existing backup is much smaller than the estimated size of its snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeCheck {
+    Warn,
+    Reupload,
 }
 
 impl ZfsBackupConfigEntry {
     pub fn snapshot_regex_re(&self) -> Regex {
         Regex::new(&self.snapshot_regex).unwrap()
     }
+
+    /// Days before backups expire, None if they should be kept forever (`expire_in_days` <= 0).
+    pub fn expire_after_days(&self) -> Option<i64> {
+        if self.expire_in_days > 0 {
+            Some(self.expire_in_days)
+        } else {
+            None
+        }
+    }
+
+    /// Flags passed to zfs send, without the leading dash. `P` is always added.
+    pub fn send_flags(&self) -> String {
+        let send_flags = self.send_flags.as_deref().unwrap_or(DEFAULT_SEND_FLAGS);
+        match self.raw {
+            Some(true) if !send_flags.contains('w') => format!("w{}", send_flags),
+            Some(false) => send_flags.replace('w', ""),
+            _ => send_flags.to_string(),
+        }
+    }
+
+    /// Storage class S3 should move backups to, and after how many days. Always None for
+    /// IntelligentTiering uploads, S3 moves those between tiers itself.
+    pub fn transition(&self) -> Option<(StorageClass, i64)> {
+        if self.storage_class == StorageClass::IntelligentTiering {
+            return None;
+        }
+        Some((self.transition_to?, self.transition_after_days?))
+    }
+
+    /// Warning when backups expire before the minimum storage duration of their storage class,
+    /// as S3 bills the full minimum anyway.
+    fn retention_warning(&self, field: &str) -> Option<String> {
+        let expire_in_days = self.expire_after_days()?;
+        let (storage_class, stored_from_day) = match self.transition() {
+            Some((storage_class, transition_after_days)) => (storage_class, transition_after_days),
+            None => (self.storage_class, 0),
+        };
+        let minimum_days = stored_from_day + storage_class.minimum_storage_days();
+        if expire_in_days < minimum_days {
+            Some(format!(
+                "{}.expire_in_days is {}, but {} is billed for at least {} days, so backups are billed until day {} regardless",
+                field,
+                expire_in_days,
+                storage_class.to_string(),
+                storage_class.minimum_storage_days(),
+                minimum_days
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self, field: &str) -> Result<(), ConfigError> {
+        validate_regex(format!("{}.snapshot_regex", field), &self.snapshot_regex)?;
+        if let Some(send_flags) = &self.send_flags {
+            if !send_flags
+                .chars()
+                .all(|c| c.is_ascii_alphabetic() && !"nviI".contains(c))
+            {
+                return Err(ConfigError::InvalidValue {
+                    field: format!("{}.send_flags", field),
+                    reason: "only flag letters without arguments are allowed, and not n, v, i or I",
+                });
+            }
+            if self.raw == Some(false) && send_flags.contains('w') {
+                return Err(ConfigError::InvalidValue {
+                    field: format!("{}.raw", field),
+                    reason: "raw is false, but send_flags contains w",
+                });
+            }
+        }
+        let reason = match (self.transition_to, self.transition_after_days) {
+            (Some(_), None) | (None, Some(_)) => {
+                Some("transition_to and transition_after_days must be set together")
+            }
+            (Some(StorageClass::STANDARD), _) => Some("can't transition to STANDARD"),
+            (_, Some(days)) if days < 0 => Some("transition_after_days can't be negative"),
+            (_, Some(days))
+                if self
+                    .expire_after_days()
+                    .is_some_and(|expire| days >= expire) =>
+            {
+                Some("transition_after_days must be less than expire_in_days")
+            }
+            _ => None,
+        };
+        match reason {
+            Some(reason) => Err(ConfigError::InvalidValue {
+                field: format!("{}.transition_to", field),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
 impl ZfsBackupConfig {
     pub fn pool_regex_re(&self) -> Regex {
         Regex::new(&self.pool_regex).unwrap()
     }
+
+    pub fn exclude_regex_re(&self) -> Option<Regex> {
+        self.exclude_regex
+            .as_ref()
+            .map(|exclude_regex| Regex::new(exclude_regex).unwrap())
+    }
+
+    /// `incremental` followed by `incremental_tiers`, indexed by tier.
+    pub fn incremental_tiers(&self) -> Vec<&ZfsBackupConfigEntry> {
+        let mut result = vec![&self.incremental];
+        result.extend(&self.incremental_tiers);
+        result
+    }
+
+    /// Config field of an incremental tier, for error messages.
+    pub fn tier_field(tier: usize) -> String {
+        match tier {
+            0 => "incremental".to_string(),
+            tier => format!("incremental_tiers[{}]", tier - 1),
+        }
+    }
+
+    /// Days before overwritten or deleted versions are removed, None when versioning is disabled.
+    pub fn noncurrent_version_expire_after_days(&self) -> Option<i64> {
+        if self.versioning {
+            Some(
+                self.noncurrent_version_expire_in_days
+                    .unwrap_or(DEFAULT_NONCURRENT_VERSION_EXPIRE_IN_DAYS),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Days before the lifecycle rule removes unfinished multipart uploads, None if it's disabled.
+    pub fn abort_incomplete_uploads_after_days(&self) -> Option<i64> {
+        let days = self
+            .abort_incomplete_uploads_after_days
+            .unwrap_or(DEFAULT_ABORT_INCOMPLETE_UPLOADS_AFTER_DAYS);
+        if days > 0 {
+            Some(days)
+        } else {
+            None
+        }
+    }
+
+    /// The first bucket wide setting that differs, configs sharing a bucket have to agree on these.
+    fn bucket_setting_conflict(&self, other: &ZfsBackupConfig) -> Option<&'static str> {
+        if self.full.expire_after_days() != other.full.expire_after_days() {
+            Some("full.expire_in_days")
+        } else if self.incremental.expire_after_days() != other.incremental.expire_after_days() {
+            Some("incremental.expire_in_days")
+        } else if self.full.transition() != other.full.transition() {
+            Some("full.transition_to")
+        } else if self.incremental.transition() != other.incremental.transition() {
+            Some("incremental.transition_to")
+        } else if self
+            .incremental_tiers
+            .iter()
+            .map(|tier| (tier.expire_after_days(), tier.transition()))
+            .ne(other
+                .incremental_tiers
+                .iter()
+                .map(|tier| (tier.expire_after_days(), tier.transition())))
+        {
+            Some("incremental_tiers")
+        } else if self.noncurrent_version_expire_after_days()
+            != other.noncurrent_version_expire_after_days()
+        {
+            Some("versioning")
+        } else if self.abort_incomplete_uploads_after_days()
+            != other.abort_incomplete_uploads_after_days()
+        {
+            Some("abort_incomplete_uploads_after_days")
+        } else if self.region != other.region {
+            Some("region")
+        } else if self.endpoint != other.endpoint {
+            Some("endpoint")
+        } else if self.key_prefix() != other.key_prefix() {
+            Some("key_prefix")
+        } else if self.key_template() != other.key_template() {
+            Some("key_template")
+        } else {
+            None
+        }
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        self.key_prefix.as_deref().unwrap_or_default()
+    }
+
+    /// `tags` sorted by key, so every backup is tagged in the same order.
+    pub fn tags(&self) -> Vec<Tag> {
+        let mut tags: Vec<Tag> = self
+            .tags
+            .iter()
+            .map(|(key, value)| Tag {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        tags.sort_by(|a, b| a.key.cmp(&b.key));
+        tags
+    }
+
+    /// S3 limits the number of tags and their length, a backup tagged beyond them fails at the end
+    /// of its upload.
+    fn validate_tags(&self, field: &str) -> Result<(), ConfigError> {
+        if self.key_template().needs_type_tag() {
+            if self.tags.len() + BACKUP_TAG_KEYS.len() + 1 > MAX_TAGS {
+                return Err(ConfigError::InvalidValue {
+                    field: field.to_string(),
+                    reason: "S3 allows 10 tags per object, including the 9 zfs_to_glacier sets with dates before {type}",
+                });
+            }
+        } else if self.tags.len() + BACKUP_TAG_KEYS.len() > MAX_TAGS {
+            return Err(ConfigError::InvalidValue {
+                field: field.to_string(),
+                reason: "S3 allows 10 tags per object, including the 8 zfs_to_glacier sets",
+            });
+        }
+        for (key, value) in &self.tags {
+            let reason = if BACKUP_TAG_KEYS.contains(&key.as_str()) || key == BACKUP_TYPE_TAG {
+                Some("set by zfs_to_glacier itself")
+            } else if key.starts_with("aws:") {
+                Some("the aws: prefix is reserved by AWS")
+            } else if key.is_empty() || key.chars().count() > MAX_TAG_KEY_LENGTH {
+                Some("tag keys have to be 1 to 128 characters")
+            } else if value.chars().count() > MAX_TAG_VALUE_LENGTH {
+                Some("tag values can't be longer than 256 characters")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return Err(ConfigError::InvalidValue {
+                    field: format!("{}.{}", field, key),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn key_template(&self) -> &KeyTemplate {
+        &self.parsed_key_template
+    }
+
+    /// Region of the bucket, falling back to the environment/profile default when not configured.
+    /// With an `endpoint` the region is only used to sign requests, so any name is accepted.
+    pub fn s3_region(&self) -> Result<Region, ParseRegionError> {
+        match (&self.endpoint, &self.region) {
+            (Some(endpoint), region) => Ok(Region::Custom {
+                name: region
+                    .clone()
+                    .unwrap_or_else(|| Region::default().name().to_string()),
+                endpoint: endpoint.clone(),
+            }),
+            (None, Some(region)) => region.parse(),
+            (None, None) => Ok(Region::default()),
+        }
+    }
+}
+
+impl ZfsBaseConfig {
+    pub fn zfs_command(&self) -> &str {
+        self.zfs_command.as_deref().unwrap_or(DEFAULT_ZFS_COMMAND)
+    }
+
+    pub fn http_read_buf_bytes(&self) -> usize {
+        self.http_read_buf_bytes
+            .unwrap_or(DEFAULT_HTTP_READ_BUF_BYTES)
+    }
+
+    pub fn http_pool_idle_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.http_pool_idle_secs
+                .unwrap_or(DEFAULT_HTTP_POOL_IDLE_SECS),
+        )
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_secs.map(Duration::from_secs)
+    }
+
+    pub fn uses_bookmarks(&self) -> bool {
+        self.configs.iter().any(|config| config.use_bookmarks)
+    }
+
+    /// Whether some snapshots are sent without `-w`, which encrypted datasets still need.
+    pub fn sends_decrypted(&self) -> bool {
+        self.configs.iter().any(|config| {
+            config
+                .incremental_tiers()
+                .into_iter()
+                .chain(std::iter::once(&config.full))
+                .any(|entry| !entry.send_flags().contains('w'))
+        })
+    }
+
+    /// Parses the `key_template` of every config entry once, rather than on every key.
+    pub fn parse_key_templates(&mut self) -> Result<(), ConfigError> {
+        for (i, config) in self.configs.iter_mut().enumerate() {
+            if let Some(key_template) = &config.key_template {
+                config.parsed_key_template =
+                    KeyTemplate::new(key_template).map_err(|err| ConfigError::InvalidValue {
+                        field: format!("configs[{}].key_template", i),
+                        reason: err.0,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the fields serde can't, so mistakes are reported when loading the config.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.external_id.is_some() && self.assume_role_arn.is_none() {
+            return Err(ConfigError::InvalidValue {
+                field: "external_id".to_string(),
+                reason: "only used when assuming a role, set assume_role_arn as well",
+            });
+        }
+        if self.http_read_buf_bytes == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                field: "http_read_buf_bytes".to_string(),
+                reason: "has to be more than 0",
+            });
+        }
+        if self.notify_on_success && self.notify_webhook.is_none() {
+            return Err(ConfigError::InvalidValue {
+                field: "notify_on_success".to_string(),
+                reason: "only used with a webhook, set notify_webhook as well",
+            });
+        }
+        for (i, config) in self.configs.iter().enumerate() {
+            validate_regex(format!("configs[{}].pool_regex", i), &config.pool_regex)?;
+            if let Some(exclude_regex) = &config.exclude_regex {
+                validate_regex(format!("configs[{}].exclude_regex", i), exclude_regex)?;
+            }
+            for (tier, entry) in config.incremental_tiers().into_iter().enumerate() {
+                entry.validate(&format!(
+                    "configs[{}].{}",
+                    i,
+                    ZfsBackupConfig::tier_field(tier)
+                ))?;
+            }
+            config.full.validate(&format!("configs[{}].full", i))?;
+            config.validate_tags(&format!("configs[{}].tags", i))?;
+            if let Some(first) = self.configs[..i]
+                .iter()
+                .position(|x| x.bucket == config.bucket)
+            {
+                if let Some(setting) = self.configs[first].bucket_setting_conflict(config) {
+                    return Err(ConfigError::ConflictingBucket {
+                        bucket: config.bucket.clone(),
+                        first,
+                        second: i,
+                        setting,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Config entries expiring backups before their storage class' minimum storage duration.
+    pub fn retention_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (i, config) in self.configs.iter().enumerate() {
+            let mut entries = vec![("full".to_string(), &config.full)];
+            for (tier, entry) in config.incremental_tiers().into_iter().enumerate() {
+                entries.push((ZfsBackupConfig::tier_field(tier), entry));
+            }
+            for (name, entry) in entries {
+                if let Some(warning) = entry.retention_warning(&format!("configs[{}].{}", i, name))
+                {
+                    warnings.push(warning);
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Configs with unique buckets, the first config of each bucket is used for bucket wide settings.
+    pub fn unique_buckets(&self) -> Vec<&ZfsBackupConfig> {
+        let mut result: Vec<&ZfsBackupConfig> = Vec::new();
+        for config in &self.configs {
+            if !result.iter().any(|x| x.bucket == config.bucket) {
+                result.push(config);
+            }
+        }
+        result
+    }
+
+    pub fn upload_options(&self, backup_config: &ZfsBackupConfig) -> UploadOptions {
+        UploadOptions {
+            max_retries: self.max_retries(),
+            resume: backup_config.resume_uploads,
+            part_size: backup_config.part_size_mb.map(|x| x * 1024 * 1024),
+            concurrency: self.upload_concurrency.unwrap_or_else(num_cpus::get),
+            buffered_parts: self.buffered_parts,
+            sse: backup_config.sse.clone(),
+            sse_kms_key_id: backup_config.sse_kms_key_id.clone(),
+            request_timeout: self.request_timeout(),
+            tags: backup_config.tags(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Format of a config file, by extension. Anything that isn't `.json` is read as YAML.
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+const CONFIG_PATHS: [&str; 3] = ["config.yaml", "config.yml", "config.json"];
+
+/// The first of config.yaml, config.yml and config.json that exists, config.yaml if none do.
+pub fn config_path() -> &'static Path {
+    CONFIG_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new(CONFIG_PATHS[0]))
 }
 
 pub fn read_config() -> Result<ZfsBaseConfig, Box<dyn Error>> {
-    debug!("Loading configuration file...");
-    let contents = fs::read_to_string("config.yaml").expect("Failed to read config.yaml");
+    read_config_from(config_path())
+}
+
+pub fn read_config_from(path: &Path) -> Result<ZfsBaseConfig, Box<dyn Error>> {
+    debug!("Loading configuration file {}...", path.display());
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let config = parse_config_as(&contents, ConfigFormat::from_path(path))?;
+    for warning in config.retention_warnings() {
+        warn!("{}", warning);
+    }
+    Ok(config)
+}
+
+pub fn parse_config(contents: &str) -> Result<ZfsBaseConfig, Box<dyn Error>> {
+    parse_config_as(contents, ConfigFormat::Yaml)
+}
 
-    let content: ZfsBaseConfig = serde_yaml::from_str(&contents)?;
+pub fn parse_config_as(
+    contents: &str,
+    format: ConfigFormat,
+) -> Result<ZfsBaseConfig, Box<dyn Error>> {
+    let mut content: ZfsBaseConfig = match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+        ConfigFormat::Json => serde_json::from_str(contents)?,
+    };
+    content.parse_key_templates()?;
+    content.validate()?;
     Ok(content)
 }
 
@@ -53,17 +651,63 @@ pub fn write_default_config() -> Result<(), Box<dyn Error>> {
     debug!("Writing default configuration file...");
     fs::write(
         "config.yaml",
-        "configs:
+        "#max_retries: 20 #Optional, how many times a failing S3 request is retried.
+#request_timeout_secs: 600 #Optional, S3 requests taking longer than this are retried. Allow for uploading a whole part.
+#http_read_buf_bytes: 67108864 #Optional, buffer S3 responses are read into, 64MiB by default. Lower it on hosts with little memory.
+#http_pool_idle_secs: 5 #Optional, how long unused connections are kept open. Raise it on high latency links.
+#size_check: warn #Optional, warn or reupload when an existing backup is much smaller than its estimated size. Off by default, as it estimates every existing backup once.
+#upload_concurrency: 4 #Optional, parts uploaded in parallel. Defaults to the number of cpus.
+#buffered_parts: 2 #Optional, parts read ahead of the uploads. Defaults to upload_concurrency.
+#max_memory_mb: 1024 #Optional, sync refuses to start when (buffered_parts + upload_concurrency) * part size * file_concurrency is more.
+#max_upload_bytes_per_sec: 10000000 #Optional, limits the upload bandwidth.
+#file_concurrency: 2 #Optional, datasets uploaded in parallel. Defaults to 1.
+#aws_profile: backup #Optional, profile in ~/.aws/credentials to use instead of the default credentials. --profile overrides it.
+#assume_role_arn: \"arn:aws:iam::123456789012:role/backup\" #Optional, role to assume with the credentials above, for example in another account.
+#external_id: \"secret\" #Optional, external id the role requires.
+#notify_webhook: \"https://example.com/hooks/backup\" #Optional, sync POSTs a json summary here when an upload failed.
+#notify_on_success: true #Optional, also notify after syncs without failures.
+#zfs_command: \"sudo zfs\" #Optional, how to run zfs, for example through sudo or ssh.
+#pricing: #Optional, USD prices used by sync --estimate-cost, defaults to us-east-1 prices.
+#  DeepArchive:
+#    gb_month: 0.00099
+#    per_1000_requests: 0.05
+configs:
 - pool_regex: \"rpool/.*\"
+  #exclude_regex: \"rpool/(tmp|scratch)\" #Optional, pools matching this are skipped.
   incremental:
     snapshot_regex: \"daily\"
-    storage_class: \"StandardInfrequentAccess\"
-    expire_in_days: 40
+    storage_class: \"StandardInfrequentAccess\" #One of STANDARD, StandardInfrequentAccess, GlacierInstantRetrieval, IntelligentTiering, Glacier or DeepArchive.
+    expire_in_days: 40 #0 keeps backups forever.
+    #transition_to: \"DeepArchive\" #Optional, storage class S3 moves backups to after transition_after_days.
+    #transition_after_days: 30
+    #recursive: true #Optional, send child datasets in the same stream (zfs send -R), see README.
+    #send_flags: \"wL\" #Optional zfs send flags, defaults to w (raw). Without w encrypted data is sent decrypted!
+    #raw: false #Optional, send without -w so the received data can be recompressed. Encrypted datasets are sent raw regardless.
+  #incremental_tiers: #Optional, more incremental tiers below incremental, each with its own expiry, see README.
+  #- snapshot_regex: \"hourly\"
+  #  storage_class: \"STANDARD\"
+  #  expire_in_days: 7
   full:
     snapshot_regex: \"monthly\"
     storage_class: \"DeepArchive\" #minimum storage period as of this writing is 180 days for deeparchive.
     expire_in_days: 200
-  bucket: \"zfs-rpool\" #You can backup multiple pools to one bucket.",
+  bucket: \"zfs-rpool\" #You can backup multiple pools to one bucket.
+  #region: \"eu-west-3\" #Optional, defaults to AWS_REGION.
+  #key_prefix: \"host1/\" #Optional, prepended to every key, to share a bucket between hosts.
+  #key_template: \"{type}/{year}/{month}/{name}\" #Optional, layout of keys below key_prefix, see README.
+  #tags: #Optional, added to the tags of every backup. S3 allows 2 besides the ones zfs_to_glacier sets.
+  #  CostCenter: \"backups\"
+  #endpoint: \"https://s3.us-west-002.backblazeb2.com\" #Optional, for S3 compatible services other than AWS.
+  #resume_uploads: true #Continue interrupted uploads instead of starting over, only safe with the default raw sends.
+  #part_size_mb: 64 #Optional, by default parts start at 8MiB and grow with the snapshot size.
+  #sse: \"aws:kms\" #Optional S3 server side encryption, in addition to zfs native encryption.
+  #sse_kms_key_id: \"<KMS key id>\" #Optional, the bucket default key is used when not set.
+  #use_bookmarks: true #Allow bookmarks as the parent of incremental backups, so parents can be pruned locally.
+  #incremental_base: LastRemote #Optional, send one incremental per sync against the newest backed up snapshot, instead of one per snapshot (LastLocal).
+  #verify_creation_date: true #Upload backups again when their snapshot was destroyed and recreated with the same name.
+  #versioning: true #Keep old versions of backups that are overwritten or deleted, see README.
+  #noncurrent_version_expire_in_days: 30 #Optional, how long old versions are kept with versioning.
+  #abort_incomplete_uploads_after_days: 7 #Optional, when the lifecycle rule removes unfinished uploads, 0 to never. Raise it if a single upload can take longer.",
     )?;
     println!("config.yaml written");
     Ok(())
@@ -0,0 +1,89 @@
+use std::{collections::BTreeMap, error::Error, fs, io, path::Path};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compute_backups::{S3Backup, S3BackupCommand},
+    zfs_utils::LocalZfsState,
+};
+
+pub const DEFAULT_ESTIMATE_CACHE_PATH: &str = ".zfs-to-glacier-cache.json";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CachedEstimate {
+    snapshot: String,
+    /// Creation time of the snapshot, so a snapshot recreated with the same name isn't mistaken for the old one.
+    creation: i64,
+    size: usize,
+}
+
+/// Size estimates of earlier runs. The send stream of a snapshot never changes, so these stay valid
+/// for as long as the snapshot exists.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EstimateCache {
+    /// Estimates by the `zfs send -n` command that produced them.
+    entries: BTreeMap<String, CachedEstimate>,
+}
+
+impl EstimateCache {
+    /// Reads the cache at `path`, starting empty when it's missing or unreadable.
+    pub fn load(path: &Path) -> EstimateCache {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                warn!("Ignoring size estimate cache {}: {}", path.display(), err);
+                EstimateCache::default()
+            }),
+            Err(_) => EstimateCache::default(),
+        }
+    }
+
+    /// The estimated size of `backup`, running `zfs send -n` only when it isn't cached.
+    pub fn get_estimated_size(&mut self, backup: &S3Backup) -> Result<usize, Box<dyn Error>> {
+        let command = backup.backup_cmd(true);
+        let creation = backup.snapshot.creation.timestamp();
+        if let Some(cached) = self.entries.get(&command) {
+            if cached.creation == creation {
+                return Ok(cached.size);
+            }
+        }
+        let size = backup.get_estimated_size()?;
+        self.entries.insert(
+            command,
+            CachedEstimate {
+                snapshot: backup.snapshot.name.clone(),
+                creation,
+                size,
+            },
+        );
+        Ok(size)
+    }
+
+    /// Drops the estimates of snapshots that no longer exist.
+    pub fn retain_existing(&mut self, local_state: &LocalZfsState) {
+        self.entries.retain(|_, cached| {
+            let dataset = cached.snapshot.split('@').next().unwrap_or_default();
+            local_state.pools.get(dataset).is_some_and(|snapshots| {
+                snapshots
+                    .iter()
+                    .any(|x| x.name == cached.snapshot && x.creation.timestamp() == cached.creation)
+            })
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the cache through a temporary file, so an interrupted write doesn't lose it.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)
+    }
+}
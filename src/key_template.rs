@@ -0,0 +1,330 @@
+use std::hash::{Hash, Hasher};
+
+use chrono::Utc;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use thiserror::Error;
+
+use crate::zfs_utils::ZfsSnapshot;
+
+/// Layout of keys before `key_template` existed.
+pub const DEFAULT_KEY_TEMPLATE: &str = "{type}/{name}";
+
+/// Tag holding `{type}` for templates with dates before it, lifecycle rules select by it.
+pub const BACKUP_TYPE_TAG: &str = "backup_type";
+
+/// Characters percent-encoded in keys. `@` among them, so it can't be confused with anything in a
+/// dataset name, and `%` so decoding is unambiguous.
+pub(crate) const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b':');
+
+#[derive(Error, Debug, PartialEq)]
+#[error("{0}")]
+pub struct InvalidKeyTemplate(pub &'static str);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Placeholder {
+    /// `full`, `incremental` or `incremental-<tier>`.
+    Type,
+    /// `dataset@snapshot`, percent-encoded.
+    Name,
+    Dataset,
+    Snapshot,
+    /// Creation date of the snapshot in UTC, `2021-01-31`.
+    Date,
+    Year,
+    Month,
+    Day,
+}
+
+impl Placeholder {
+    fn is_date(self) -> bool {
+        matches!(
+            self,
+            Placeholder::Date | Placeholder::Year | Placeholder::Month | Placeholder::Day
+        )
+    }
+
+    fn parse(name: &str) -> Option<Placeholder> {
+        Some(match name {
+            "type" => Placeholder::Type,
+            "name" => Placeholder::Name,
+            "dataset" => Placeholder::Dataset,
+            "snapshot" => Placeholder::Snapshot,
+            "date" => Placeholder::Date,
+            "year" => Placeholder::Year,
+            "month" => Placeholder::Month,
+            "day" => Placeholder::Day,
+            _ => return None,
+        })
+    }
+
+    fn pattern(self) -> &'static str {
+        match self {
+            Placeholder::Type => "(?P<type>full|incremental|incremental-[0-9]+)",
+            Placeholder::Name => "(?P<name>.+)",
+            Placeholder::Dataset => "(?P<dataset>.+)",
+            Placeholder::Snapshot => "(?P<snapshot>[^/]+)",
+            Placeholder::Date => "[0-9]{4}-[0-9]{2}-[0-9]{2}",
+            Placeholder::Year => "[0-9]{4}",
+            Placeholder::Month | Placeholder::Day => "[0-9]{2}",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Where a backup is stored in its bucket, below `key_prefix`. Only date placeholders can come
+/// before `{type}`, which has to be followed by a `/`. When `{type}` is first lifecycle rules select
+/// backups by key prefix, otherwise by the `backup_type` tag.
+#[derive(Debug, Clone)]
+pub struct KeyTemplate {
+    segments: Vec<Segment>,
+    /// Matches the keys rendered from `segments`, for `parse()`.
+    regex: Regex,
+}
+
+impl PartialEq for KeyTemplate {
+    fn eq(&self, other: &Self) -> bool {
+        self.segments == other.segments
+    }
+}
+
+impl Eq for KeyTemplate {}
+
+impl Hash for KeyTemplate {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.segments.hash(state);
+    }
+}
+
+impl Default for KeyTemplate {
+    fn default() -> Self {
+        KeyTemplate::new(DEFAULT_KEY_TEMPLATE).unwrap()
+    }
+}
+
+/// `{type}` of a backup, the lifecycle rule of each type can expire it on its own. The first
+/// incremental tier is `incremental` like in configs without tiers.
+pub fn backup_type(incremental: bool, tier: usize) -> String {
+    match (incremental, tier) {
+        (false, _) => "full".to_string(),
+        (true, 0) => "incremental".to_string(),
+        (true, tier) => format!("incremental-{}", tier),
+    }
+}
+
+impl KeyTemplate {
+    pub fn new(template: &str) -> Result<KeyTemplate, InvalidKeyTemplate> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or(InvalidKeyTemplate("unclosed {"))?;
+            let placeholder = Placeholder::parse(&rest[start + 1..start + end]).ok_or(
+                InvalidKeyTemplate(
+                    "unknown placeholder, expected {type}, {name}, {dataset}, {snapshot}, {date}, {year}, {month} or {day}",
+                ),
+            )?;
+            segments.push(Segment::Placeholder(placeholder));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        let count = |placeholder: Placeholder| {
+            segments
+                .iter()
+                .filter(|segment| **segment == Segment::Placeholder(placeholder))
+                .count()
+        };
+        if count(Placeholder::Type) != 1 {
+            return Err(InvalidKeyTemplate("{type} has to appear exactly once"));
+        }
+        match (
+            count(Placeholder::Name),
+            count(Placeholder::Dataset),
+            count(Placeholder::Snapshot),
+        ) {
+            (1, 0, 0) | (0, 1, 1) => {}
+            _ => {
+                return Err(InvalidKeyTemplate(
+                    "either {name}, or {dataset} and {snapshot}, have to appear exactly once",
+                ))
+            }
+        }
+        let type_index = segments
+            .iter()
+            .position(|segment| *segment == Segment::Placeholder(Placeholder::Type))
+            .unwrap();
+        if segments[..type_index].iter().any(|segment| {
+            matches!(segment, Segment::Placeholder(placeholder) if !placeholder.is_date())
+        }) {
+            return Err(InvalidKeyTemplate(
+                "only {date}, {year}, {month} and {day} can come before {type}",
+            ));
+        }
+        match segments.get(type_index + 1) {
+            Some(Segment::Literal(literal)) if literal.starts_with('/') => {}
+            _ => return Err(InvalidKeyTemplate("{type} has to be followed by a /")),
+        }
+        let regex = key_regex(&segments);
+        Ok(KeyTemplate { segments, regex })
+    }
+
+    /// Key of `snapshot`, with `name` as the already encoded `{name}`.
+    fn render_with_name(&self, backup_type: &str, snapshot: &ZfsSnapshot, name: &str) -> String {
+        let (dataset, snapshot_name) = snapshot
+            .name
+            .split_once('@')
+            .unwrap_or((&snapshot.name, ""));
+        let creation = snapshot.creation.with_timezone(&Utc);
+        let mut key = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => key.push_str(literal),
+                Segment::Placeholder(placeholder) => key.push_str(&match placeholder {
+                    Placeholder::Type => backup_type.to_string(),
+                    Placeholder::Name => name.to_string(),
+                    Placeholder::Dataset => {
+                        utf8_percent_encode(dataset, KEY_ENCODE_SET).to_string()
+                    }
+                    Placeholder::Snapshot => {
+                        utf8_percent_encode(snapshot_name, KEY_ENCODE_SET).to_string()
+                    }
+                    Placeholder::Date => creation.format("%Y-%m-%d").to_string(),
+                    Placeholder::Year => creation.format("%Y").to_string(),
+                    Placeholder::Month => creation.format("%m").to_string(),
+                    Placeholder::Day => creation.format("%d").to_string(),
+                }),
+            }
+        }
+        key
+    }
+
+    pub fn render(&self, backup_type: &str, snapshot: &ZfsSnapshot) -> String {
+        self.render_with_name(
+            backup_type,
+            snapshot,
+            &utf8_percent_encode(&snapshot.name, KEY_ENCODE_SET).to_string(),
+        )
+    }
+
+    /// Key used before `@` was percent-encoded, when it was replaced by `_AT_` in `{name}`.
+    pub fn render_legacy(&self, backup_type: &str, snapshot: &ZfsSnapshot) -> String {
+        self.render_with_name(backup_type, snapshot, &snapshot.name.replace("@", "_AT_"))
+    }
+
+    /// Whether dates come before `{type}`, so the keys of every type share their prefix and
+    /// backups are tagged with `backup_type` for lifecycle rules.
+    pub fn needs_type_tag(&self) -> bool {
+        match self
+            .segments
+            .iter()
+            .find(|segment| matches!(segment, Segment::Placeholder(_)))
+        {
+            Some(segment) => *segment != Segment::Placeholder(Placeholder::Type),
+            None => false,
+        }
+    }
+
+    /// Start of the keys of `backup_type`, for lifecycle rules. Up to the first date when
+    /// `needs_type_tag()`.
+    pub fn type_prefix(&self, backup_type: &str) -> String {
+        let mut prefix = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => prefix.push_str(literal),
+                Segment::Placeholder(Placeholder::Type) => prefix.push_str(backup_type),
+                // Validated to come after `{type}/`, or to be a date.
+                Segment::Placeholder(_) => break,
+            }
+        }
+        prefix
+    }
+
+    /// Whether `key` looks like it was meant to be a backup, with `full/` or `incremental` where
+    /// `{type}` goes, or somewhere after the dates before it.
+    pub fn has_type_prefix(&self, key: &str) -> bool {
+        let mut rest = key;
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => match rest.strip_prefix(literal.as_str()) {
+                    Some(stripped) => rest = stripped,
+                    None => return false,
+                },
+                _ => break,
+            }
+        }
+        if self.needs_type_tag() {
+            return rest.contains("/full/") || rest.contains("/incremental");
+        }
+        rest.starts_with("full/") || rest.starts_with("incremental")
+    }
+
+    /// Reverses `render()` and `render_legacy()`, returning the `dataset@snapshot` name and whether
+    /// the backup is incremental.
+    ///
+    /// Returns None for keys not created by either. zfs names can't contain `%`, so names with `%` in
+    /// them are percent-encoded, others are legacy names. For legacy names where `_AT_` appears more
+    /// than once it's impossible to tell which occurrence was the `@`, so those return None too.
+    pub fn parse(&self, key: &str) -> Option<(String, bool)> {
+        let captures = self.regex.captures(key)?;
+        let incremental = &captures["type"] != "full";
+        if let Some(name) = captures.name("name") {
+            return Some((decode_name(name.as_str())?, incremental));
+        }
+        let dataset = percent_decode_str(captures.name("dataset")?.as_str())
+            .decode_utf8()
+            .ok()?;
+        let snapshot = percent_decode_str(captures.name("snapshot")?.as_str())
+            .decode_utf8()
+            .ok()?;
+        if dataset.is_empty() || dataset.contains('@') || snapshot.contains('@') {
+            return None;
+        }
+        Some((format!("{}@{}", dataset, snapshot), incremental))
+    }
+}
+
+fn key_regex(segments: &[Segment]) -> Regex {
+    let mut pattern = "^".to_string();
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => pattern.push_str(&regex::escape(literal)),
+            Segment::Placeholder(placeholder) => pattern.push_str(placeholder.pattern()),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).unwrap()
+}
+
+/// Decodes `{name}`, percent-encoded or legacy.
+fn decode_name(name: &str) -> Option<String> {
+    if name.contains('%') {
+        let name = percent_decode_str(name).decode_utf8().ok()?;
+        let (dataset, snapshot) = name.split_once('@')?;
+        if dataset.is_empty() || snapshot.is_empty() || snapshot.contains('@') {
+            return None;
+        }
+        return Some(name.to_string());
+    }
+    let mut parts = name.split("_AT_");
+    let (dataset, snapshot) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() || dataset.is_empty() || snapshot.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", dataset, snapshot))
+}
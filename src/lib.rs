@@ -1,6 +1,22 @@
-pub mod s3_utils;
+pub mod cloudformation;
 pub mod cmd_execute;
-pub mod zfs_utils;
-pub mod config;
 pub mod compute_backups;
-pub mod cloudformation;
+pub mod config;
+pub mod estimate_cache;
+pub mod key_template;
+pub mod listing;
+pub mod manifest;
+pub mod metrics;
+pub mod notify;
+pub mod parallel_datasets;
+pub mod pricing;
+pub mod report;
+pub mod restore;
+pub mod s3_ops;
+pub mod s3_utils;
+pub mod sync_lock;
+pub mod systemd;
+pub mod throttle;
+pub mod upload_budget;
+pub mod upload_speed;
+pub mod zfs_utils;
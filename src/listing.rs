@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use log::warn;
+use rusoto_s3::S3Client;
+use serde::Serialize;
+
+use crate::{
+    compute_backups::S3Backup,
+    config::ZfsBackupConfig,
+    key_template::KeyTemplate,
+    s3_utils::{get_all_files, get_object_tags, S3Key},
+    zfs_utils::ZfsSnapshot,
+};
+
+/// A backup stored in S3, as found by listing a bucket.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RemoteBackup {
+    pub key: String,
+    pub dataset: String,
+    pub snapshot: String,
+    pub incremental: bool,
+    pub size: i64,
+    pub storage_class: String,
+    pub creation_date: Option<String>,
+    pub parent: Option<String>,
+    /// Key of the parent's backup, missing on backups made before it was tagged.
+    pub parent_key: Option<String>,
+    /// Machine that uploaded the backup, missing on backups made before it was tagged.
+    pub source_host: Option<String>,
+}
+
+/// All backups in `bucket` under `key_prefix`, keys that weren't created by zfs_to_glacier with
+/// `key_template` are skipped.
+pub async fn get_remote_backups(
+    client: &S3Client,
+    bucket: &str,
+    key_prefix: &str,
+    key_template: &KeyTemplate,
+    max_retries: u32,
+    request_timeout: Option<Duration>,
+) -> Result<Vec<RemoteBackup>, Box<dyn Error>> {
+    let mut result = Vec::new();
+    for file in get_all_files(client, bucket, max_retries, request_timeout).await? {
+        let key = match file.key.strip_prefix(key_prefix) {
+            Some(key) => key,
+            None => continue,
+        };
+        let (name, incremental) = match key_template.parse(key) {
+            Some(parsed) => parsed,
+            None => {
+                if key_template.has_type_prefix(key) {
+                    warn!(
+                        "Skipping s3://{}/{}, unable to parse the snapshot name",
+                        bucket, file.key
+                    );
+                }
+                continue;
+            }
+        };
+        let (dataset, snapshot) = name.split_once('@').unwrap();
+        let mut tags = get_object_tags(client, bucket, &file.key, request_timeout).await?;
+        result.push(RemoteBackup {
+            key: file.key,
+            dataset: dataset.to_string(),
+            snapshot: snapshot.to_string(),
+            incremental,
+            size: file.size,
+            storage_class: file.storage_class,
+            creation_date: tags.remove("creation_date"),
+            parent: tags.remove("parent").filter(|parent| parent != "full"),
+            parent_key: tags.remove("parent_key"),
+            source_host: tags.remove("source_host"),
+        });
+    }
+    Ok(result)
+}
+
+/// Keys of the backups in `remote_files` of datasets `config` selects, sorted. Configs sharing a
+/// bucket each get the backups of their own datasets.
+pub fn get_config_backup_keys<'a>(
+    remote_files: &'a HashSet<S3Key>,
+    config: &ZfsBackupConfig,
+) -> Vec<&'a str> {
+    let pool_regex = config.pool_regex_re();
+    let exclude_regex = config.exclude_regex_re();
+    let key_template = config.key_template();
+    let mut result: Vec<&str> = remote_files
+        .iter()
+        .filter(|file| {
+            let name = match file
+                .key
+                .strip_prefix(config.key_prefix())
+                .and_then(|key| key_template.parse(key))
+            {
+                Some((name, _)) => name,
+                None => return false,
+            };
+            let dataset = name.split('@').next().unwrap_or_default();
+            pool_regex.is_match(dataset)
+                && !exclude_regex
+                    .as_ref()
+                    .is_some_and(|re| re.is_match(dataset))
+        })
+        .map(|file| file.key.as_str())
+        .collect();
+    result.sort_unstable();
+    result
+}
+
+/// Backups grouped by dataset, each group sorted by creation date.
+pub fn group_by_dataset(backups: Vec<RemoteBackup>) -> BTreeMap<String, Vec<RemoteBackup>> {
+    let mut result: BTreeMap<String, Vec<RemoteBackup>> = BTreeMap::new();
+    for backup in backups {
+        result
+            .entry(backup.dataset.clone())
+            .or_default()
+            .push(backup);
+    }
+    for backups in result.values_mut() {
+        backups
+            .sort_by(|a, b| (&a.creation_date, &a.snapshot).cmp(&(&b.creation_date, &b.snapshot)));
+    }
+    result
+}
+
+/// How far the backups of one dataset in a bucket are behind the local snapshots.
+#[derive(Debug, Default, PartialEq)]
+pub struct DatasetStatus {
+    /// Backups planned by the config that are in S3.
+    pub backed_up: usize,
+    /// Backups planned by the config that are not in S3 yet.
+    pub pending: usize,
+    /// Newest snapshot the config plans a backup of.
+    pub latest_snapshot: Option<ZfsSnapshot>,
+    /// Whether a backup of `latest_snapshot` is in S3.
+    pub latest_backed_up: bool,
+}
+
+/// Status per dataset of `actions`, the backups `get_pending_actions` plans for one bucket.
+pub fn get_dataset_status(
+    actions: &[S3Backup],
+    remote_files: &HashSet<S3Key>,
+) -> BTreeMap<String, DatasetStatus> {
+    let remote_keys: HashSet<&str> = remote_files.iter().map(|x| x.key.as_str()).collect();
+    let mut result: BTreeMap<String, DatasetStatus> = BTreeMap::new();
+    for action in actions {
+        let dataset = action.snapshot.name.split('@').next().unwrap_or_default();
+        let status = result.entry(dataset.to_string()).or_default();
+        let in_s3 = action.is_in(&remote_keys);
+        if in_s3 {
+            status.backed_up += 1;
+        } else {
+            status.pending += 1;
+        }
+        match &status.latest_snapshot {
+            Some(latest) if latest.name == action.snapshot.name => status.latest_backed_up |= in_s3,
+            Some(latest) if latest.creation >= action.snapshot.creation => {}
+            _ => {
+                status.latest_snapshot = Some(action.snapshot.clone());
+                status.latest_backed_up = in_s3;
+            }
+        }
+    }
+    result
+}
+
+/// Datasets without a backup of a snapshot created at or after `oldest_allowed`, with the creation
+/// date of their newest backed up snapshot. Backups without a `creation_date` tag are ignored.
+pub fn get_stale_datasets(
+    datasets: &[&str],
+    backups: &[RemoteBackup],
+    oldest_allowed: DateTime<Utc>,
+) -> Vec<(String, Option<DateTime<FixedOffset>>)> {
+    let mut newest: BTreeMap<&str, DateTime<FixedOffset>> = BTreeMap::new();
+    for backup in backups {
+        let creation_date = match backup
+            .creation_date
+            .as_deref()
+            .and_then(|date| DateTime::parse_from_rfc3339(date).ok())
+        {
+            Some(creation_date) => creation_date,
+            None => continue,
+        };
+        let entry = newest
+            .entry(backup.dataset.as_str())
+            .or_insert(creation_date);
+        if creation_date > *entry {
+            *entry = creation_date;
+        }
+    }
+    datasets
+        .iter()
+        .filter_map(|dataset| match newest.get(dataset) {
+            Some(newest) if *newest >= oldest_allowed => None,
+            newest => Some((dataset.to_string(), newest.copied())),
+        })
+        .collect()
+}
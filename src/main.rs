@@ -1,23 +1,80 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use log::info;
-use rusoto_core::{HttpClient, HttpConfig, Region, credential::DefaultCredentialsProvider};
+use chrono::{Local, Utc};
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use log::{error, info, warn};
+use rusoto_core::{
+    credential::{
+        AutoRefreshingProvider, DefaultCredentialsProvider, ProfileProvider, ProvideAwsCredentials,
+    },
+    HttpClient, HttpConfig, Region,
+};
 use rusoto_s3::{S3Client, Tag};
-use std::{cmp::max, convert::TryInto, default::Default, env, time::Duration};
-use tokio::runtime;
-use zfs_to_glacier::{cloudformation, compute_backups, config, s3_utils, zfs_utils};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use std::{
+    cmp::max,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryInto,
+    env,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{runtime, signal};
+use zfs_to_glacier::{
+    cloudformation, compute_backups, config,
+    estimate_cache::{EstimateCache, DEFAULT_ESTIMATE_CACHE_PATH},
+    key_template::BACKUP_TYPE_TAG,
+    listing,
+    manifest::{get_manifest, manifest_key, record_backup},
+    metrics::{write_metrics_file, SyncMetrics},
+    notify::{send_webhook, SyncSummary},
+    parallel_datasets::{group_by_pool, run_groups},
+    pricing::{self, CostEstimate, PricingConfig},
+    report::{write_report, ReportEntry, ReportStatus, SyncReport},
+    restore::{get_restore_plan, RestoreError},
+    s3_utils,
+    sync_lock::{SyncLock, DEFAULT_LOCK_PATH},
+    systemd,
+    throttle::Throttle,
+    upload_budget::{parse_byte_size, UploadBudget},
+    upload_speed::{UploadSpeed, DEFAULT_UPLOAD_SPEED_PATH},
+    zfs_utils,
+};
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
 use compute_backups::*;
+use config::{ConfigError, SizeCheck, ZfsBaseConfig};
+use listing::*;
+use regex::Regex;
 use s3_utils::*;
+use zfs_to_glacier::cmd_execute::{Executor, ExecutorCommand};
 use zfs_utils::*;
 
-fn init_logging(verbose: bool) {
+fn init_logging(verbose: bool, json: bool) {
     if verbose {
         env::set_var("RUST_LOG", "zfs_to_glacier=debug");
     } else {
         env::set_var("RUST_LOG", "zfs_to_glacier=info");
     }
-    let _ = env_logger::builder().try_init();
+    let mut builder = env_logger::builder();
+    if json {
+        // One object per line, for log aggregators.
+        builder.format(|buf, record| {
+            let event = serde_json::json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", event)
+        });
+    }
+    let _ = builder.try_init();
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -28,13 +85,614 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .block_on(app())
 }
 
-fn build_s3_client() -> S3Client {
-    let cred_provider =  DefaultCredentialsProvider::new().unwrap();
+/// Where clients get their credentials from, and how they connect.
+struct ClientConfig {
+    /// Profile in ~/.aws/credentials, the default credential chain (environment, AWS_PROFILE,
+    /// instance metadata...) when None.
+    profile: Option<String>,
+    /// Role assumed with the credentials above.
+    assume_role_arn: Option<String>,
+    external_id: Option<String>,
+    http_read_buf_bytes: usize,
+    http_pool_idle_timeout: Duration,
+}
+
+impl ClientConfig {
+    /// `--profile` takes precedence over `aws_profile` in the config.
+    fn new(app: &ArgMatches, config: &ZfsBaseConfig) -> Self {
+        ClientConfig {
+            profile: app
+                .value_of("profile")
+                .map(|profile| profile.to_string())
+                .or_else(|| config.aws_profile.clone()),
+            assume_role_arn: config.assume_role_arn.clone(),
+            external_id: config.external_id.clone(),
+            http_read_buf_bytes: config.http_read_buf_bytes(),
+            http_pool_idle_timeout: config.http_pool_idle_timeout(),
+        }
+    }
+}
+
+/// Session name of assumed roles, shown in CloudTrail.
+const ROLE_SESSION_NAME: &str = "zfs_to_glacier";
+
+fn build_s3_client(
+    region: Region,
+    client_config: &ClientConfig,
+) -> Result<S3Client, Box<dyn std::error::Error>> {
+    match &client_config.profile {
+        Some(profile) => build_s3_client_with(
+            region,
+            ProfileProvider::with_default_credentials(profile)?,
+            client_config,
+        ),
+        None => build_s3_client_with(region, DefaultCredentialsProvider::new()?, client_config),
+    }
+}
+
+fn build_s3_client_with<P: ProvideAwsCredentials + Send + Sync + 'static>(
+    region: Region,
+    provider: P,
+    client_config: &ClientConfig,
+) -> Result<S3Client, Box<dyn std::error::Error>> {
     let mut http_config = HttpConfig::new();
-    http_config.read_buf_size(1024 * 1024 * 64);
-    http_config.pool_idle_timeout(Some(Duration::from_secs(5)));
-    let http_provider = HttpClient::new_with_config(http_config).unwrap();
-    S3Client::new_with(http_provider, cred_provider, Region::default())
+    http_config.read_buf_size(client_config.http_read_buf_bytes);
+    http_config.pool_idle_timeout(Some(client_config.http_pool_idle_timeout));
+    let http_provider = HttpClient::new_with_config(http_config)?;
+    let role_arn = match &client_config.assume_role_arn {
+        Some(role_arn) => role_arn,
+        None => return Ok(S3Client::new_with(http_provider, provider, region)),
+    };
+    // STS has no counterpart on S3 compatible services, use AWS' default region for those.
+    let sts_region = match &region {
+        Region::Custom { .. } => Region::default(),
+        region => region.clone(),
+    };
+    let sts_client = StsClient::new_with(HttpClient::new()?, provider, sts_region);
+    // Assumed role credentials expire after an hour, AutoRefreshingProvider assumes the role
+    // again shortly before that, so uploads running for hours keep working.
+    let provider = AutoRefreshingProvider::new(StsAssumeRoleSessionCredentialsProvider::new(
+        sts_client,
+        role_arn.clone(),
+        ROLE_SESSION_NAME.to_string(),
+        client_config.external_id.clone(),
+        None,
+        None,
+        None,
+    ))?;
+    Ok(S3Client::new_with(http_provider, provider, region))
+}
+
+/// Clients per region, so buckets sharing a region share a client.
+struct S3Clients {
+    client_config: ClientConfig,
+    clients: HashMap<Region, S3Client>,
+}
+
+impl S3Clients {
+    fn new(client_config: ClientConfig) -> Self {
+        S3Clients {
+            client_config,
+            clients: HashMap::new(),
+        }
+    }
+
+    fn get(
+        &mut self,
+        config: &config::ZfsBackupConfig,
+    ) -> Result<S3Client, Box<dyn std::error::Error>> {
+        let region = config.s3_region()?;
+        if let Some(client) = self.clients.get(&region) {
+            return Ok(client.clone());
+        }
+        let client = build_s3_client(region.clone(), &self.client_config)?;
+        self.clients.insert(region, client.clone());
+        Ok(client)
+    }
+}
+
+/// Name of this machine, "unknown" if it can't be determined.
+fn hostname() -> String {
+    ExecutorCommand("hostname".to_string())
+        .execute()
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// On the first Ctrl-C abort the multipart uploads in flight so no orphaned parts are left in S3,
+/// a second Ctrl-C exits immediately.
+fn abort_uploads_on_interrupt(active_uploads: ActiveUploads) {
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Interrupted, aborting in-flight uploads. Press Ctrl-C again to exit immediately.");
+        tokio::spawn(async {
+            let _ = signal::ctrl_c().await;
+            std::process::exit(130);
+        });
+        active_uploads.abort_all().await;
+        std::process::exit(130);
+    });
+}
+
+struct SyncAction {
+    index: usize,
+    client: S3Client,
+    upload_options: UploadOptions,
+    backup_action: S3Backup,
+    /// None when estimates are skipped.
+    estimated_size: Option<usize>,
+}
+
+struct SyncContext {
+    verbose: bool,
+    /// Tagged on every backup, to tell which machine uploaded it.
+    source_host: String,
+    show_progress: bool,
+    total_actions: usize,
+    multi_progress: Arc<MultiProgress>,
+    total_pb: ProgressBar,
+    succeeded: AtomicUsize,
+    bytes_uploaded: AtomicU64,
+    failed: AtomicUsize,
+    skipped: AtomicUsize,
+    /// Bytes `--max-bytes` allows the sync to upload.
+    budget: Option<UploadBudget>,
+    /// Backups left for the next sync once the budget is used up.
+    deferred: AtomicUsize,
+    /// Outcome of every backup, for `--report`.
+    report: Mutex<Vec<ReportEntry>>,
+    /// Throughput of earlier uploads, for the ETA of each upload.
+    upload_speed: Mutex<UploadSpeed>,
+}
+
+impl SyncContext {
+    fn report_not_uploaded<'a>(
+        &self,
+        actions: impl Iterator<Item = &'a SyncAction>,
+        status: ReportStatus,
+    ) {
+        let mut report = self.report.lock().unwrap();
+        for action in actions {
+            report.push(ReportEntry::new(&action.backup_action, status));
+        }
+    }
+}
+
+/// Uploads the backups of one dataset in order. After a failure the remaining backups of the
+/// dataset are skipped, as they may be incrementals on top of the failed one, other datasets
+/// carry on. Once the upload budget is used up the remaining backups are deferred.
+async fn sync_dataset(context: &SyncContext, actions: Vec<SyncAction>) {
+    let mut actions = actions.into_iter();
+    while let Some(action) = actions.next() {
+        let key = action.backup_action.key();
+        let bucket = action.backup_action.bucket.clone();
+        let estimated_size = action.estimated_size;
+        if let Some(budget) = &context.budget {
+            if !budget.reserve(estimated_size) {
+                context
+                    .deferred
+                    .fetch_add(actions.len() + 1, Ordering::SeqCst);
+                context.report_not_uploaded(
+                    std::iter::once(&action).chain(actions.as_slice()),
+                    ReportStatus::Deferred,
+                );
+                return;
+            }
+        }
+        let r = sync_action(context, action).await;
+        if let Some(budget) = &context.budget {
+            budget.settle(estimated_size, r.as_ref().ok().copied());
+        }
+        match r {
+            Ok(_) => {
+                context.succeeded.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(err) => {
+                error!("Upload of s3://{}/{} failed: {}", bucket, key, err);
+                context.failed.fetch_add(1, Ordering::SeqCst);
+                let remaining = actions.len();
+                if remaining > 0 {
+                    warn!(
+                        "  Skipping the {} remaining backup(s) of this dataset",
+                        remaining
+                    );
+                    context.skipped.fetch_add(remaining, Ordering::SeqCst);
+                    context.report_not_uploaded(actions.as_slice().iter(), ReportStatus::Skipped);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Uploads one backup, returning the bytes uploaded. The outcome is added to the report.
+async fn sync_action(
+    context: &SyncContext,
+    action: SyncAction,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let SyncAction {
+        index,
+        client,
+        mut upload_options,
+        backup_action,
+        estimated_size,
+    } = action;
+    upload_options.retries = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    // A bar for an estimate of 0 would sit at 0% and then jump to done, count bytes instead.
+    let bar_size = estimated_size.filter(|&estimated_size| estimated_size > 0);
+    let pb = context.multi_progress.add(match bar_size {
+        Some(bar_size) => ProgressBar::new(bar_size.try_into()?),
+        None => ProgressBar::new_spinner(),
+    });
+    let pb_template = match (bar_size, context.verbose) {
+        (Some(_), true) => "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {msg}\n",
+        (Some(_), false) => "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {msg}",
+        (None, true) => {
+            "{spinner:.green} [{elapsed_precise}] {msg}, uploaded {bytes} ({bytes_per_sec})\n"
+        }
+        (None, false) => {
+            "{spinner:.green} [{elapsed_precise}] {msg}, uploaded {bytes} ({bytes_per_sec})"
+        }
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(pb_template)
+            .progress_chars("#>-"),
+    );
+    // Without an estimate the size is unknown, assume it's large enough for the configured class.
+    let storage_class = match estimated_size {
+        Some(estimated_size) => backup_action.upload_storage_class(estimated_size),
+        None => backup_action.storage_class,
+    };
+    info!(
+        "Processing file {}/{} - {} (storage class {})",
+        index + 1,
+        context.total_actions,
+        backup_action.key(),
+        storage_class.to_string()
+    );
+    let mut tags: Vec<Tag> = Vec::new();
+    tags.push(Tag {
+        key: "backup_cmd".to_string(),
+        value: backup_action.backup_cmd(false),
+    });
+    tags.push(Tag {
+        key: "parent".to_string(),
+        value: backup_action.parent.clone().unwrap_or("full".to_string()),
+    });
+    if let Some(parent_key) = &backup_action.parent_key {
+        // Restores follow this tag to the parent without rebuilding its key.
+        if parent_key.len() <= MAX_TAG_VALUE_LENGTH {
+            tags.push(Tag {
+                key: "parent_key".to_string(),
+                value: parent_key.clone(),
+            });
+        } else {
+            warn!(
+                "  Not tagging {} with its parent's key, it's longer than S3 allows in a tag",
+                backup_action.key()
+            );
+        }
+    }
+    tags.push(Tag {
+        key: "creation_date".to_string(),
+        value: backup_action.snapshot.creation.to_rfc3339(),
+    });
+    tags.push(Tag {
+        key: "source_host".to_string(),
+        value: context.source_host.clone(),
+    });
+    tags.push(Tag {
+        key: "tool_version".to_string(),
+        value: env!("CARGO_PKG_VERSION").to_string(),
+    });
+    if backup_action.key_template.needs_type_tag() {
+        // Dates come first in the key, lifecycle rules select the type by this tag.
+        tags.push(Tag {
+            key: BACKUP_TYPE_TAG.to_string(),
+            value: backup_action.backup_type(),
+        });
+    }
+    tags.extend(upload_options.tags.iter().cloned());
+    // indicatif's {eta} follows the first parts of each upload, this one starts from the speed of
+    // earlier uploads instead.
+    let message = |progress: UploadProgress| match bar_size {
+        Some(bar_size) => {
+            let eta = context.upload_speed.lock().unwrap().eta(
+                progress.bytes_uploaded,
+                (bar_size as u64).saturating_sub(progress.bytes_uploaded),
+                started.elapsed(),
+            );
+            format!(
+                "read {}%, uploaded {}/{}{}",
+                progress.bytes_read * 100 / bar_size as u64,
+                HumanBytes(progress.bytes_uploaded),
+                HumanBytes(bar_size as u64),
+                eta.map_or(String::new(), |eta| format!(" ({})", HumanDuration(eta)))
+            )
+        }
+        None => format!("read {}", HumanBytes(progress.bytes_read)),
+    };
+    pb.set_message(&message(UploadProgress::default()));
+    let last_position = AtomicU64::new(0);
+    // Boxed errors aren't Send, keep them out of scope of the awaits that follow the upload.
+    let bytes_uploaded = {
+        let r: Result<u64, Box<dyn std::error::Error>> =
+            match backup_action.backup(false).map_err(|err| err.to_string()) {
+                Ok(child) => upload_stdout(
+                    &client,
+                    Box::new(child),
+                    UploadTarget {
+                        bucket: &backup_action.bucket,
+                        key: &backup_action.key(),
+                        tags,
+                        storage_class,
+                    },
+                    estimated_size,
+                    &upload_options,
+                    |progress: UploadProgress| {
+                        // The bar follows what S3 confirmed, reading far ahead of it means the
+                        // network is the bottleneck.
+                        pb.set_message(&message(progress));
+                        pb.set_position(progress.bytes_uploaded);
+                        let last = last_position.swap(progress.bytes_uploaded, Ordering::SeqCst);
+                        context
+                            .total_pb
+                            .inc(progress.bytes_uploaded.saturating_sub(last));
+                    },
+                )
+                .await
+                .map_err(|err| {
+                    if let S3Error::AbortFailed { .. } = err {
+                        warn!(
+                            "  Parts of s3://{}/{} were left behind, they will be removed by the AbortIncompleteMultipartUpload lifecycle rule",
+                            backup_action.bucket,
+                            backup_action.key()
+                        );
+                    }
+                    err.into()
+                }),
+                Err(err) => Err(err.into()),
+            };
+        context.report.lock().unwrap().push(ReportEntry {
+            storage_class: storage_class.to_string(),
+            bytes_uploaded: r.as_ref().ok().copied().unwrap_or(0),
+            duration_secs: started.elapsed().as_secs_f64(),
+            retries: upload_options.retries.load(Ordering::SeqCst),
+            error: r.as_ref().err().map(|err| err.to_string()),
+            ..ReportEntry::new(
+                &backup_action,
+                if r.is_ok() {
+                    ReportStatus::Uploaded
+                } else {
+                    ReportStatus::Failed
+                },
+            )
+        });
+        if r.is_err() {
+            // Unfinished bars would keep the progress thread waiting forever.
+            pb.abandon_with_message("File failed");
+        }
+        r?
+    };
+    context
+        .upload_speed
+        .lock()
+        .unwrap()
+        .record(bytes_uploaded, started.elapsed());
+    context
+        .bytes_uploaded
+        .fetch_add(bytes_uploaded, Ordering::SeqCst);
+    if let Some(estimated_size) = estimated_size {
+        context
+            .total_pb
+            .inc((estimated_size as u64).saturating_sub(last_position.load(Ordering::SeqCst)));
+    }
+    if let Err(err) = record_backup(&client, &backup_action, storage_class, bytes_uploaded).await {
+        warn!(
+            "  Unable to add {} to the manifest of its dataset: {}",
+            backup_action.key(),
+            err
+        );
+    }
+    pb.finish_with_message("File completed");
+    if !context.show_progress {
+        info!("  Uploaded {}", backup_action.key());
+    }
+    Ok(bytes_uploaded)
+}
+
+/// Prints what a sync would upload, and the backups that are already in S3.
+fn print_dryrun(
+    actions: &[(S3Client, UploadOptions, S3Backup)],
+    estimated_sizes: &[Result<usize, Box<dyn std::error::Error>>],
+    present: &[(String, S3Key)],
+) {
+    let mut total_size: u64 = 0;
+    let mut size_per_class: BTreeMap<String, u64> = BTreeMap::new();
+    let mut failed_estimates = 0;
+    println!("To upload ({}):", actions.len());
+    for ((_, _, backup_action), estimated_size) in actions.iter().zip(estimated_sizes) {
+        match estimated_size {
+            Ok(estimated_size) => {
+                let storage_class = backup_action
+                    .upload_storage_class(*estimated_size)
+                    .to_string();
+                println!(
+                    "  s3://{}/{} ~{} {}",
+                    backup_action.bucket,
+                    backup_action.key(),
+                    HumanBytes(*estimated_size as u64),
+                    storage_class
+                );
+                total_size += *estimated_size as u64;
+                *size_per_class.entry(storage_class).or_default() += *estimated_size as u64;
+            }
+            Err(err) => {
+                println!(
+                    "  s3://{}/{} size estimate failed: {}",
+                    backup_action.bucket,
+                    backup_action.key(),
+                    err
+                );
+                failed_estimates += 1;
+            }
+        }
+    }
+    println!("Already in S3 ({}):", present.len());
+    for (bucket, remote) in present {
+        println!(
+            "  s3://{}/{} {} {}",
+            bucket,
+            remote.key,
+            HumanBytes(remote.size.max(0) as u64),
+            remote.storage_class
+        );
+    }
+    let per_class: Vec<String> = size_per_class
+        .iter()
+        .map(|(storage_class, size)| format!("{} {}", storage_class, HumanBytes(*size)))
+        .collect();
+    print!(
+        "Estimated upload: {} ({})",
+        HumanBytes(total_size),
+        per_class.join(", ")
+    );
+    if failed_estimates > 0 {
+        print!(
+            ", {} estimate(s) failed and are not included",
+            failed_estimates
+        );
+    }
+    println!();
+}
+
+/// Drops the actions whose size estimate failed, for example because the snapshot was destroyed
+/// since it was listed, and the later actions of their datasets, as they may be incrementals on top
+/// of them. Returns the remaining actions with their estimates, and report entries of the ones that
+/// failed or were skipped.
+#[allow(clippy::type_complexity)]
+fn drop_failed_estimates(
+    actions: Vec<(S3Client, UploadOptions, S3Backup)>,
+    estimated_sizes: Vec<Result<Option<usize>, Box<dyn std::error::Error>>>,
+) -> (
+    Vec<(S3Client, UploadOptions, S3Backup)>,
+    Vec<Option<usize>>,
+    Vec<ReportEntry>,
+) {
+    let mut remaining_actions = Vec::new();
+    let mut remaining_sizes = Vec::new();
+    let mut failed_datasets: Vec<String> = Vec::new();
+    let mut dropped = Vec::new();
+    for (action, estimated_size) in actions.into_iter().zip(estimated_sizes) {
+        let dataset = action
+            .2
+            .snapshot
+            .name
+            .split('@')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if failed_datasets.contains(&dataset) {
+            dropped.push(ReportEntry::new(&action.2, ReportStatus::Skipped));
+            continue;
+        }
+        match estimated_size {
+            Ok(estimated_size) => {
+                remaining_actions.push(action);
+                remaining_sizes.push(estimated_size);
+            }
+            Err(err) => {
+                error!(
+                    "Size estimate of s3://{}/{} failed, skipping the remaining backup(s) of {}: {}",
+                    action.2.bucket,
+                    action.2.key(),
+                    dataset,
+                    err
+                );
+                dropped.push(ReportEntry {
+                    error: Some(format!("Size estimate failed: {}", err)),
+                    ..ReportEntry::new(&action.2, ReportStatus::Failed)
+                });
+                failed_datasets.push(dataset);
+            }
+        }
+    }
+    (remaining_actions, remaining_sizes, dropped)
+}
+
+/// Fails before anything is uploaded when the uploads with the largest parts, `file_concurrency` of
+/// them in parallel, could buffer more than `max_memory_mb`.
+fn check_peak_memory(
+    actions: &[(S3Client, UploadOptions, S3Backup)],
+    estimated_sizes: &[Option<usize>],
+    file_concurrency: usize,
+    max_memory_mb: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut peak = 0;
+    for ((_, upload_options, _), estimated_size) in actions.iter().zip(estimated_sizes) {
+        let part_size = part_size(*estimated_size, upload_options.part_size)?;
+        peak = max(peak, upload_options.peak_memory(part_size));
+    }
+    let peak_mb = (peak * file_concurrency.min(actions.len())).div_ceil(1024 * 1024);
+    if peak_mb > max_memory_mb {
+        return Err(S3Error::MemoryLimitExceeded {
+            peak_mb,
+            max_memory_mb,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Prints the monthly storage cost and one-time request cost of the pending uploads.
+fn print_cost_estimate(
+    actions: &[(S3Client, UploadOptions, S3Backup)],
+    estimated_sizes: &[Result<usize, Box<dyn std::error::Error>>],
+    pricing_config: Option<&PricingConfig>,
+) {
+    let mut cost_per_class: BTreeMap<String, CostEstimate> = BTreeMap::new();
+    for ((_, upload_options, backup_action), estimated_size) in actions.iter().zip(estimated_sizes)
+    {
+        let estimated_size = match estimated_size {
+            Ok(estimated_size) => *estimated_size,
+            Err(_) => continue,
+        };
+        let part_count = match part_size(Some(estimated_size), upload_options.part_size) {
+            Ok(part_size) => max(1, estimated_size.div_ceil(part_size)),
+            Err(err) => {
+                warn!("Not estimating cost of {}: {}", backup_action.key(), err);
+                continue;
+            }
+        };
+        let storage_class = backup_action.upload_storage_class(estimated_size);
+        cost_per_class
+            .entry(storage_class.to_string())
+            .or_default()
+            .add(pricing::estimate_cost(
+                estimated_size as u64,
+                part_count as u64,
+                pricing::pricing(pricing_config, storage_class),
+            ));
+    }
+    let mut total = CostEstimate::default();
+    println!("Estimated cost (USD):");
+    for (storage_class, cost) in cost_per_class {
+        println!(
+            "  {} ${:.2}/month storage, ${:.2} once for requests",
+            storage_class, cost.storage_per_month, cost.requests
+        );
+        total.add(cost);
+    }
+    println!(
+        "  Total ${:.2}/month storage, ${:.2} once for requests",
+        total.storage_per_month, total.requests
+    );
 }
 
 async fn app() -> Result<(), Box<dyn std::error::Error>> {
@@ -42,6 +700,20 @@ async fn app() -> Result<(), Box<dyn std::error::Error>> {
         .version("0.2")
         .author("Anders Aagaard <aagaande@gmail.com>")
         .about("Sync ZFS backups to S3")
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .about("Log output format"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .takes_value(true)
+                .about("AWS profile to use, overrides aws_profile in the config"),
+        )
         .subcommand(
             App::new("sync")
                 .about("Sync state")
@@ -50,128 +722,950 @@ async fn app() -> Result<(), Box<dyn std::error::Error>> {
                         .short('n')
                         .about("Print expected actions but do nothing"),
                 )
-                .arg(Arg::new("verbose").short('v').about("Verbose logging")),
+                .arg(
+                    Arg::new("estimate_cost")
+                        .long("estimate-cost")
+                        .about("Like -n, and also print the projected S3 cost"),
+                )
+                .arg(Arg::new("verbose").short('v').about("Verbose logging"))
+                .arg(
+                    Arg::new("only")
+                        .long("only")
+                        .takes_value(true)
+                        .about("Only sync datasets matching this regex, on top of pool_regex"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .takes_value(true)
+                        .about("Skip snapshots created before this date (2021-01-31) or duration ago (30d, 12h, 2w)"),
+                )
+                .arg(
+                    Arg::new("skip_estimate")
+                        .long("skip-estimate")
+                        .about("Don't run zfs send -n to estimate sizes, progress then has no total"),
+                )
+                .arg(
+                    Arg::new("metrics_file")
+                        .long("metrics-file")
+                        .takes_value(true)
+                        .about("Write node_exporter textfile metrics about the run to this file"),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .takes_value(true)
+                        .about("Write a report of every backup to this file, json for .json paths and YAML otherwise"),
+                )
+                .arg(
+                    Arg::new("no_progress")
+                        .long("no-progress")
+                        .about("Don't draw progress bars, the default when not run from a terminal"),
+                )
+                .arg(
+                    Arg::new("max_bytes")
+                        .long("max-bytes")
+                        .takes_value(true)
+                        .about("Stop starting uploads once this much (500G, 2T...) would be uploaded, the rest is left for the next sync"),
+                )
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .about("Wait for a sync that is already running instead of exiting"),
+                )
+                .arg(
+                    Arg::new("parallel_datasets")
+                        .long("parallel-datasets")
+                        .takes_value(true)
+                        .about("Upload this many datasets at once, overrides file_concurrency in the config"),
+                )
+                .arg(
+                    Arg::new("create_bucket")
+                        .long("create-bucket")
+                        .about("Create buckets that don't exist yet, without the lifecycle rules of generatecloudformation"),
+                ),
         )
         .subcommand(App::new("generateconfig").about("Generate default local config"))
         .subcommand(App::new("estimate_size").about("Estimate total size of backup"))
-        .subcommand(App::new("generatecloudformation").about("Generate cloudformation file"))
+        .subcommand(
+            App::new("generatecloudformation")
+                .about("Generate cloudformation file")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .default_value(cloudformation::DEFAULT_CLOUDFORMATION_PATH)
+                        .about("File to write the template to, - for stdout"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .about("Overwrite the output file if it exists"),
+                ),
+        )
+        .subcommand(
+            App::new("generatesystemd")
+                .about("Generate a systemd service and timer running sync daily")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .default_value(".")
+                        .about("Directory to write the units to"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .about("Overwrite the units if they exist"),
+                ),
+        )
+        .subcommand(
+            App::new("cleanup")
+                .about("Abort abandoned multipart uploads")
+                .arg(
+                    Arg::new("older_than_hours")
+                        .long("older-than-hours")
+                        .takes_value(true)
+                        .default_value("24")
+                        .about("Only abort uploads started more than this many hours ago"),
+                ),
+        )
+        .subcommand(
+            App::new("check")
+                .about("Exit with status 2 if a dataset has no recent backup in S3")
+                .arg(
+                    Arg::new("max_age_hours")
+                        .long("max-age-hours")
+                        .takes_value(true)
+                        .default_value("48")
+                        .about("Newest backed up snapshot of every dataset must be younger than this"),
+                ),
+        )
+        .subcommand(
+            App::new("restore")
+                .about("Print the plan for restoring a dataset from S3")
+                .arg(Arg::new("dataset").required(true).about("Dataset to restore"))
+                .arg(
+                    Arg::new("snapshot")
+                        .long("snapshot")
+                        .takes_value(true)
+                        .about("Snapshot to restore, defaults to the newest one in S3"),
+                )
+                .arg(
+                    Arg::new("target")
+                        .long("target")
+                        .takes_value(true)
+                        .about("Dataset to receive into, defaults to the restored dataset"),
+                )
+                .arg(
+                    Arg::new("dryrun")
+                        .short('n')
+                        .long("dryrun")
+                        .about("Print the zfs receive commands and S3 keys without restoring"),
+                ),
+        )
+        .subcommand(
+            App::new("retag")
+                .about("Add the tags in the config to backups uploaded before they were set")
+                .arg(
+                    Arg::new("dryrun")
+                        .short('n')
+                        .long("dryrun")
+                        .about("Print the backups whose tags would change without changing them"),
+                ),
+        )
+        .subcommand(App::new("status").about("Compare local snapshots to the backups in S3"))
+        .subcommand(
+            App::new("list")
+                .about("List backups stored in S3")
+                .arg(Arg::new("json").long("json").about("Print as json")),
+        )
+        .subcommand(
+            App::new("validate")
+                .about("Check the config without syncing, exits with status 1 if it has errors")
+                .arg(
+                    Arg::new("s3")
+                        .long("s3")
+                        .about("Also check that S3 can be reached and every bucket exists"),
+                ),
+        )
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
+    let json_logs = app.value_of("log_format") == Some("json");
     match app.subcommand() {
         Some(("sync", args)) => {
             let verbose = args.occurrences_of("verbose") > 0;
-            init_logging(verbose);
-            let dryrun = args.occurrences_of("dryrun") > 0;
-            let config = config::read_config()?;
-            let client = build_s3_client();          
+            init_logging(verbose, json_logs);
+            // Progress bars only make sense in a terminal, from cron they end up as control
+            // characters in the mail.
+            let show_progress = args.occurrences_of("no_progress") == 0
+                && io::stdout().is_terminal()
+                && io::stderr().is_terminal();
+            let estimate_cost = args.occurrences_of("estimate_cost") > 0;
+            let dryrun = args.occurrences_of("dryrun") > 0 || estimate_cost;
+            let skip_estimate = args.occurrences_of("skip_estimate") > 0;
+            let create_missing_buckets = args.occurrences_of("create_bucket") > 0;
+            let since = args
+                .value_of("since")
+                .map(|since| parse_since(since, Local::now()))
+                .transpose()?;
+            let parallel_datasets: Option<usize> = args
+                .value_of("parallel_datasets")
+                .map(str::parse)
+                .transpose()?;
+            let max_bytes = args
+                .value_of("max_bytes")
+                .map(parse_byte_size)
+                .transpose()?;
+            let only = args
+                .value_of("only")
+                .map(|only| {
+                    Regex::new(only).map_err(|source| ConfigError::InvalidRegex {
+                        field: "--only".to_string(),
+                        pattern: only.to_string(),
+                        source,
+                    })
+                })
+                .transpose()?;
+            // Dry runs don't upload, so they can run alongside a sync.
+            let _lock = if dryrun {
+                None
+            } else {
+                Some(SyncLock::acquire(
+                    Path::new(DEFAULT_LOCK_PATH),
+                    args.occurrences_of("wait") > 0,
+                )?)
+            };
+            let started = Utc::now();
+            let base_config = config::read_config()?;
+            let active_uploads = ActiveUploads::default();
+            abort_uploads_on_interrupt(active_uploads.clone());
+            let throttle = base_config
+                .max_upload_bytes_per_sec
+                .map(|bytes_per_sec| Arc::new(Throttle::new(bytes_per_sec)));
 
-            let local_zfs_state = get_local_zfs_state()?;
-            let mut actions: Vec<S3Backup> = Vec::new();
-            for config in config.configs {
-                let s3_backup_actions = get_pending_actions(&local_zfs_state, &config);
-                let remote_files = get_all_files(&client, &config.bucket).await?;
-                for backup_action in s3_backup_actions.filter_existing_backups(&remote_files) {
-                    actions.push(backup_action);
+            check_zfs_available(base_config.zfs_command())?;
+            let local_zfs_state = get_local_zfs_state(
+                base_config.zfs_command(),
+                base_config.uses_bookmarks(),
+                base_config.sends_decrypted(),
+            )?;
+            let cache_path = Path::new(DEFAULT_ESTIMATE_CACHE_PATH);
+            let mut cache = EstimateCache::load(cache_path);
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &base_config));
+            let max_retries = base_config.max_retries();
+            let request_timeout = base_config.request_timeout();
+            let mut actions: Vec<(S3Client, UploadOptions, S3Backup)> = Vec::new();
+            let mut present: Vec<(String, S3Key)> = Vec::new();
+            for config in &base_config.configs {
+                let client = clients.get(config)?;
+                let mut upload_options = base_config.upload_options(config);
+                upload_options.active_uploads = Some(active_uploads.clone());
+                upload_options.throttle = throttle.clone();
+                let remote_files = match get_all_files(
+                    &client,
+                    &config.bucket,
+                    max_retries,
+                    request_timeout,
+                )
+                .await
+                {
+                    Err(err)
+                        if create_missing_buckets
+                            && matches!(
+                                err.downcast_ref::<BucketError>(),
+                                Some(BucketError::Missing(_))
+                            ) =>
+                    {
+                        if dryrun {
+                            println!("Would create bucket {}", config.bucket);
+                        } else {
+                            create_bucket(&client, &config.bucket, &config.s3_region()?).await?;
+                            warn!(
+                                "Created bucket {}, it has none of the lifecycle rules of generatecloudformation",
+                                config.bucket
+                            );
+                        }
+                        HashSet::new()
+                    }
+                    remote_files => remote_files?,
+                };
+                let remote_keys: HashSet<&str> =
+                    remote_files.iter().map(|x| x.key.as_str()).collect();
+                let mut s3_backup_actions =
+                    get_pending_actions_with_remote(&local_zfs_state, config, since, &remote_keys);
+                if let Some(only) = &only {
+                    s3_backup_actions.retain(|backup_action| {
+                        only.is_match(
+                            backup_action
+                                .snapshot
+                                .name
+                                .split('@')
+                                .next()
+                                .unwrap_or_default(),
+                        )
+                    });
+                }
+                let remote_by_key: HashMap<&str, &S3Key> =
+                    remote_files.iter().map(|x| (x.key.as_str(), x)).collect();
+                for backup_action in s3_backup_actions {
+                    let remote = match backup_action.find_remote(&remote_by_key) {
+                        Some(remote) => remote,
+                        None => {
+                            actions.push((client.clone(), upload_options.clone(), backup_action));
+                            continue;
+                        }
+                    };
+                    if config.verify_creation_date {
+                        let tags =
+                            get_object_tags(&client, &config.bucket, &remote.key, request_timeout)
+                                .await?;
+                        let creation_date = tags.get("creation_date").map(String::as_str);
+                        if !creation_date_matches(creation_date, &backup_action.snapshot) {
+                            warn!(
+                                "s3://{}/{} was created from an earlier {}, uploading it again",
+                                config.bucket, remote.key, backup_action.snapshot.name
+                            );
+                            actions.push((client.clone(), upload_options.clone(), backup_action));
+                            continue;
+                        }
+                    }
+                    if let Some(size_check) = base_config.size_check {
+                        match cache.get_estimated_size(&backup_action) {
+                            Ok(estimated_size) if is_truncated(remote.size, estimated_size) => {
+                                warn!(
+                                    "s3://{}/{} is {} bytes, but {} is estimated at {} bytes, it may be truncated",
+                                    config.bucket,
+                                    remote.key,
+                                    remote.size,
+                                    backup_action.snapshot.name,
+                                    estimated_size
+                                );
+                                if size_check == SizeCheck::Reupload {
+                                    actions.push((
+                                        client.clone(),
+                                        upload_options.clone(),
+                                        backup_action,
+                                    ));
+                                    continue;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(err) => warn!(
+                                "Unable to estimate {}, not checking the size of s3://{}/{}: {}",
+                                backup_action.snapshot.name, config.bucket, remote.key, err
+                            ),
+                        }
+                    }
+                    if dryrun {
+                        present.push((config.bucket.clone(), remote.clone()));
+                    }
                 }
             }
 
-            let mut actions_performed = 1;
+            // Estimating runs a `zfs send -n` per backup, with --skip-estimate only dry runs do so.
+            let estimated_sizes: Vec<Result<usize, Box<dyn std::error::Error>>> =
+                if skip_estimate && !dryrun {
+                    Vec::new()
+                } else {
+                    actions
+                        .iter()
+                        .map(|(_, _, backup_action)| cache.get_estimated_size(backup_action))
+                        .collect()
+                };
+            cache.retain_existing(&local_zfs_state);
+            if let Err(err) = cache.save(cache_path) {
+                warn!(
+                    "Unable to save size estimates to {}: {}",
+                    cache_path.display(),
+                    err
+                );
+            }
+            if dryrun {
+                print_dryrun(&actions, &estimated_sizes, &present);
+                if estimate_cost {
+                    print_cost_estimate(&actions, &estimated_sizes, base_config.pricing.as_ref());
+                }
+                return Ok(());
+            }
+            let estimated_sizes: Vec<Result<Option<usize>, Box<dyn std::error::Error>>> =
+                if skip_estimate {
+                    actions.iter().map(|_| Ok(None)).collect()
+                } else {
+                    estimated_sizes
+                        .into_iter()
+                        .map(|estimated_size| estimated_size.map(Some))
+                        .collect()
+                };
+            let (actions, estimated_sizes, dropped) =
+                drop_failed_estimates(actions, estimated_sizes);
+            let failed_estimates = dropped
+                .iter()
+                .filter(|entry| entry.status == ReportStatus::Failed)
+                .count();
+            let skipped_estimates = dropped.len() - failed_estimates;
             let total_actions = actions.len();
+            let file_concurrency = parallel_datasets
+                .or(base_config.file_concurrency)
+                .unwrap_or(1)
+                .max(1);
+            if let Some(max_memory_mb) = base_config.max_memory_mb {
+                check_peak_memory(&actions, &estimated_sizes, file_concurrency, max_memory_mb)?;
+            }
+            let multi_progress = Arc::new(if show_progress {
+                MultiProgress::new()
+            } else {
+                MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+            });
+            let total_pb = if skip_estimate {
+                let total_pb = multi_progress.add(ProgressBar::new_spinner());
+                total_pb.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("Total [{elapsed_precise}] {bytes} ({bytes_per_sec})"),
+                );
+                total_pb
+            } else {
+                let total_pb = multi_progress.add(ProgressBar::new(
+                    estimated_sizes.iter().flatten().sum::<usize>().try_into()?,
+                ));
+                total_pb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("Total [{elapsed_precise}] [{bar:40.green/white}] {bytes}/{total_bytes} ({eta})")
+                        .progress_chars("#>-"),
+                );
+                total_pb
+            };
+            // MultiProgress draws from a blocking join, running until every bar is finished.
+            let progress_thread = {
+                let multi_progress = multi_progress.clone();
+                tokio::task::spawn_blocking(move || multi_progress.join())
+            };
 
-            for backup_action in actions {
-                let estimated_size = backup_action.get_estimated_size()?;
-                let pb = ProgressBar::new(estimated_size.try_into()?);
-                let pb_template = {
-                    if verbose {
-                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})\n"
+            // Backups of the same dataset are uploaded in order, so an incremental is never
+            // uploaded before the backup it depends on.
+            let datasets = group_by_pool(
+                actions.into_iter().zip(estimated_sizes).enumerate().map(
+                    |(index, ((client, upload_options, backup_action), estimated_size))| {
+                        SyncAction {
+                            index,
+                            client,
+                            upload_options,
+                            backup_action,
+                            estimated_size,
+                        }
+                    },
+                ),
+                |action| action.backup_action.snapshot.name.as_str(),
+            );
+
+            let sync_context = Arc::new(SyncContext {
+                verbose,
+                source_host: hostname(),
+                show_progress,
+                total_actions,
+                multi_progress: multi_progress.clone(),
+                total_pb: total_pb.clone(),
+                succeeded: AtomicUsize::new(0),
+                bytes_uploaded: AtomicU64::new(0),
+                failed: AtomicUsize::new(failed_estimates),
+                skipped: AtomicUsize::new(skipped_estimates),
+                budget: max_bytes.map(UploadBudget::new),
+                deferred: AtomicUsize::new(0),
+                report: Mutex::new(dropped),
+                upload_speed: Mutex::new(UploadSpeed::load(Path::new(DEFAULT_UPLOAD_SPEED_PATH))),
+            });
+            // Each upload adds its own bar while it runs, one per active dataset.
+            {
+                let sync_context = sync_context.clone();
+                run_groups(datasets, file_concurrency, move |actions| {
+                    let sync_context = sync_context.clone();
+                    async move { sync_dataset(&sync_context, actions).await }
+                })
+                .await?;
+            }
+            let succeeded = sync_context.succeeded.load(Ordering::SeqCst);
+            let failed = sync_context.failed.load(Ordering::SeqCst);
+            let skipped = sync_context.skipped.load(Ordering::SeqCst);
+            let deferred = sync_context.deferred.load(Ordering::SeqCst);
+            if let Err(err) = sync_context
+                .upload_speed
+                .lock()
+                .unwrap()
+                .save(Path::new(DEFAULT_UPLOAD_SPEED_PATH))
+            {
+                warn!(
+                    "Unable to save upload speed to {}: {}",
+                    DEFAULT_UPLOAD_SPEED_PATH, err
+                );
+            }
+            if failed == 0 && deferred == 0 {
+                total_pb.finish_with_message("All files completed");
+            } else {
+                total_pb.abandon();
+            }
+            progress_thread.await??;
+            if let Some(metrics_file) = args.value_of("metrics_file") {
+                let metrics = SyncMetrics {
+                    bytes_uploaded: sync_context.bytes_uploaded.load(Ordering::SeqCst),
+                    files_uploaded: succeeded,
+                    files_failed: failed,
+                    last_success_timestamp: if failed == 0 {
+                        Some(Utc::now().timestamp())
                     } else {
-                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})"
-                    }
+                        None
+                    },
                 };
-                pb.set_style(ProgressStyle::default_bar()
-                    .template(pb_template)
-                    .progress_chars("#>-"));
-                let storage_class = {
-                    if estimated_size > 128000 { 
-                        backup_action.storage_class
-                    } else { 
-                        StorageClass::STANDARD
-                    }                
+                if let Err(err) = write_metrics_file(Path::new(metrics_file), metrics) {
+                    error!("Unable to write metrics to {}: {}", metrics_file, err);
+                }
+            }
+            if let Some(report_path) = args.value_of("report") {
+                let report = SyncReport {
+                    source_host: sync_context.source_host.clone(),
+                    started: started.to_rfc3339(),
+                    finished: Utc::now().to_rfc3339(),
+                    actions: std::mem::take(&mut *sync_context.report.lock().unwrap()),
                 };
-                info!(
-                    "Processing file {}/{} - {} (storage class {})",
-                    actions_performed,
-                    total_actions,
-                    backup_action.key(),
-                    storage_class.to_string()
-                );
-                if !dryrun {
-                    let mut tags: Vec<Tag> = Vec::new();
-                    tags.push(Tag {
-                        key: "backup_cmd".to_string(),
-                        value: backup_action.backup_cmd(false),
-                    });
-                    tags.push(Tag {
-                        key: "parent".to_string(),
-                        value: backup_action.parent.clone().unwrap_or("full".to_string()),
-                    });
-                    tags.push(Tag {
-                        key: "creation_date".to_string(),
-                        value: backup_action.snapshot.creation.to_rfc3339(),
-                    });
-                    upload_stdout(
-                        &client,
-                        Box::new(backup_action.backup(false)?),
-                        &backup_action.bucket,
-                        &backup_action.key(),
-                        tags,
-                        storage_class,
-                        estimated_size,
-                        |bytes_sent| {
-                            pb.set_position(bytes_sent);
-                        },
-                    )
-                    .await?;
-                } else {
-                    info!("  Dryrun, skipping upload {}", &backup_action.key());
+                if let Err(err) = write_report(Path::new(report_path), &report) {
+                    error!("Unable to write report to {}: {}", report_path, err);
+                }
+            }
+            if let Some(notify_webhook) = &base_config.notify_webhook {
+                let summary = SyncSummary {
+                    source_host: sync_context.source_host.clone(),
+                    succeeded,
+                    failed,
+                    skipped,
+                    bytes_uploaded: sync_context.bytes_uploaded.load(Ordering::SeqCst),
+                };
+                if summary.should_notify(base_config.notify_on_success) {
+                    if let Err(err) = send_webhook(notify_webhook, &summary).await {
+                        error!("Unable to notify {}: {}", notify_webhook, err);
+                    }
                 }
-                actions_performed += 1;
-                pb.finish_with_message("File completed");
             }
+            if let Some(budget) = &sync_context.budget {
+                if budget.is_exhausted() {
+                    info!(
+                        "Stopped at the upload budget of {}, {} backup(s) left for the next sync",
+                        HumanBytes(budget.max_bytes()),
+                        deferred
+                    );
+                }
+            }
+            if failed > 0 {
+                error!(
+                    "{} upload(s) succeeded, {} failed, {} skipped",
+                    succeeded, failed, skipped
+                );
+                std::process::exit(2);
+            }
+            info!("{} upload(s) succeeded", succeeded);
         }
         Some(("generateconfig", _)) => {
-            init_logging(false);
+            init_logging(false, json_logs);
             config::write_default_config()?
         }
         Some(("estimate_size", _)) => {
-            init_logging(false);
+            init_logging(false, json_logs);
             info!("Estimating total backup size");
             info!(" - NB, compressed backups will not be estimated 100% correctly!");
-            let local_zfs_state = get_local_zfs_state()?;
             let config = config::read_config()?;
+            let local_zfs_state = get_local_zfs_state(
+                config.zfs_command(),
+                config.uses_bookmarks(),
+                config.sends_decrypted(),
+            )?;
             let mut total_size = 0;
             for config in config.configs {
-                let s3_backup_actions = get_pending_actions(&local_zfs_state, &config);                
+                let s3_backup_actions = get_pending_actions(&local_zfs_state, &config);
                 for backup_action in s3_backup_actions {
                     let estimated_size = backup_action.get_estimated_size()?;
                     total_size += estimated_size;
                 }
             }
-            info!("Estimated size for total backup is : {}gb", total_size / 1024 / 1024 / 1024)
+            info!(
+                "Estimated size for total backup is : {}gb",
+                total_size / 1024 / 1024 / 1024
+            )
+        }
+        Some(("cleanup", args)) => {
+            init_logging(false, json_logs);
+            let older_than_hours: i64 = args.value_of_t("older_than_hours")?;
+            let cutoff = Utc::now() - chrono::Duration::hours(older_than_hours);
+            let config = config::read_config()?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let request_timeout = config.request_timeout();
+            for config in config.unique_buckets() {
+                let client = clients.get(config)?;
+                for (upload, initiated) in get_multipart_uploads(
+                    &client,
+                    &config.bucket,
+                    config.key_prefix.as_deref(),
+                    request_timeout,
+                )
+                .await?
+                {
+                    if initiated > cutoff {
+                        continue;
+                    }
+                    abort_multipart_upload(&client, &upload).await?;
+                    info!(
+                        "Aborted multipart upload s3://{}/{} (started {})",
+                        upload.bucket,
+                        upload.key,
+                        initiated.to_rfc3339()
+                    );
+                }
+            }
+        }
+        Some(("check", args)) => {
+            init_logging(false, json_logs);
+            let max_age_hours: i64 = args.value_of_t("max_age_hours")?;
+            let oldest_allowed = Utc::now() - chrono::Duration::hours(max_age_hours);
+            let config = config::read_config()?;
+            let local_zfs_state = get_local_zfs_state(
+                config.zfs_command(),
+                config.uses_bookmarks(),
+                config.sends_decrypted(),
+            )?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let max_retries = config.max_retries();
+            let request_timeout = config.request_timeout();
+            let mut stale_count = 0;
+            for config in &config.configs {
+                let client = clients.get(config)?;
+                let datasets = get_backed_up_datasets(&local_zfs_state, config);
+                let backups = get_remote_backups(
+                    &client,
+                    &config.bucket,
+                    config.key_prefix(),
+                    config.key_template(),
+                    max_retries,
+                    request_timeout,
+                )
+                .await?;
+                for (dataset, newest) in get_stale_datasets(&datasets, &backups, oldest_allowed) {
+                    stale_count += 1;
+                    match newest {
+                        Some(newest) => println!(
+                            "STALE s3://{} {} last backup {}",
+                            config.bucket,
+                            dataset,
+                            newest.to_rfc3339()
+                        ),
+                        None => {
+                            println!("STALE s3://{} {} never backed up", config.bucket, dataset)
+                        }
+                    }
+                }
+            }
+            if stale_count > 0 {
+                println!(
+                    "{} dataset(s) without a backup in the last {} hours",
+                    stale_count, max_age_hours
+                );
+                std::process::exit(2);
+            }
+            println!(
+                "OK, all datasets have a backup from the last {} hours",
+                max_age_hours
+            );
+        }
+        Some(("restore", args)) => {
+            init_logging(false, json_logs);
+            if args.occurrences_of("dryrun") == 0 {
+                return Err(
+                    "Only restore --dryrun is supported, run the printed commands to restore"
+                        .into(),
+                );
+            }
+            let dataset = args.value_of("dataset").unwrap();
+            let target = args.value_of("target").unwrap_or(dataset);
+            let config = config::read_config()?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let max_retries = config.max_retries();
+            let request_timeout = config.request_timeout();
+            let mut plan = None;
+            for config in config.unique_buckets() {
+                let client = clients.get(config)?;
+                let key = manifest_key(config.key_prefix(), dataset);
+                // Backups made before manifests existed are only found by listing the bucket.
+                match get_manifest(&client, &config.bucket, &key).await {
+                    Ok(Some(manifest)) => match get_restore_plan(
+                        &config.bucket,
+                        &manifest.remote_backups(),
+                        dataset,
+                        args.value_of("snapshot"),
+                    ) {
+                        Ok(manifest_plan) => {
+                            plan = Some(manifest_plan);
+                            break;
+                        }
+                        Err(err) => warn!(
+                            "Unable to plan the restore from s3://{}/{}, listing the bucket: {}",
+                            config.bucket, key, err
+                        ),
+                    },
+                    Ok(None) => {}
+                    Err(err) => warn!(
+                        "Unable to read s3://{}/{}, listing the bucket: {}",
+                        config.bucket, key, err
+                    ),
+                }
+                let backups = get_remote_backups(
+                    &client,
+                    &config.bucket,
+                    config.key_prefix(),
+                    config.key_template(),
+                    max_retries,
+                    request_timeout,
+                )
+                .await?;
+                if backups.iter().any(|backup| backup.dataset == dataset) {
+                    plan = Some(get_restore_plan(
+                        &config.bucket,
+                        &backups,
+                        dataset,
+                        args.value_of("snapshot"),
+                    )?);
+                    break;
+                }
+            }
+            let plan = plan.ok_or_else(|| RestoreError::NoBackups(dataset.to_string()))?;
+            println!(
+                "Restoring {} into {} takes {} step(s):",
+                plan.last().map_or(dataset, |step| step.snapshot.as_str()),
+                target,
+                plan.len()
+            );
+            for (i, step) in plan.iter().enumerate() {
+                println!(
+                    "  {}. s3://{}/{} | {}",
+                    i + 1,
+                    step.bucket,
+                    step.key,
+                    step.restore_cmd(config.zfs_command(), target, false)
+                );
+            }
+        }
+        Some(("retag", args)) => {
+            init_logging(false, json_logs);
+            let dryrun = args.occurrences_of("dryrun") > 0;
+            let config = config::read_config()?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let max_retries = config.max_retries();
+            let request_timeout = config.request_timeout();
+            let mut retagged = 0;
+            for config in &config.configs {
+                let tags = config.tags();
+                if tags.is_empty() {
+                    continue;
+                }
+                let client = clients.get(config)?;
+                let remote_files =
+                    get_all_files(&client, &config.bucket, max_retries, request_timeout).await?;
+                for key in get_config_backup_keys(&remote_files, config) {
+                    let current =
+                        get_object_tags(&client, &config.bucket, key, request_timeout).await?;
+                    let merged = match merge_tags(&current, &tags) {
+                        Some(merged) => merged,
+                        None => continue,
+                    };
+                    if merged.len() > MAX_TAGS {
+                        warn!(
+                            "Not retagging s3://{}/{}, it would have {} tags and S3 allows {}",
+                            config.bucket,
+                            key,
+                            merged.len(),
+                            MAX_TAGS
+                        );
+                        continue;
+                    }
+                    if dryrun {
+                        println!("Would retag s3://{}/{}", config.bucket, key);
+                    } else {
+                        put_object_tags(&client, &config.bucket, key, merged, max_retries).await?;
+                        info!("Retagged s3://{}/{}", config.bucket, key);
+                    }
+                    retagged += 1;
+                }
+            }
+            if dryrun {
+                println!("{} backup(s) would be retagged", retagged);
+            } else {
+                info!("Retagged {} backup(s)", retagged);
+            }
+        }
+        Some(("status", _)) => {
+            init_logging(false, json_logs);
+            let config = config::read_config()?;
+            let local_zfs_state = get_local_zfs_state(
+                config.zfs_command(),
+                config.uses_bookmarks(),
+                config.sends_decrypted(),
+            )?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let max_retries = config.max_retries();
+            let request_timeout = config.request_timeout();
+            println!(
+                "{:<40} {:>10} {:>8}  latest snapshot",
+                "dataset", "backed up", "pending"
+            );
+            for config in &config.configs {
+                let client = clients.get(config)?;
+                let actions = get_pending_actions(&local_zfs_state, config);
+                let remote_files =
+                    get_all_files(&client, &config.bucket, max_retries, request_timeout).await?;
+                println!("s3://{}", config.bucket);
+                for (dataset, status) in get_dataset_status(&actions, &remote_files) {
+                    let latest = match &status.latest_snapshot {
+                        Some(snapshot) => format!(
+                            "{} {}",
+                            snapshot.name.split_once('@').map_or("", |(_, name)| name),
+                            if status.latest_backed_up {
+                                "in S3"
+                            } else {
+                                "NOT in S3"
+                            }
+                        ),
+                        None => "none".to_string(),
+                    };
+                    println!(
+                        "{:<40} {:>10} {:>8}  {}",
+                        dataset, status.backed_up, status.pending, latest
+                    );
+                }
+            }
+        }
+        Some(("list", args)) => {
+            init_logging(false, json_logs);
+            let json = args.occurrences_of("json") > 0;
+            let config = config::read_config()?;
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let max_retries = config.max_retries();
+            let request_timeout = config.request_timeout();
+            let mut buckets: BTreeMap<String, BTreeMap<String, Vec<RemoteBackup>>> =
+                BTreeMap::new();
+            for config in config.unique_buckets() {
+                let client = clients.get(config)?;
+                let backups = get_remote_backups(
+                    &client,
+                    &config.bucket,
+                    config.key_prefix(),
+                    config.key_template(),
+                    max_retries,
+                    request_timeout,
+                )
+                .await?;
+                buckets.insert(config.bucket.clone(), group_by_dataset(backups));
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&buckets)?);
+            } else {
+                for (bucket, datasets) in buckets {
+                    println!("s3://{}", bucket);
+                    for (dataset, backups) in datasets {
+                        println!("  {}", dataset);
+                        for backup in backups {
+                            let kind = match &backup.parent {
+                                Some(parent) => format!("incremental from {}", parent),
+                                None if backup.incremental => "incremental".to_string(),
+                                None => "full".to_string(),
+                            };
+                            println!(
+                                "    @{} {} {} {} {} from {}",
+                                backup.snapshot,
+                                kind,
+                                HumanBytes(backup.size.try_into()?),
+                                backup.storage_class,
+                                backup.creation_date.as_deref().unwrap_or("unknown date"),
+                                backup.source_host.as_deref().unwrap_or("unknown host"),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Some(("validate", args)) => {
+            init_logging(false, json_logs);
+            let path = config::config_path();
+            let contents = match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    println!("FAIL {}: {}", path.display(), err);
+                    std::process::exit(1);
+                }
+            };
+            let config =
+                match config::parse_config_as(&contents, config::ConfigFormat::from_path(path)) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        println!("FAIL {}: {}", path.display(), err);
+                        std::process::exit(1);
+                    }
+                };
+            println!(
+                "OK   {}: {} config(s), regexes compile and buckets shared between configs have the same settings",
+                path.display(),
+                config.configs.len()
+            );
+            let warnings = config.retention_warnings();
+            if warnings.is_empty() {
+                println!(
+                    "OK   Backups are kept for the minimum storage duration of their storage class"
+                );
+            }
+            for warning in warnings {
+                println!("WARN {}", warning);
+            }
+            if args.occurrences_of("s3") == 0 {
+                println!("SKIP S3 access, run with --s3 to check every bucket exists");
+                return Ok(());
+            }
+            let mut clients = S3Clients::new(ClientConfig::new(&app, &config));
+            let request_timeout = config.request_timeout();
+            let mut failed = 0;
+            for config in config.unique_buckets() {
+                let checked = match clients.get(config) {
+                    Ok(client) => check_bucket(&client, &config.bucket, request_timeout).await,
+                    Err(err) => Err(err),
+                };
+                match checked {
+                    Ok(()) => println!("OK   s3://{} exists and can be listed", config.bucket),
+                    Err(err) => {
+                        failed += 1;
+                        println!("FAIL s3://{}: {}", config.bucket, err);
+                    }
+                }
+            }
+            if failed > 0 {
+                std::process::exit(1);
+            }
         }
-        Some(("generatecloudformation", _)) => {
-            init_logging(false);
+        Some(("generatecloudformation", args)) => {
+            init_logging(false, json_logs);
             let config = config::read_config()?;
-            cloudformation::generate_cloudformation(&config)?
+            let output = match args.value_of("output") {
+                Some("-") | None => None,
+                Some(path) => Some(PathBuf::from(path)),
+            };
+            let force = args.occurrences_of("force") > 0;
+            cloudformation::generate_cloudformation(&config, output, force)?
+        }
+        Some(("generatesystemd", args)) => {
+            init_logging(false, json_logs);
+            let output = Path::new(args.value_of("output").unwrap_or("."));
+            let force = args.occurrences_of("force") > 0;
+            systemd::generate_systemd(&env::current_exe()?, &env::current_dir()?, output, force)?
         }
         _ => {}
     }
 
     // @fixme future:
-    // - storing the amazon etag like md5 checksum
-    // - need to check that content online is in sync - on listing maybe confirm some sizes etc? Creation date?.. - maybe new snapshot?
-    //    - check size, if file exists and file is wrong creation date/size we can complain.
     // - if we get an error that might be due to AWS_REGION we should put that info in the error.
     Ok(())
 }
@@ -0,0 +1,149 @@
+use std::error::Error;
+
+use futures::TryStreamExt;
+use percent_encoding::utf8_percent_encode;
+use rusoto_core::{ByteStream, RusotoError};
+use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compute_backups::S3Backup, key_template::KEY_ENCODE_SET, listing::RemoteBackup, s3_ops::S3Ops,
+    s3_utils::StorageClass,
+};
+
+/// One uploaded backup of the dataset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub snapshot: String,
+    pub key: String,
+    /// None for full backups.
+    pub parent: Option<String>,
+    pub parent_key: Option<String>,
+    pub size: u64,
+    pub storage_class: String,
+    pub creation_date: String,
+}
+
+/// The backups of one dataset in a bucket, kept in `manifest/<dataset>.json` so `restore` can plan
+/// without listing the bucket and fetching the tags of every backup.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub dataset: String,
+    /// Sorted by creation date.
+    pub backups: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Adds `entry`, replacing an earlier upload to the same key.
+    pub fn insert(&mut self, entry: ManifestEntry) {
+        self.backups.retain(|backup| backup.key != entry.key);
+        self.backups.push(entry);
+        self.backups
+            .sort_by(|a, b| (&a.creation_date, &a.snapshot).cmp(&(&b.creation_date, &b.snapshot)));
+    }
+
+    /// The backups as if listed from the bucket, for `get_restore_plan`.
+    pub fn remote_backups(&self) -> Vec<RemoteBackup> {
+        self.backups
+            .iter()
+            .map(|backup| RemoteBackup {
+                key: backup.key.clone(),
+                dataset: self.dataset.clone(),
+                snapshot: backup.snapshot.clone(),
+                incremental: backup.parent.is_some(),
+                size: backup.size as i64,
+                storage_class: backup.storage_class.clone(),
+                creation_date: Some(backup.creation_date.clone()),
+                parent: backup.parent.clone(),
+                parent_key: backup.parent_key.clone(),
+                source_host: None,
+            })
+            .collect()
+    }
+}
+
+/// Key of the manifest of `dataset`, below `key_prefix` but outside the backup keys.
+pub fn manifest_key(key_prefix: &str, dataset: &str) -> String {
+    format!(
+        "{}manifest/{}.json",
+        key_prefix,
+        utf8_percent_encode(dataset, KEY_ENCODE_SET)
+    )
+}
+
+/// The manifest stored at `key`, None if there is none yet.
+pub async fn get_manifest<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<Manifest>, Box<dyn Error>> {
+    let response = match client
+        .get_object(GetObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(response) => response,
+        Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let body = match response.body {
+        Some(body) => body.map_ok(|x| x.to_vec()).try_concat().await?,
+        None => Vec::new(),
+    };
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub async fn put_manifest<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    manifest: &Manifest,
+) -> Result<(), Box<dyn Error>> {
+    let body = serde_json::to_vec_pretty(manifest)?;
+    client
+        .put_object(PutObjectRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            content_type: Some("application/json".to_string()),
+            content_length: Some(body.len() as i64),
+            body: Some(ByteStream::from(body)),
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+/// Adds the upload of `backup`, `size` bytes in `storage_class`, to the manifest of its dataset. The manifest is read
+/// again for every upload, the uploads of a dataset are sequential so none are lost.
+pub async fn record_backup<C: S3Ops + ?Sized>(
+    client: &C,
+    backup: &S3Backup,
+    storage_class: StorageClass,
+    size: u64,
+) -> Result<(), Box<dyn Error>> {
+    let (dataset, snapshot) = backup
+        .snapshot
+        .name
+        .split_once('@')
+        .ok_or_else(|| format!("{} is not a snapshot", backup.snapshot.name))?;
+    let key = manifest_key(&backup.key_prefix, dataset);
+    let mut manifest = get_manifest(client, &backup.bucket, &key)
+        .await?
+        .unwrap_or_else(|| Manifest {
+            dataset: dataset.to_string(),
+            backups: Vec::new(),
+        });
+    manifest.insert(ManifestEntry {
+        snapshot: snapshot.to_string(),
+        key: backup.key(),
+        parent: backup.parent.clone(),
+        parent_key: backup.parent_key.clone(),
+        size,
+        storage_class: storage_class.to_string(),
+        creation_date: backup.snapshot.creation.to_rfc3339(),
+    });
+    put_manifest(client, &backup.bucket, &key, &manifest).await
+}
@@ -0,0 +1,75 @@
+use std::{fs, io, path::Path};
+
+/// Results of a sync run, written in the node_exporter textfile collector format.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncMetrics {
+    pub bytes_uploaded: u64,
+    pub files_uploaded: usize,
+    pub files_failed: usize,
+    /// Unix time of the last sync without failures, None if there never was one.
+    pub last_success_timestamp: Option<i64>,
+}
+
+const LAST_SUCCESS_METRIC: &str = "zfs_glacier_last_success_timestamp";
+
+impl SyncMetrics {
+    pub fn to_textfile(&self) -> String {
+        let mut result = String::new();
+        let mut gauge = |name: &str, help: &str, value: String| {
+            result.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+                name, help, name, name, value
+            ));
+        };
+        gauge(
+            "zfs_glacier_bytes_uploaded",
+            "Bytes uploaded by the last sync.",
+            self.bytes_uploaded.to_string(),
+        );
+        gauge(
+            "zfs_glacier_files_uploaded",
+            "Backups uploaded by the last sync.",
+            self.files_uploaded.to_string(),
+        );
+        gauge(
+            "zfs_glacier_files_failed",
+            "Backups that failed to upload in the last sync.",
+            self.files_failed.to_string(),
+        );
+        if let Some(timestamp) = self.last_success_timestamp {
+            gauge(
+                LAST_SUCCESS_METRIC,
+                "Unix time of the last sync without failures.",
+                timestamp.to_string(),
+            );
+        }
+        result
+    }
+}
+
+/// The last success timestamp of a metrics file written by `write_metrics_file`.
+pub fn parse_last_success_timestamp(contents: &str) -> Option<i64> {
+    contents.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        if name == LAST_SUCCESS_METRIC {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Writes `metrics` to `path` through a temporary file, so the collector never reads a partial file.
+///
+/// Without a `last_success_timestamp` the one in the existing file is kept.
+pub fn write_metrics_file(path: &Path, mut metrics: SyncMetrics) -> io::Result<()> {
+    if metrics.last_success_timestamp.is_none() {
+        metrics.last_success_timestamp = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| parse_last_success_timestamp(&contents));
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, metrics.to_textfile())?;
+    fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,37 @@
+use std::error::Error;
+
+use hyper::{header, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+
+/// Results of a sync run, posted as json to `notify_webhook`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SyncSummary {
+    pub source_host: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub bytes_uploaded: u64,
+}
+
+impl SyncSummary {
+    /// Runs with failures are always reported, successful ones only with `notify_on_success`.
+    pub fn should_notify(&self, notify_on_success: bool) -> bool {
+        self.failed > 0 || notify_on_success
+    }
+}
+
+/// POSTs `summary` to `url`, failing on responses other than 2xx.
+pub async fn send_webhook(url: &str, summary: &SyncSummary) -> Result<(), Box<dyn Error>> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(summary)?))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("{} responded with {}", url, response.status()).into());
+    }
+    Ok(())
+}
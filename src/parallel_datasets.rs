@@ -0,0 +1,56 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use futures::future;
+use tokio::{sync::Semaphore, task::JoinError};
+
+/// Splits `items` into one group per pool (dataset), `snapshot_name` giving the `dataset@snapshot` of an
+/// item. Items keep their order within a group, groups are ordered by their first item.
+pub fn group_by_pool<T>(
+    items: impl IntoIterator<Item = T>,
+    snapshot_name: impl Fn(&T) -> &str,
+) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<T>> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    for item in items {
+        let dataset = snapshot_name(&item)
+            .split('@')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let i = *group_index.entry(dataset).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[i].push(item);
+    }
+    groups
+}
+
+/// Runs `run` on every group on a task of its own, at most `limit` groups at a time. Every group is
+/// awaited, a failed one doesn't leave the others running unobserved, the first failure is returned.
+pub async fn run_groups<T, F, Fut>(
+    groups: Vec<Vec<T>>,
+    limit: usize,
+    run: F,
+) -> Result<(), JoinError>
+where
+    T: Send + 'static,
+    F: Fn(Vec<T>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+    let run = Arc::new(run);
+    let tasks: Vec<_> = groups
+        .into_iter()
+        .map(|group| {
+            let semaphore = semaphore.clone();
+            let run = run.clone();
+            tokio::spawn(async move {
+                // The semaphore is never closed, so acquiring only waits for a free slot.
+                let _permit = semaphore.acquire().await;
+                run(group).await
+            })
+        })
+        .collect();
+    future::join_all(tasks).await.into_iter().collect()
+}
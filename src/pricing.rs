@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::s3_utils::StorageClass;
+
+const BYTES_PER_GB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// S3 prices of one storage class, in USD.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StorageClassPricing {
+    pub gb_month: f64,
+    pub per_1000_requests: f64,
+}
+
+/// Prices per storage class, overriding the defaults.
+pub type PricingConfig = HashMap<StorageClass, StorageClassPricing>;
+
+/// us-east-1 prices as of this writing, configure `pricing` for other regions.
+pub fn default_pricing(storage_class: StorageClass) -> StorageClassPricing {
+    let (gb_month, per_1000_requests) = match storage_class {
+        StorageClass::STANDARD => (0.023, 0.005),
+        StorageClass::StandardInfrequentAccess => (0.0125, 0.01),
+        StorageClass::Glacier => (0.0036, 0.03),
+        StorageClass::DeepArchive => (0.00099, 0.05),
+        StorageClass::GlacierInstantRetrieval => (0.004, 0.02),
+        // Frequent access tier, the tier every object starts in.
+        StorageClass::IntelligentTiering => (0.023, 0.005),
+    };
+    StorageClassPricing {
+        gb_month,
+        per_1000_requests,
+    }
+}
+
+pub fn pricing(config: Option<&PricingConfig>, storage_class: StorageClass) -> StorageClassPricing {
+    config
+        .and_then(|config| config.get(&storage_class))
+        .copied()
+        .unwrap_or_else(|| default_pricing(storage_class))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    pub storage_per_month: f64,
+    pub requests: f64,
+}
+
+impl CostEstimate {
+    pub fn add(&mut self, other: CostEstimate) {
+        self.storage_per_month += other.storage_per_month;
+        self.requests += other.requests;
+    }
+}
+
+/// Cost of keeping `size` bytes, uploaded in `part_count` parts, in S3.
+///
+/// A multipart upload also needs a create and a complete request besides the parts.
+pub fn estimate_cost(size: u64, part_count: u64, pricing: StorageClassPricing) -> CostEstimate {
+    CostEstimate {
+        storage_per_month: size as f64 / BYTES_PER_GB * pricing.gb_month,
+        requests: (part_count + 2) as f64 / 1000.0 * pricing.per_1000_requests,
+    }
+}
@@ -0,0 +1,67 @@
+use std::{error::Error, fs, path::Path};
+
+use serde::Serialize;
+
+use crate::compute_backups::S3Backup;
+use crate::config::ConfigFormat;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Uploaded,
+    Failed,
+    /// Not attempted, an earlier backup of the dataset failed.
+    Skipped,
+    /// Left for the next sync by `--max-bytes`.
+    Deferred,
+}
+
+/// What happened to one backup during a sync.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportEntry {
+    pub bucket: String,
+    pub key: String,
+    pub storage_class: String,
+    pub bytes_uploaded: u64,
+    pub duration_secs: f64,
+    /// S3 requests that were retried during the upload.
+    pub retries: usize,
+    pub status: ReportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ReportEntry {
+    /// Entry for a backup that wasn't uploaded.
+    pub fn new(backup: &S3Backup, status: ReportStatus) -> Self {
+        ReportEntry {
+            bucket: backup.bucket.clone(),
+            key: backup.key(),
+            storage_class: backup.storage_class.to_string(),
+            bytes_uploaded: 0,
+            duration_secs: 0.0,
+            retries: 0,
+            status,
+            error: None,
+        }
+    }
+}
+
+/// Record of a sync run, written by `sync --report`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SyncReport {
+    pub source_host: String,
+    pub started: String,
+    pub finished: String,
+    pub actions: Vec<ReportEntry>,
+}
+
+/// Writes `report` as json for `.json` paths and as YAML otherwise, like config files.
+pub fn write_report(path: &Path, report: &SyncReport) -> Result<(), Box<dyn Error>> {
+    let contents = match ConfigFormat::from_path(path) {
+        ConfigFormat::Json => serde_json::to_string_pretty(report)?,
+        ConfigFormat::Yaml => serde_yaml::to_string(report)?,
+    };
+    fs::write(path, contents)?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::listing::RemoteBackup;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RestoreError {
+    #[error("No backups of {0} found")]
+    NoBackups(String),
+    #[error("No backup of {0} found")]
+    MissingBackup(String),
+    #[error("s3://{bucket}/{key} has no parent tag, unable to tell which backup it depends on")]
+    MissingParent { bucket: String, key: String },
+}
+
+/// One backup to stream from S3 into `zfs receive`.
+#[derive(Debug, PartialEq)]
+pub struct RestoreStep {
+    pub bucket: String,
+    pub key: String,
+    /// The `dataset@snapshot` the step restores.
+    pub snapshot: String,
+    pub parent: Option<String>,
+}
+
+impl RestoreStep {
+    /// Receives into `target` without mounting it, dryrun only checks and prints what would be received.
+    pub fn restore_cmd(&self, zfs_command: &str, target: &str, dryrun: bool) -> String {
+        let dryrun_flags = if dryrun { " -nv" } else { "" };
+        format!("{} receive -u{} {}", zfs_command, dryrun_flags, target)
+    }
+}
+
+/// The backups to restore, in order, to get `snapshot` of `dataset` back. Starts at the nearest
+/// full backup and follows the parent tags of the incrementals from there, the `parent_key` tag
+/// when it names a backup in `backups`. Restores the newest backed up snapshot when `snapshot` is
+/// None.
+pub fn get_restore_plan(
+    bucket: &str,
+    backups: &[RemoteBackup],
+    dataset: &str,
+    snapshot: Option<&str>,
+) -> Result<Vec<RestoreStep>, RestoreError> {
+    let mut full: HashMap<String, &RemoteBackup> = HashMap::new();
+    let mut incremental: HashMap<String, &RemoteBackup> = HashMap::new();
+    let mut by_key: HashMap<&str, &RemoteBackup> = HashMap::new();
+    for backup in backups.iter().filter(|x| x.dataset == dataset) {
+        let name = format!("{}@{}", backup.dataset, backup.snapshot);
+        if backup.incremental {
+            incremental.insert(name, backup);
+        } else {
+            full.insert(name, backup);
+        }
+        by_key.insert(&backup.key, backup);
+    }
+    let mut current = match snapshot {
+        Some(snapshot) => format!("{}@{}", dataset, snapshot),
+        None => full
+            .values()
+            .chain(incremental.values())
+            .max_by(|a, b| (&a.creation_date, &a.snapshot).cmp(&(&b.creation_date, &b.snapshot)))
+            .map(|x| format!("{}@{}", x.dataset, x.snapshot))
+            .ok_or_else(|| RestoreError::NoBackups(dataset.to_string()))?,
+    };
+    let mut steps: Vec<RestoreStep> = Vec::new();
+    // The backup `parent_key` of the previous step points to.
+    let mut next: Option<&RemoteBackup> = None;
+    loop {
+        let backup = match next.take().or_else(|| full.get(&current).copied()) {
+            Some(backup) => backup,
+            None => incremental
+                .get(&current)
+                .copied()
+                .ok_or_else(|| RestoreError::MissingBackup(current.clone()))?,
+        };
+        if !backup.incremental {
+            steps.push(RestoreStep {
+                bucket: bucket.to_string(),
+                key: backup.key.clone(),
+                snapshot: current,
+                parent: None,
+            });
+            break;
+        }
+        let parent = backup
+            .parent
+            .clone()
+            .ok_or_else(|| RestoreError::MissingParent {
+                bucket: bucket.to_string(),
+                key: backup.key.clone(),
+            })?;
+        // A parent that is a bookmark was sent from the snapshot with the same name.
+        let parent = parent.replacen('#', "@", 1);
+        if steps.iter().any(|step| step.snapshot == parent) {
+            return Err(RestoreError::MissingBackup(parent));
+        }
+        steps.push(RestoreStep {
+            bucket: bucket.to_string(),
+            key: backup.key.clone(),
+            snapshot: current,
+            parent: Some(parent.clone()),
+        });
+        next = backup
+            .parent_key
+            .as_deref()
+            .and_then(|key| by_key.get(key))
+            .copied();
+        current = parent;
+    }
+    steps.reverse();
+    Ok(steps)
+}
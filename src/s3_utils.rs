@@ -1,38 +1,94 @@
 use crate::cmd_execute;
+use crate::s3_ops::S3Ops;
+use crate::throttle::Throttle;
 
 use async_channel::{Receiver, Sender};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use cmd_execute::CommandStreamActions;
-use futures::future;
-use log::{debug, error, warn};
+use futures::{future, stream, Future};
+use log::{debug, error, info, warn};
 use md5::Digest;
-use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
-use rusoto_core::ByteStream;
-use rusoto_s3::{CreateMultipartUploadRequest, ListObjectsV2Request, S3Client, Tag, S3};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::Rng;
+use rusoto_core::{request::HttpDispatchError, ByteStream, Region, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadError, CompleteMultipartUploadError, CreateBucketConfiguration,
+    CreateBucketRequest, CreateMultipartUploadError, CreateMultipartUploadRequest,
+    DeleteObjectError, DeleteObjectRequest, GetObjectTaggingRequest, HeadObjectError,
+    HeadObjectRequest, ListMultipartUploadsRequest, ListObjectsV2Error, ListObjectsV2Output,
+    ListObjectsV2Request, ListPartsRequest, PutObjectTaggingError, PutObjectTaggingRequest, Tag,
+    Tagging, UploadPartError,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::error::Error;
-use std::io::Read;
+use std::fmt;
+use std::io::{self, Read};
+use std::process::ExitStatus;
 use std::str;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
 use std::time;
-use std::{convert::TryInto, io::BufReader};
 use std::{
-    fmt,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+    convert::{TryFrom, TryInto},
+    io::BufReader,
 };
+use thiserror::Error;
 use tokio::task::JoinHandle;
 
 const MAX_S3_PART_COUNT: usize = 10000;
+const MIN_S3_PART_SIZE: usize = 5 * 1024 * 1024;
+/// Part size when the size of the stream isn't known, allowing objects up to 640GiB.
+pub const UNKNOWN_SIZE_PART_SIZE: usize = 64 * 1024 * 1024;
+/// Parts are read from the command in chunks of this size, so progress moves within a part.
+const PROGRESS_CHUNK_SIZE: usize = 1024 * 1024;
+/// Progress is reported at least this often while waiting for parts to upload.
+const PROGRESS_INTERVAL: time::Duration = time::Duration::from_millis(500);
 
+/// Parsed with `FromStr`, so configs can use either the variant names or the AWS names.
 #[derive(Hash, Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String")]
 pub enum StorageClass {
     STANDARD,
     Glacier,
     DeepArchive,
-    StandardInfrequentAccess
+    StandardInfrequentAccess,
+    /// Glacier storage prices with millisecond reads, no restore needed.
+    GlacierInstantRetrieval,
+    /// S3 moves objects between access tiers itself, based on how they're accessed.
+    IntelligentTiering,
+}
+
+impl StorageClass {
+    const ALL: [StorageClass; 6] = [
+        StorageClass::STANDARD,
+        StorageClass::Glacier,
+        StorageClass::DeepArchive,
+        StorageClass::StandardInfrequentAccess,
+        StorageClass::GlacierInstantRetrieval,
+        StorageClass::IntelligentTiering,
+    ];
+
+    /// Whether objects have to be restored with restore-object before they can be downloaded.
+    pub fn needs_restore(&self) -> bool {
+        matches!(self, StorageClass::Glacier | StorageClass::DeepArchive)
+    }
+
+    /// Days S3 bills objects in this class for at least, even when they're deleted earlier.
+    pub fn minimum_storage_days(&self) -> i64 {
+        match self {
+            StorageClass::STANDARD | StorageClass::IntelligentTiering => 0,
+            StorageClass::StandardInfrequentAccess => 30,
+            StorageClass::Glacier | StorageClass::GlacierInstantRetrieval => 90,
+            StorageClass::DeepArchive => 180,
+        }
+    }
 }
 
 impl ToString for StorageClass {
@@ -42,14 +98,49 @@ impl ToString for StorageClass {
             StorageClass::Glacier => "GLACIER".to_string(),
             StorageClass::DeepArchive => "DEEP_ARCHIVE".to_string(),
             StorageClass::StandardInfrequentAccess => "STANDARD_IA".to_string(),
+            StorageClass::GlacierInstantRetrieval => "GLACIER_IR".to_string(),
+            StorageClass::IntelligentTiering => "INTELLIGENT_TIERING".to_string(),
         }
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Debug)]
+#[derive(Error, Debug, PartialEq)]
+#[error("Unknown storage class '{0}', expected one of STANDARD, STANDARD_IA, GLACIER_IR, INTELLIGENT_TIERING, GLACIER or DEEP_ARCHIVE")]
+pub struct InvalidStorageClassError(String);
+
+impl FromStr for StorageClass {
+    type Err = InvalidStorageClassError;
+
+    /// Accepts the variant names (`DeepArchive`) and AWS names (`DEEP_ARCHIVE`), in any case.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let normalize = |name: &str| name.replace(['_', '-'], "").to_lowercase();
+        let value_normalized = normalize(value);
+        StorageClass::ALL
+            .iter()
+            .find(|storage_class| {
+                value_normalized == normalize(&storage_class.to_string())
+                    || value_normalized == normalize(&format!("{:?}", storage_class))
+            })
+            .copied()
+            .ok_or_else(|| InvalidStorageClassError(value.to_string()))
+    }
+}
+
+impl TryFrom<String> for StorageClass {
+    type Error = InvalidStorageClassError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Hash, Clone, PartialEq, Eq, Debug)]
 pub struct S3Key {
     pub key: String,
-    pub etag: String,
+    /// Missing for some objects on S3 compatible services.
+    pub etag: Option<String>,
+    pub size: i64,
+    pub storage_class: String,
 }
 
 macro_rules! _wrapper {
@@ -60,65 +151,262 @@ macro_rules! _wrapper {
     }};
 }
 
-#[derive(Debug)]
-pub struct S3UploadFailedError(String, String);
-impl fmt::Display for S3UploadFailedError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "S3 upload operation {} failed with error: {}",
-            self.0, self.1
-        )
+#[derive(Debug, Error)]
+pub enum S3Error {
+    #[error("S3 create multipart upload failed with error: {0}")]
+    MultipartCreateFailed(#[from] RusotoError<CreateMultipartUploadError>),
+    #[error("S3 upload part failed with error: {0}")]
+    UploadPartFailed(#[from] RusotoError<UploadPartError>),
+    #[error("S3 complete multipart upload failed with error: {0}")]
+    CompleteFailed(#[from] RusotoError<CompleteMultipartUploadError>),
+    /// The upload failed and the multipart upload could not be aborted, so parts are left behind in S3.
+    #[error("{original}, in addition abort_multipart_upload also failed: {source}")]
+    AbortFailed {
+        original: Box<S3Error>,
+        source: RusotoError<AbortMultipartUploadError>,
+    },
+    #[error("zfs command exited with error code {status}{}", stderr.as_ref().map(|stderr| format!(", stderr:\n{}", stderr)).unwrap_or_default())]
+    CommandExited {
+        status: ExitStatus,
+        /// The last lines the command wrote to stderr.
+        stderr: Option<String>,
+    },
+    #[error("Failed to read command output: {0}")]
+    Io(#[from] io::Error),
+    #[error("Upload task failed: {0}")]
+    TaskFailed(String),
+    #[error("S3 head object failed with error: {0}")]
+    HeadObjectFailed(#[from] RusotoError<HeadObjectError>),
+    #[error("S3 put object tagging failed with error: {0}")]
+    PutTaggingFailed(#[from] RusotoError<PutObjectTaggingError>),
+    #[error("Uploaded object has ETag {actual}, expected {expected}")]
+    ETagMismatch { expected: String, actual: String },
+    #[error("Uploads may buffer up to {peak_mb}MiB, more than max_memory_mb of {max_memory_mb}MiB. Lower part_size_mb, upload_concurrency, buffered_parts or file_concurrency")]
+    MemoryLimitExceeded {
+        peak_mb: usize,
+        max_memory_mb: usize,
+    },
+    #[error("{0}")]
+    InvalidPartSize(#[from] InvalidPartSize),
+}
+
+#[derive(Debug, Error, PartialEq)]
+#[error("Invalid part size of {part_size} bytes: {reason}")]
+pub struct InvalidPartSize {
+    pub part_size: usize,
+    pub reason: &'static str,
+}
+
+/// Failures listing a bucket that retrying won't fix.
+#[derive(Debug, Error, PartialEq)]
+pub enum BucketError {
+    #[error("Bucket {0} doesn't exist. Create it with the template from `zfs_to_glacier generatecloudformation`, or run sync with --create-bucket")]
+    Missing(String),
+    #[error("Access to bucket {0} was denied, check the credentials and the bucket policy")]
+    AccessDenied(String),
+}
+
+/// Retries made by `retry!` before giving up, unless overridden with `max_retries = ...;`.
+pub const DEFAULT_MAX_RETRIES: u32 = 20;
+const RETRY_BASE_DELAY: time::Duration = time::Duration::from_secs(1);
+const RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(60);
+
+/// Capped exponential backoff for the given attempt (starting at 1), with random jitter so
+/// parts failing at the same time don't all retry at the same time.
+fn retry_delay(attempt: u32) -> time::Duration {
+    let exponent = attempt.saturating_sub(1).min(16);
+    let delay = RETRY_BASE_DELAY
+        .checked_mul(2u32.pow(exponent))
+        .unwrap_or(RETRY_MAX_DELAY)
+        .min(RETRY_MAX_DELAY);
+    let delay_ms = delay.as_millis() as u64;
+    time::Duration::from_millis(rand::thread_rng().gen_range(delay_ms / 2..=delay_ms))
+}
+
+/// Runs an S3 request, failing it like a dropped connection when it takes longer than `timeout`
+/// so `retry!` tries again.
+pub async fn with_timeout<T, E>(
+    timeout: Option<time::Duration>,
+    request: impl Future<Output = Result<T, RusotoError<E>>>,
+) -> Result<T, RusotoError<E>> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, request).await {
+            Ok(result) => result,
+            Err(_) => Err(RusotoError::HttpDispatch(HttpDispatchError::new(format!(
+                "Request timed out after {:?}",
+                timeout
+            )))),
+        },
+        None => request.await,
     }
 }
-impl Error for S3UploadFailedError {}
 
-macro_rules! retry {
-    ($( $args:expr$(,)? )+) => {{
-        let mut attempt:u64 = 1;
-        loop {
-            let res = _wrapper!($( $args, )*).await;
-            if res.is_ok() {
-                break res;
-            }
-            if attempt < 20 {
-                warn!("\nTask failed, retrying... attempt {}\n{}\n\n", attempt, res.unwrap_err());
-                std::thread::sleep(time::Duration::from_secs(attempt * 2));
-                attempt += 1;
-                continue;
-            }
+/// Runs `operation` until it succeeds, retrying a failure up to `max_retries` times after waiting
+/// the `retry_delay` of that attempt with `sleep`. Returns the last error if every attempt fails.
+pub async fn retry_with<T, E, F, Fut, S, SleepFut>(
+    max_retries: u32,
+    mut operation: F,
+    mut sleep: S,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    S: FnMut(time::Duration) -> SleepFut,
+    SleepFut: Future<Output = ()>,
+{
+    let mut attempt: u32 = 1;
+    loop {
+        let err = match operation().await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if attempt > max_retries {
             warn!("Task failed, ran out of retry attempts!");
-            break res;
+            return Err(err);
         }
+        let delay = retry_delay(attempt);
+        warn!(
+            "\nTask failed, retrying in {:?}... attempt {}\n{}\n\n",
+            delay, attempt, err
+        );
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+macro_rules! retry {
+    (max_retries = $max_retries:expr; retries = $retries:expr; $( $args:expr$(,)? )+) => {{
+        let retries: &AtomicUsize = &$retries;
+        retry_with(
+            $max_retries,
+            || _wrapper!($( $args, )*),
+            |delay| {
+                retries.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(delay)
+            },
+        )
+        .await
     }};
+    (max_retries = $max_retries:expr; $( $args:expr$(,)? )+) => {{
+        retry_with($max_retries, || _wrapper!($( $args, )*), tokio::time::sleep).await
+    }};
+    ($( $args:expr$(,)? )+) => {
+        retry!(max_retries = DEFAULT_MAX_RETRIES; $( $args, )*)
+    };
+}
+
+/// S3 answers a continuation token it no longer accepts with a 400 InvalidArgument, retrying the
+/// same token won't help.
+fn is_invalid_continuation_token<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Unknown(response) => {
+            response.status.as_u16() == 400 && response.body_as_str().contains("continuation token")
+        }
+        _ => false,
+    }
 }
 
-pub async fn get_all_files(
-    client: &S3Client,
+/// `BucketError` for `err`. rusoto only parses some error bodies, so unparsed responses are
+/// recognized by their status.
+fn bucket_error(bucket: &str, err: &RusotoError<ListObjectsV2Error>) -> Option<BucketError> {
+    match err {
+        RusotoError::Service(ListObjectsV2Error::NoSuchBucket(_)) => {
+            Some(BucketError::Missing(bucket.to_string()))
+        }
+        RusotoError::Unknown(response) => match response.status.as_u16() {
+            404 if response.body_as_str().contains("NoSuchBucket") => {
+                Some(BucketError::Missing(bucket.to_string()))
+            }
+            403 => Some(BucketError::AccessDenied(bucket.to_string())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// One page of `get_all_files`, or why listing can't continue with it.
+enum ListPage {
+    Page(Box<ListObjectsV2Output>),
+    InvalidContinuationToken,
+    Failed(BucketError),
+}
+
+/// Lists every object in `bucket`. Failed pages are retried with the same continuation token, the
+/// listing only starts over when S3 rejects the token. A missing or inaccessible bucket fails with
+/// a `BucketError` right away.
+pub async fn get_all_files<C: S3Ops + ?Sized>(
+    client: &C,
     bucket: &str,
+    max_retries: u32,
+    request_timeout: Option<time::Duration>,
 ) -> Result<HashSet<S3Key>, Box<dyn Error>> {
     let mut scan: bool = true;
     let mut continuation_token: Option<String> = None;
     let mut result: HashSet<S3Key> = HashSet::new();
 
     while scan {
-        let request = client
-            .list_objects_v2(ListObjectsV2Request {
-                bucket: bucket.to_string(),
-                continuation_token: continuation_token,
-                max_keys: Some(1000),
-                ..Default::default()
-            })
-            .await?;
+        let request = retry_with(
+            max_retries,
+            || {
+                let resuming = continuation_token.is_some();
+                let request = with_timeout(
+                    request_timeout,
+                    client.list_objects_v2(ListObjectsV2Request {
+                        bucket: bucket.to_string(),
+                        continuation_token: continuation_token.clone(),
+                        max_keys: Some(1000),
+                        ..Default::default()
+                    }),
+                );
+                async move {
+                    match request.await {
+                        Ok(page) => Ok(ListPage::Page(Box::new(page))),
+                        Err(err) if resuming && is_invalid_continuation_token(&err) => {
+                            Ok(ListPage::InvalidContinuationToken)
+                        }
+                        Err(err) => match bucket_error(bucket, &err) {
+                            Some(bucket_error) => Ok(ListPage::Failed(bucket_error)),
+                            None => Err(err),
+                        },
+                    }
+                }
+            },
+            tokio::time::sleep,
+        )
+        .await?;
+        let request = match request {
+            ListPage::Page(request) => *request,
+            ListPage::InvalidContinuationToken => {
+                warn!(
+                    "Continuation token of s3://{} was rejected, listing it again from the start",
+                    bucket
+                );
+                continuation_token = None;
+                result.clear();
+                continue;
+            }
+            ListPage::Failed(bucket_error) => return Err(bucket_error.into()),
+        };
         continuation_token = request.next_continuation_token;
         scan = request.is_truncated.unwrap_or(false);
 
         if request.contents.is_some() {
             for entry in request.contents.unwrap() {
-                let key = entry.key.unwrap().to_string();
+                let key = match entry.key {
+                    Some(key) => key,
+                    None => {
+                        warn!("Skipping object without a key in {}", bucket);
+                        continue;
+                    }
+                };
                 result.insert(S3Key {
-                    key: key.to_owned(),
-                    etag: entry.e_tag.unwrap().to_string(),
+                    key,
+                    etag: entry.e_tag,
+                    size: entry.size.unwrap_or(0),
+                    storage_class: entry
+                        .storage_class
+                        .unwrap_or_else(|| "STANDARD".to_string()),
                 });
             }
         }
@@ -126,56 +414,506 @@ pub async fn get_all_files(
     Ok(result)
 }
 
+/// Lists at most one object of `bucket`, to check it exists and the credentials can read it.
+/// Not retried, a `BucketError` or the request failure is returned as is.
+pub async fn check_bucket<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    request_timeout: Option<time::Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let request = with_timeout(
+        request_timeout,
+        client.list_objects_v2(ListObjectsV2Request {
+            bucket: bucket.to_string(),
+            max_keys: Some(1),
+            ..Default::default()
+        }),
+    );
+    match request.await {
+        Ok(_) => Ok(()),
+        Err(err) => match bucket_error(bucket, &err) {
+            Some(bucket_error) => Err(bucket_error.into()),
+            None => Err(err.into()),
+        },
+    }
+}
+
+/// Creates `bucket` in `region`. It has none of the lifecycle rules of the cloudformation template.
+pub async fn create_bucket<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    region: &Region,
+) -> Result<(), Box<dyn Error>> {
+    // us-east-1 is the default location, S3 rejects it as a constraint.
+    let create_bucket_configuration = match region.name() {
+        "us-east-1" => None,
+        name => Some(CreateBucketConfiguration {
+            location_constraint: Some(name.to_string()),
+        }),
+    };
+    client
+        .create_bucket(CreateBucketRequest {
+            bucket: bucket.to_string(),
+            create_bucket_configuration,
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn get_object_tags<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    request_timeout: Option<time::Duration>,
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let request = with_timeout(
+        request_timeout,
+        client.get_object_tagging(GetObjectTaggingRequest {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        }),
+    )
+    .await?;
+    Ok(request
+        .tag_set
+        .into_iter()
+        .map(|tag| (tag.key, tag.value))
+        .collect())
+}
+
+/// `current` tags with `tags` added, replacing the values of tags with the same key, sorted by key.
+/// None when that changes nothing.
+pub fn merge_tags(current: &HashMap<String, String>, tags: &[Tag]) -> Option<Vec<Tag>> {
+    if tags
+        .iter()
+        .all(|tag| current.get(&tag.key) == Some(&tag.value))
+    {
+        return None;
+    }
+    let mut merged = current.clone();
+    for tag in tags {
+        merged.insert(tag.key.clone(), tag.value.clone());
+    }
+    let mut result: Vec<Tag> = merged
+        .into_iter()
+        .map(|(key, value)| Tag { key, value })
+        .collect();
+    result.sort_by(|a, b| a.key.cmp(&b.key));
+    Some(result)
+}
+
+/// Replaces the tags of an existing object.
+pub async fn put_object_tags<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    tags: Vec<Tag>,
+    max_retries: u32,
+) -> Result<(), Box<dyn Error>> {
+    retry_with(
+        max_retries,
+        || {
+            client.put_object_tagging(PutObjectTaggingRequest {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                tagging: Tagging {
+                    tag_set: tags.clone(),
+                },
+                ..Default::default()
+            })
+        },
+        tokio::time::sleep,
+    )
+    .await?;
+    Ok(())
+}
+
+/// A multipart upload that has been created, but not yet completed or aborted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultipartUpload {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+}
+
+/// Registry of the multipart uploads currently in flight, shared so they can be aborted on interrupt.
+#[derive(Clone, Default)]
+pub struct ActiveUploads(Arc<Mutex<HashMap<String, ActiveUpload>>>);
+
+/// The client an upload was started with, so it can be aborted with the same one.
+type ActiveUpload = (Arc<dyn S3Ops>, MultipartUpload);
+
+impl ActiveUploads {
+    fn insert<C: S3Ops + Clone + 'static>(&self, client: &C, upload: &MultipartUpload) {
+        self.0.lock().unwrap().insert(
+            upload.upload_id.clone(),
+            (Arc::new(client.clone()), upload.clone()),
+        );
+    }
+
+    fn remove(&self, upload: &MultipartUpload) {
+        self.0.lock().unwrap().remove(&upload.upload_id);
+    }
+
+    /// Aborts every upload still registered, logging (but otherwise ignoring) failures.
+    pub async fn abort_all(&self) {
+        let uploads: Vec<ActiveUpload> = self.0.lock().unwrap().drain().map(|(_, x)| x).collect();
+        for (client, upload) in uploads {
+            warn!(
+                "  Aborting multipart upload file s3://{}/{}",
+                upload.bucket, upload.key
+            );
+            if let Err(err) = abort_multipart_upload(client.as_ref(), &upload).await {
+                error!(
+                    "Failed to abort multipart upload s3://{}/{}: {}",
+                    upload.bucket, upload.key, err
+                );
+            }
+        }
+    }
+}
+
+/// Tunables for an upload, usually derived from the configuration.
+#[derive(Clone)]
+pub struct UploadOptions {
+    pub max_retries: u32,
+    /// Counts the requests retried by the upload.
+    pub retries: Arc<AtomicUsize>,
+    pub active_uploads: Option<ActiveUploads>,
+    /// Continue an unfinished multipart upload of the same key instead of starting over.
+    pub resume: bool,
+    /// Size of each multipart part in bytes, computed from the estimated size when not set.
+    pub part_size: Option<usize>,
+    /// Number of parts uploaded in parallel.
+    pub concurrency: usize,
+    /// Parts read ahead of the uploads, defaults to `concurrency`.
+    pub buffered_parts: Option<usize>,
+    /// Shared limit on upload bandwidth.
+    pub throttle: Option<Arc<Throttle>>,
+    /// S3 server side encryption, for example `aws:kms`.
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    /// S3 requests taking longer than this are failed and retried.
+    pub request_timeout: Option<time::Duration>,
+    /// Tags from the config, added to the ones zfs_to_glacier sets on each backup.
+    pub tags: Vec<Tag>,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            max_retries: DEFAULT_MAX_RETRIES,
+            retries: Arc::new(AtomicUsize::new(0)),
+            active_uploads: None,
+            resume: false,
+            part_size: None,
+            concurrency: num_cpus::get(),
+            buffered_parts: None,
+            throttle: None,
+            sse: None,
+            sse_kms_key_id: None,
+            request_timeout: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl UploadOptions {
+    fn buffered_parts(&self) -> usize {
+        self.buffered_parts.unwrap_or(self.concurrency).max(1)
+    }
+
+    /// Most memory an upload with parts of `part_size` bytes holds in buffers, the parts read ahead
+    /// plus the parts being uploaded: `(buffered_parts + concurrency) * part_size`.
+    pub fn peak_memory(&self, part_size: usize) -> usize {
+        (self.buffered_parts() + self.concurrency.max(1)) * part_size
+    }
+}
+
+pub async fn abort_multipart_upload<C: S3Ops + ?Sized>(
+    client: &C,
+    upload: &MultipartUpload,
+) -> Result<(), RusotoError<AbortMultipartUploadError>> {
+    client
+        .abort_multipart_upload(rusoto_s3::AbortMultipartUploadRequest {
+            bucket: upload.bucket.clone(),
+            key: upload.key.clone(),
+            upload_id: upload.upload_id.clone(),
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+/// Lists the multipart uploads in the bucket that have been started but not completed or aborted,
+/// along with when they were initiated.
+pub async fn get_multipart_uploads<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    prefix: Option<&str>,
+    request_timeout: Option<time::Duration>,
+) -> Result<Vec<(MultipartUpload, DateTime<Utc>)>, Box<dyn Error>> {
+    let mut scan: bool = true;
+    let mut key_marker: Option<String> = None;
+    let mut upload_id_marker: Option<String> = None;
+    let mut result: Vec<(MultipartUpload, DateTime<Utc>)> = Vec::new();
+
+    while scan {
+        let request = with_timeout(
+            request_timeout,
+            client.list_multipart_uploads(ListMultipartUploadsRequest {
+                bucket: bucket.to_string(),
+                key_marker,
+                upload_id_marker,
+                prefix: prefix.map(|x| x.to_string()),
+                ..Default::default()
+            }),
+        )
+        .await?;
+        key_marker = request.next_key_marker;
+        upload_id_marker = request.next_upload_id_marker;
+        scan = request.is_truncated.unwrap_or(false);
+
+        for entry in request.uploads.unwrap_or_default() {
+            if let (Some(key), Some(upload_id), Some(initiated)) =
+                (entry.key, entry.upload_id, entry.initiated)
+            {
+                let initiated = DateTime::parse_from_rfc3339(&initiated)?.with_timezone(&Utc);
+                result.push((
+                    MultipartUpload {
+                        bucket: bucket.to_string(),
+                        key,
+                        upload_id,
+                    },
+                    initiated,
+                ));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Lists the parts already uploaded to a multipart upload, by part number.
+pub async fn get_uploaded_parts<C: S3Ops + ?Sized>(
+    client: &C,
+    upload: &MultipartUpload,
+    request_timeout: Option<time::Duration>,
+) -> Result<HashMap<i64, rusoto_s3::Part>, Box<dyn Error>> {
+    let mut scan: bool = true;
+    let mut part_number_marker: Option<i64> = None;
+    let mut result: HashMap<i64, rusoto_s3::Part> = HashMap::new();
+
+    while scan {
+        let request = with_timeout(
+            request_timeout,
+            client.list_parts(ListPartsRequest {
+                bucket: upload.bucket.clone(),
+                key: upload.key.clone(),
+                upload_id: upload.upload_id.clone(),
+                part_number_marker,
+                ..Default::default()
+            }),
+        )
+        .await?;
+        part_number_marker = request.next_part_number_marker;
+        scan = request.is_truncated.unwrap_or(false);
+
+        for part in request.parts.unwrap_or_default() {
+            if let Some(part_number) = part.part_number {
+                result.insert(part_number, part);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Finds the most recently started unfinished upload of `key`, along with the parts it already holds.
+async fn get_resumable_upload<C: S3Ops + ?Sized>(
+    client: &C,
+    bucket: &str,
+    key: &str,
+    request_timeout: Option<time::Duration>,
+) -> Result<Option<(MultipartUpload, HashMap<i64, rusoto_s3::Part>)>, Box<dyn Error>> {
+    let upload = get_multipart_uploads(client, bucket, Some(key), request_timeout)
+        .await?
+        .into_iter()
+        .filter(|(upload, _)| upload.key == key)
+        .max_by_key(|(_, initiated)| *initiated);
+    match upload {
+        Some((upload, _)) => {
+            let parts = get_uploaded_parts(client, &upload, request_timeout).await?;
+            Ok(Some((upload, parts)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Progress of an upload, passed to its callback. Reading ahead of the upload shows the network is
+/// the bottleneck, the upload catching up with reading shows zfs is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UploadProgress {
+    /// Bytes read from the zfs stream.
+    pub bytes_read: u64,
+    /// Bytes S3 confirmed receiving.
+    pub bytes_uploaded: u64,
+}
+
 #[derive(Clone)]
-struct UploadContext {
-    client: S3Client,
+struct UploadContext<C> {
+    client: C,
     bucket: String,
     key: String,
     upload_id: String,
+    /// Bytes read from the command.
+    data_read: Arc<AtomicUsize>,
+    /// Bytes of the parts S3 confirmed.
     data_sent: Arc<AtomicUsize>,
     buf_size: usize,
+    max_retries: u32,
+    retries: Arc<AtomicUsize>,
+    concurrency: usize,
+    buffered_parts: usize,
+    throttle: Option<Arc<Throttle>>,
+    existing_parts: Arc<HashMap<i64, rusoto_s3::Part>>,
+    request_timeout: Option<time::Duration>,
 }
 
-impl UploadContext {
+impl<C> UploadContext<C> {
     fn get_bytes_sent(&self) -> usize {
         self.data_sent.load(Ordering::SeqCst)
     }
+
+    fn progress(&self) -> UploadProgress {
+        UploadProgress {
+            bytes_read: self.data_read.load(Ordering::SeqCst) as u64,
+            bytes_uploaded: self.get_bytes_sent() as u64,
+        }
+    }
+
+    /// The part left by an earlier attempt of this upload (and its MD5), if it holds exactly `buffer`.
+    fn uploaded_part(&self, part_number: i64, buffer: &[u8]) -> Option<UploadedPart> {
+        let part = self.existing_parts.get(&part_number)?;
+        let e_tag = part.e_tag.as_ref()?;
+        let content_md5 = md5::Md5::digest(buffer);
+        if part.size == Some(buffer.len() as i64) && *e_tag == format!("\"{:x}\"", content_md5) {
+            Some((
+                rusoto_s3::CompletedPart {
+                    e_tag: Some(e_tag.clone()),
+                    part_number: Some(part_number),
+                },
+                content_md5.to_vec(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn multipart_upload(&self) -> MultipartUpload {
+        MultipartUpload {
+            bucket: self.bucket.clone(),
+            key: self.key.clone(),
+            upload_id: self.upload_id.clone(),
+        }
+    }
 }
 
-async fn upload_stdout_send_parts<'a, T: Read, F>(
-    upload_context: UploadContext,
-    mut child: Box<dyn CommandStreamActions<T> + 'a>,
+/// A part uploaded to S3 along with the MD5 digest of its content.
+type UploadedPart = (rusoto_s3::CompletedPart, Vec<u8>);
+
+/// Tag holding the hex SHA-256 of the complete uploaded stream.
+pub const CONTENT_SHA256_TAG: &str = "content_sha256";
+
+/// Tags S3 allows per object.
+pub const MAX_TAGS: usize = 10;
+/// Longest key S3 accepts in an object tag, in characters.
+pub const MAX_TAG_KEY_LENGTH: usize = 128;
+/// Longest value S3 accepts in an object tag, in characters.
+pub const MAX_TAG_VALUE_LENGTH: usize = 256;
+/// Tags zfs_to_glacier sets on backups, `parent_key` only on incrementals.
+pub const BACKUP_TAG_KEYS: [&str; 8] = [
+    "backup_cmd",
+    "parent",
+    "parent_key",
+    "creation_date",
+    "source_host",
+    "tool_version",
+    "buffer_size",
+    CONTENT_SHA256_TAG,
+];
+
+/// The ETag S3 assigns to an object uploaded in parts with the given MD5 digests.
+pub fn multipart_etag(part_digests: &[Vec<u8>]) -> String {
+    let mut hasher = md5::Md5::new();
+    for digest in part_digests {
+        hasher.update(digest);
+    }
+    format!("\"{:x}-{}\"", hasher.finalize(), part_digests.len())
+}
+
+/// Reads up to `buf_size` bytes from `reader`, adding them to `data_read` as they come in.
+fn read_part<R: Read>(
+    reader: &mut R,
+    buf_size: usize,
+    data_read: &AtomicUsize,
+) -> io::Result<Vec<u8>> {
+    let mut b = Vec::with_capacity(buf_size);
+    loop {
+        let chunk_size = (buf_size - b.len()).min(PROGRESS_CHUNK_SIZE);
+        let chunk_read = reader
+            .by_ref()
+            .take(chunk_size.try_into().unwrap())
+            .read_to_end(&mut b)?;
+        data_read.fetch_add(chunk_read, Ordering::SeqCst);
+        if chunk_read == 0 || b.len() == buf_size {
+            return Ok(b);
+        }
+    }
+}
+
+async fn upload_stdout_send_parts<'a, T: Read + Send + 'static, F, C: S3Ops + Clone + 'static>(
+    upload_context: UploadContext<C>,
+    mut child: Box<dyn CommandStreamActions<T> + Send + 'a>,
     callback: F,
-) -> Result<Vec<rusoto_s3::CompletedPart>, Box<dyn Error>>
+) -> Result<(Vec<UploadedPart>, String), S3Error>
 where
-    F: Fn(u64) -> (),
+    F: Fn(UploadProgress) -> (),
 {
-    type BufferChannel = (i64, Vec<u8>);
-    type CompletedPartChannel = Result<rusoto_s3::CompletedPart, String>;
+    // Bytes, so retrying a part shares its buffer rather than copying it.
+    type BufferChannel = (i64, Bytes);
+    type CompletedPartChannel = Result<UploadedPart, S3Error>;
 
+    let concurrency = upload_context.concurrency.max(1);
     let (tx_buffer, rx_buffer): (Sender<BufferChannel>, Receiver<BufferChannel>) =
-        async_channel::bounded(2);
+        async_channel::bounded(upload_context.buffered_parts);
     let (tx_completedpart, rx_completedpart): (
         Sender<CompletedPartChannel>,
         Receiver<CompletedPartChannel>,
     ) = async_channel::unbounded();
-    let mut completed_parts: Vec<rusoto_s3::CompletedPart> = Vec::new();
+    let mut completed_parts: Vec<UploadedPart> = Vec::new();
 
-    let senders: Vec<JoinHandle<Result<(), String>>> =
-        (0..num_cpus::get())
+    let senders: Vec<JoinHandle<Result<(), S3Error>>> =
+        (0..concurrency)
             .map(|sender_thread| {
                 let rx_channel = rx_buffer.clone();
                 let tx_completedpart_channel = tx_completedpart.clone();
                 let upload_context = upload_context.clone();
                 tokio::spawn(async move {
                     while let Ok((part_count, buffer)) = rx_channel.recv().await {
-                        let content_md5 = base64::encode(md5::Md5::digest(&buffer));
+                        let content_md5_digest = md5::Md5::digest(&buffer).to_vec();
+                        let content_md5 = base64::encode(&content_md5_digest);
                         let buffer_size: usize = buffer.len();
 
                         let completed_part = retry!(
-                            |upload_context: UploadContext,
-                             buffer: Vec<u8>,
+                            max_retries = upload_context.max_retries;
+                            retries = upload_context.retries;
+                            |upload_context: UploadContext<C>,
+                             buffer: Bytes,
                              content_md5: String| async move {
+                                if let Some(throttle) = &upload_context.throttle {
+                                    throttle.acquire(buffer_size).await;
+                                }
                                 debug!(
                                 "  sender:Part start multipart upload s3://{}/{} - part {} - thread {}",
                                 upload_context.bucket,
@@ -183,20 +921,24 @@ where
                                 part_count,
                                 sender_thread
                             );
-                                let e_tag = upload_context
-                                    .client
-                                    .upload_part(rusoto_s3::UploadPartRequest {
+                                let e_tag = with_timeout(
+                                    upload_context.request_timeout,
+                                    upload_context.client.upload_part(rusoto_s3::UploadPartRequest {
                                         bucket: upload_context.bucket.to_string(),
                                         key: upload_context.key.to_string(),
                                         upload_id: upload_context.upload_id.to_string(),
-                                        body: { Some(ByteStream::from(buffer)) },
+                                        body: Some(ByteStream::new_with_size(
+                                            stream::once(future::ready(Ok(buffer))),
+                                            buffer_size,
+                                        )),
                                         content_length: Some(buffer_size.try_into().unwrap()),
                                         content_md5: Some(content_md5),
                                         part_number: part_count,
                                         ..Default::default()
-                                    })
-                                    .await
-                                    .map(|x| x.e_tag.unwrap());
+                                    }),
+                                )
+                                .await
+                                .map(|x| x.e_tag.unwrap())?;
                                 debug!(
                                     "  sender:Part completed multipart upload s3://{}/{} - part {} thread {}",
                                     &upload_context.bucket, &upload_context.key, part_count, sender_thread
@@ -205,7 +947,7 @@ where
                                     .data_sent
                                     .fetch_add(buffer_size, Ordering::SeqCst);
                                 Ok(rusoto_s3::CompletedPart {
-                                    e_tag: Some(e_tag.map_err(|x| x.to_string())?.clone()),
+                                    e_tag: Some(e_tag),
                                     part_number: Some(part_count),
                                 })
                             },
@@ -214,9 +956,9 @@ where
                             content_md5.clone()
                         );
                         tx_completedpart_channel
-                            .send(completed_part)
+                            .send(completed_part.map(|part| (part, content_md5_digest)))
                             .await
-                            .map_err(|x| x.to_string())?;
+                            .map_err(|x| S3Error::TaskFailed(x.to_string()))?;
                     }
                     Ok(())
                 })
@@ -224,27 +966,64 @@ where
             .collect();
     drop(tx_completedpart);
 
+    let mut content_sha256 = Sha256::new();
     {
         let mut part_count: i64 = 0;
         let mut stdout = BufReader::with_capacity(upload_context.buf_size, child.as_mut().stdout());
-        let stdout_ref = stdout.by_ref();
         loop {
             part_count = part_count + 1;
-            let (buffer, bytes_read) = {
-                let mut b = Vec::with_capacity(upload_context.buf_size);
-                let bytes_read = stdout_ref
-                    .take(b.capacity().try_into().unwrap())
-                    .read_to_end(&mut b)
-                    .unwrap();
-                (b, bytes_read)
+            // Reads block until zfs send writes more, keep them off the runtime's threads.
+            let read = tokio::task::spawn_blocking({
+                let buf_size = upload_context.buf_size;
+                let data_read = upload_context.data_read.clone();
+                move || read_part(&mut stdout, buf_size, &data_read).map(|b| (stdout, b))
+            });
+            tokio::pin!(read);
+            let (buffer, bytes_read) = loop {
+                tokio::select! {
+                    read = &mut read => {
+                        let (reader, b) = read.map_err(|x| S3Error::TaskFailed(x.to_string()))??;
+                        stdout = reader;
+                        let bytes_read = b.len();
+                        break (b, bytes_read);
+                    }
+                    _ = tokio::time::sleep(PROGRESS_INTERVAL) => {
+                        (callback)(upload_context.progress())
+                    }
+                }
             };
+            (callback)(upload_context.progress());
             while let Ok(result) = rx_completedpart.try_recv() {
                 // extra loop to make sure we exit early if a failure occures.
                 completed_parts.push(result?);
             }
             if bytes_read > 0 {
-                tx_buffer.send((part_count, buffer)).await?;
-                (callback)(upload_context.get_bytes_sent().try_into()?);
+                content_sha256.update(&buffer);
+                if let Some(uploaded_part) = upload_context.uploaded_part(part_count, &buffer) {
+                    debug!("  Part {} already uploaded, skipping", part_count);
+                    upload_context
+                        .data_sent
+                        .fetch_add(bytes_read, Ordering::SeqCst);
+                    completed_parts.push(uploaded_part);
+                    (callback)(upload_context.progress());
+                    continue;
+                }
+                let send = tx_buffer.send((part_count, Bytes::from(buffer)));
+                tokio::pin!(send);
+                // Keep reporting the parts being uploaded while every buffer is taken.
+                let sent = loop {
+                    tokio::select! {
+                        sent = &mut send => break sent,
+                        _ = tokio::time::sleep(PROGRESS_INTERVAL) => {
+                            (callback)(upload_context.progress())
+                        }
+                    }
+                };
+                if sent.is_err() {
+                    // All senders have exited, the reason is reported when joining them below.
+                    break;
+                }
+                (callback)(upload_context.progress());
             } else {
                 debug!("End of file reached");
                 break;
@@ -255,48 +1034,195 @@ where
 
     // Join all channels and confirm results are ok.
     for sender in future::join_all(senders).await {
-        let sender = sender?;
-        sender?;
+        sender.map_err(|x| S3Error::TaskFailed(x.to_string()))??;
     }
 
     let exit_status = child.wait()?;
     if !exit_status.success() {
         error!("zfs command exited with failure code {}", exit_status);
-        Err(Box::new(S3UploadFailedError("uploadparts".to_string(), format!("zfs command exited with error code {}", exit_status))))
+        Err(S3Error::CommandExited {
+            status: exit_status,
+            stderr: child.stderr(),
+        })
     } else {
         let completed_parts = {
             // finish building completed parts
             while let Ok(result) = rx_completedpart.recv().await {
                 completed_parts.push(result?);
-            }    
-            completed_parts.sort_by(|a, b| a.part_number.partial_cmp(&b.part_number).unwrap());
+            }
+            completed_parts
+                .sort_by(|(a, _), (b, _)| a.part_number.partial_cmp(&b.part_number).unwrap());
             completed_parts
         };
-        Ok(completed_parts)
+        Ok((completed_parts, format!("{:x}", content_sha256.finalize())))
     }
 }
 
-pub async fn upload_stdout_internal<'a, T: Read, F>(
-    client: &S3Client,
-    child: Box<dyn CommandStreamActions<T> + 'a>,
-    bucket: &str,
-    key: &str,
+/// Completes the multipart upload, and confirms the ETag of the resulting object matches the parts we sent.
+///
+/// SSE-KMS encrypted objects don't have MD5 based ETags, so `verify_etag` should be false for those.
+async fn complete_upload<C: S3Ops + Clone + 'static>(
+    upload_context: &UploadContext<C>,
+    completed_parts: Vec<UploadedPart>,
+) -> Result<u64, S3Error> {
+    debug!(
+        "  Completing file s3://{}/{}",
+        &upload_context.bucket, &upload_context.key
+    );
+    let (completed_parts, part_digests): (Vec<rusoto_s3::CompletedPart>, Vec<Vec<u8>>) =
+        completed_parts.into_iter().unzip();
+    let r: Result<(), S3Error> = retry!(
+        max_retries = upload_context.max_retries;
+        retries = upload_context.retries;
+        |upload_context: UploadContext<C>, completed_parts: Vec<rusoto_s3::CompletedPart>| async move {
+            with_timeout(
+                upload_context.request_timeout,
+                upload_context
+                    .client
+                    .complete_multipart_upload(rusoto_s3::CompleteMultipartUploadRequest {
+                        bucket: upload_context.bucket.clone(),
+                        key: upload_context.key.clone(),
+                        upload_id: upload_context.upload_id.clone(),
+                        multipart_upload: Some(rusoto_s3::CompletedMultipartUpload {
+                            parts: Some(completed_parts.clone()),
+                        }),
+                        ..Default::default()
+                    }),
+            )
+            .await?;
+            Ok(())
+        },
+        upload_context.clone(),
+        completed_parts.clone()
+    );
+    r?;
+
+    let r: Result<(Option<String>, Option<String>), S3Error> = retry!(
+        max_retries = upload_context.max_retries;
+        retries = upload_context.retries;
+        |upload_context: UploadContext<C>| async move {
+            let head = with_timeout(
+                upload_context.request_timeout,
+                upload_context.client.head_object(HeadObjectRequest {
+                    bucket: upload_context.bucket.clone(),
+                    key: upload_context.key.clone(),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+            Ok((head.e_tag, head.server_side_encryption))
+        },
+        upload_context.clone()
+    );
+    let (actual, server_side_encryption) = r?;
+    // Only unencrypted and SSE-S3 objects have an ETag derived from the MD5 of their parts. With
+    // SSE-KMS, also when it's the bucket default, it's unrelated to the content.
+    if matches!(server_side_encryption.as_deref(), None | Some("AES256")) {
+        let expected = multipart_etag(&part_digests);
+        let actual = actual.unwrap_or_default();
+        if actual != expected {
+            error!(
+                "ETag of s3://{}/{} is {}, expected {}",
+                &upload_context.bucket, &upload_context.key, actual, expected
+            );
+            // Left in place the object would count as backed up by every later sync.
+            let r: Result<(), RusotoError<DeleteObjectError>> = retry!(
+                max_retries = upload_context.max_retries;
+                retries = upload_context.retries;
+                |upload_context: UploadContext<C>| async move {
+                    with_timeout(
+                        upload_context.request_timeout,
+                        upload_context.client.delete_object(DeleteObjectRequest {
+                            bucket: upload_context.bucket.clone(),
+                            key: upload_context.key.clone(),
+                            ..Default::default()
+                        }),
+                    )
+                    .await?;
+                    Ok(())
+                },
+                upload_context.clone()
+            );
+            if let Err(err) = r {
+                error!(
+                    "Unable to delete s3://{}/{}, delete it by hand or sync will take it for a complete backup: {}",
+                    &upload_context.bucket, &upload_context.key, err
+                );
+            }
+            return Err(S3Error::ETagMismatch { expected, actual });
+        }
+    }
+    Ok(upload_context.get_bytes_sent() as u64)
+}
+
+/// Replaces the tags of the uploaded object with `tags` plus the SHA-256 of its content.
+async fn put_content_sha256_tag<C: S3Ops + Clone + 'static>(
+    upload_context: &UploadContext<C>,
     tags: Vec<Tag>,
-    storage_class: StorageClass,
+    content_sha256: String,
+) -> Result<(), S3Error> {
+    let mut tag_set = tags;
+    tag_set.push(Tag {
+        key: CONTENT_SHA256_TAG.to_string(),
+        value: content_sha256,
+    });
+    retry!(
+        max_retries = upload_context.max_retries;
+        retries = upload_context.retries;
+        |upload_context: UploadContext<C>, tag_set: Vec<Tag>| async move {
+            with_timeout(
+                upload_context.request_timeout,
+                upload_context.client.put_object_tagging(PutObjectTaggingRequest {
+                    bucket: upload_context.bucket.clone(),
+                    key: upload_context.key.clone(),
+                    tagging: Tagging { tag_set },
+                    ..Default::default()
+                }),
+            )
+            .await?;
+            Ok(())
+        },
+        upload_context.clone(),
+        tag_set.clone()
+    )
+}
+
+/// The object an upload creates.
+pub struct UploadTarget<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub tags: Vec<Tag>,
+    pub storage_class: StorageClass,
+}
+
+pub async fn upload_stdout_internal<'a, T: Read + Send + 'static, F, C: S3Ops + Clone + 'static>(
+    client: &C,
+    child: Box<dyn CommandStreamActions<T> + Send + 'a>,
+    target: UploadTarget<'_>,
+    options: &UploadOptions,
     callback: F,
     buf_size: usize,
-) -> Result<u64, Box<dyn Error>>
+) -> Result<u64, S3Error>
 where
-    F: Fn(u64) -> (),
+    F: Fn(UploadProgress) -> (),
 {
-    let tags = {
+    let UploadTarget {
+        bucket,
+        key,
+        tags,
+        storage_class,
+    } = target;
+    let tag_set = {
         let mut tags = tags;
         tags.push(rusoto_s3::Tag {
             key: "buffer_size".to_string(),
             value: buf_size.to_string(),
         });
+        tags
+    };
+    let tags = {
         let mut result = String::new();
-        for tag in tags {
+        for tag in &tag_set {
             if result.len() > 0 {
                 result.push('&');
             }
@@ -306,126 +1232,178 @@ where
         }
         result
     };
-    let upload_id: Result<String, Box<dyn Error>> = {
-        retry!(
-            |client: S3Client, bucket: String, key: String, tags: String| async move {
-                let upload_id = client
-                    .create_multipart_upload(CreateMultipartUploadRequest {
-                        bucket: bucket.clone(),
-                        key: key.clone(),
-                        storage_class: Some(storage_class.to_string()),
-                        tagging: Some(tags),
-                        ..Default::default()
-                    })
+    let resumable_upload = if options.resume {
+        get_resumable_upload(client, bucket, key, options.request_timeout)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(
+                    "  Failed to look up unfinished uploads of s3://{}/{}, starting over: {}",
+                    bucket, key, err
+                );
+                None
+            })
+    } else {
+        None
+    };
+    let (upload_id, existing_parts) = match resumable_upload {
+        Some((upload, parts)) => {
+            info!(
+                "  Resuming multipart upload s3://{}/{} ({} parts already uploaded)",
+                bucket,
+                key,
+                parts.len()
+            );
+            (upload.upload_id, parts)
+        }
+        None => {
+            let upload_id: Result<String, S3Error> = retry!(
+                max_retries = options.max_retries;
+                retries = options.retries;
+                |client: C, bucket: String, key: String, tags: String, options: UploadOptions| async move {
+                    let upload_id = with_timeout(
+                        options.request_timeout,
+                        client.create_multipart_upload(CreateMultipartUploadRequest {
+                            bucket: bucket.clone(),
+                            key: key.clone(),
+                            storage_class: Some(storage_class.to_string()),
+                            tagging: Some(tags),
+                            server_side_encryption: options.sse,
+                            ssekms_key_id: options.sse_kms_key_id,
+                            ..Default::default()
+                        }),
+                    )
                     .await
                     .map(|output| output.upload_id.unwrap())?;
-                Ok(upload_id)
-            },
-            client.clone(),
-            bucket.to_string(),
-            key.to_string(),
-            tags.clone()
-        )
+                    Ok(upload_id)
+                },
+                client.clone(),
+                bucket.to_string(),
+                key.to_string(),
+                tags.clone(),
+                options.clone()
+            );
+            (upload_id?, HashMap::new())
+        }
     };
     let upload_context = UploadContext {
         client: client.clone(),
         bucket: bucket.to_string(),
         key: key.to_string(),
-        upload_id: upload_id?.clone(),
+        upload_id,
+        data_read: Arc::new(AtomicUsize::new(0)),
         data_sent: Arc::new(AtomicUsize::new(0)),
-        buf_size: buf_size,
+        buf_size,
+        max_retries: options.max_retries,
+        retries: options.retries.clone(),
+        concurrency: options.concurrency,
+        buffered_parts: options.buffered_parts(),
+        throttle: options.throttle.clone(),
+        existing_parts: Arc::new(existing_parts),
+        request_timeout: options.request_timeout,
     };
+    // Resumable uploads are left in place when interrupted, so the next run can continue them.
+    let active_uploads = options.active_uploads.as_ref().filter(|_| !options.resume);
+    if let Some(active_uploads) = active_uploads {
+        active_uploads.insert(client, &upload_context.multipart_upload());
+    }
 
-    match upload_stdout_send_parts(upload_context.clone(), child, callback).await {
-        Ok(completed_parts) => {
-            debug!(
-                "  Completing file s3://{}/{}",
-                &upload_context.bucket, &upload_context.key
-            );
-            let r: Result<(), Box<dyn Error>> = retry!(
-                |upload_context: UploadContext, completed_parts: Vec<rusoto_s3::CompletedPart>| async move {
-                    upload_context
-                        .client
-                        .complete_multipart_upload(rusoto_s3::CompleteMultipartUploadRequest {
-                            bucket: upload_context.bucket.clone(),
-                            key: upload_context.key.clone(),
-                            upload_id: upload_context.upload_id.clone(),
-                            multipart_upload: Some(rusoto_s3::CompletedMultipartUpload {
-                                parts: Some(completed_parts.clone()),
-                            }),
-                            ..Default::default()
-                        })
-                        .await?;
-                    Ok(())
-                },
-                upload_context.clone(),
-                completed_parts.clone()
+    let result = match upload_stdout_send_parts(upload_context.clone(), child, callback).await {
+        Ok((completed_parts, content_sha256)) => {
+            async {
+                let bytes_sent = complete_upload(&upload_context, completed_parts).await?;
+                put_content_sha256_tag(&upload_context, tag_set, content_sha256).await?;
+                Ok(bytes_sent)
+            }
+            .await
+        }
+        Err(original_err) if options.resume => {
+            warn!(
+                "  Leaving multipart upload s3://{}/{} in place so the next run can resume it",
+                bucket, key
             );
-            r?;
-            Ok(upload_context.get_bytes_sent().try_into()?)
+            Err(original_err)
         }
         Err(original_err) => {
             warn!("  Aborting multipart upload file s3://{}/{}", bucket, key);
-            let r: Result<(), Box<dyn Error>> = retry!(
-                |upload_context: UploadContext| async move {
-                    client
-                        .abort_multipart_upload(rusoto_s3::AbortMultipartUploadRequest {
-                            bucket: upload_context.bucket.clone(),
-                            key: upload_context.key.clone(),
-                            upload_id: upload_context.upload_id.clone(),
-                            ..Default::default()
-                        })
-                        .await?;
-                    Ok(())
+            let r: Result<(), RusotoError<AbortMultipartUploadError>> = retry!(
+                max_retries = upload_context.max_retries;
+                |upload_context: UploadContext<C>| async move {
+                    with_timeout(
+                        upload_context.request_timeout,
+                        abort_multipart_upload(&upload_context.client, &upload_context.multipart_upload()),
+                    )
+                    .await
                 },
                 upload_context.clone()
             );
             match r {
-                Ok(_) => {
-                    Err(original_err)
-                }
+                Ok(_) => Err(original_err),
                 Err(err) => {
                     error!("Error during multipart upload, in addition abort_multipart_upload also failed: {}", err.to_string());
-                    Err(original_err)
+                    Err(S3Error::AbortFailed {
+                        original: Box::new(original_err),
+                        source: err,
+                    })
                 }
             }
         }
+    };
+    if let Some(active_uploads) = active_uploads {
+        active_uploads.remove(&upload_context.multipart_upload());
     }
+    result
 }
 
-pub async fn upload_stdout<'a, T: Read, F>(
-    client: &S3Client,
-    child: Box<dyn CommandStreamActions<T> + 'a>,
-    bucket: &str,
-    key: &str,
-    tags: Vec<Tag>,
-    storage_class: StorageClass,
-    estimated_size: usize,
+/// Size of the multipart parts used to upload a stream of roughly `estimated_size` bytes, None
+/// when the size is unknown.
+pub fn part_size(
+    estimated_size: Option<usize>,
+    configured: Option<usize>,
+) -> Result<usize, InvalidPartSize> {
+    match configured {
+        Some(part_size) if part_size < MIN_S3_PART_SIZE => Err(InvalidPartSize {
+            part_size,
+            reason: "S3 requires parts of at least 5MiB",
+        }),
+        Some(part_size)
+            if estimated_size
+                .is_some_and(|estimated_size| estimated_size / part_size >= MAX_S3_PART_COUNT) =>
+        {
+            Err(InvalidPartSize {
+                part_size,
+                reason: "the estimated size would need more than 10000 parts",
+            })
+        }
+        Some(part_size) => Ok(part_size),
+        None => {
+            let estimated_size = match estimated_size {
+                Some(estimated_size) => estimated_size,
+                None => return Ok(UNKNOWN_SIZE_PART_SIZE),
+            };
+            let mut buf_size = 8 * 1024 * 1024;
+            let safe_estimated_size = estimated_size * 2; // estimated_size can be compressed considerably..
+            loop {
+                if safe_estimated_size / buf_size < MAX_S3_PART_COUNT {
+                    break;
+                }
+                buf_size *= 2;
+            }
+            Ok(buf_size)
+        }
+    }
+}
+
+pub async fn upload_stdout<'a, T: Read + Send + 'static, F, C: S3Ops + Clone + 'static>(
+    client: &C,
+    child: Box<dyn CommandStreamActions<T> + Send + 'a>,
+    target: UploadTarget<'_>,
+    estimated_size: Option<usize>,
+    options: &UploadOptions,
     callback: F,
-) -> Result<u64, Box<dyn Error>>
+) -> Result<u64, S3Error>
 where
-    F: Fn(u64) -> (),
+    F: Fn(UploadProgress) -> (),
 {
-    let buf_size = {
-        let mut buf_size = 8 * 1024 * 1024;
-        let safe_estimated_size = estimated_size * 2; // estimated_size can be compressed considerably..
-        loop {
-            if safe_estimated_size / buf_size < MAX_S3_PART_COUNT {
-                break;
-            }
-            buf_size = buf_size * 2;
-        }
-        buf_size
-    };
-    Ok(upload_stdout_internal(
-        client,
-        child,
-        bucket,
-        key,
-        tags,
-        storage_class,
-        callback,
-        buf_size,
-    )
-    .await?)
+    let buf_size = part_size(estimated_size, options.part_size)?;
+    upload_stdout_internal(client, child, target, options, callback, buf_size).await
 }
@@ -0,0 +1,55 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use thiserror::Error;
+
+pub const DEFAULT_LOCK_PATH: &str = ".zfs-to-glacier.lock";
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("Another sync is running, it holds {} (use --wait to wait for it)", path.display())]
+    Held { path: PathBuf },
+    #[error("Unable to lock {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+}
+
+/// Exclusive lock on a file, held until dropped. Syncs from the same directory share the config,
+/// so two of them would upload the same backups concurrently.
+///
+/// The lock is released by the OS when the process exits, so a killed sync never leaves it behind.
+#[derive(Debug)]
+pub struct SyncLock {
+    _file: File,
+}
+
+impl SyncLock {
+    /// Locks `path`, creating it if needed. With `wait` this blocks until the holder releases it,
+    /// otherwise a held lock fails with `LockError::Held`.
+    pub fn acquire(path: &Path, wait: bool) -> Result<SyncLock, LockError> {
+        let io_error = |source| LockError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io_error)?;
+        if wait {
+            file.lock_exclusive().map_err(io_error)?;
+        } else if let Err(err) = file.try_lock_exclusive() {
+            if err.kind() == fs2::lock_contended_error().kind() {
+                return Err(LockError::Held {
+                    path: path.to_path_buf(),
+                });
+            }
+            return Err(io_error(err));
+        }
+        Ok(SyncLock { _file: file })
+    }
+}
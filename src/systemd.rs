@@ -0,0 +1,80 @@
+use std::{error::Error, fs, io, path::Path};
+
+use log::debug;
+
+pub const SERVICE_FILE: &str = "zfs-to-glacier.service";
+pub const TIMER_FILE: &str = "zfs-to-glacier.timer";
+
+/// Service running `sync` once. The config and estimate cache are read from the working
+/// directory, so the service runs from the directory the units were generated in.
+pub fn create_service(executable: &Path, working_directory: &Path) -> String {
+    format!(
+        "[Unit]
+Description=Sync ZFS backups to S3
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+WorkingDirectory={}
+ExecStart={} sync
+",
+        working_directory.display(),
+        executable.display()
+    )
+}
+
+/// Daily timer for the service. Persistent catches up on runs missed while the machine was off.
+pub fn create_timer() -> String {
+    "[Unit]
+Description=Sync ZFS backups to S3 daily
+
+[Timer]
+OnCalendar=daily
+RandomizedDelaySec=1h
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+"
+    .to_string()
+}
+
+/// Writes the service and timer into `output_dir`, the service running `executable` from
+/// `working_directory`. Nothing is written when either file exists, unless `force` is set.
+pub fn generate_systemd(
+    executable: &Path,
+    working_directory: &Path,
+    output_dir: &Path,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    let units = [
+        (
+            output_dir.join(SERVICE_FILE),
+            create_service(executable, working_directory),
+        ),
+        (output_dir.join(TIMER_FILE), create_timer()),
+    ];
+    if !force {
+        if let Some((path, _)) = units.iter().find(|(path, _)| path.exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Cowardly not creating {}, as the file already exists (use --force to overwrite)",
+                    path.display()
+                ),
+            )
+            .into());
+        }
+    }
+    debug!("Writing systemd units...");
+    for (path, contents) in &units {
+        fs::write(path, contents)?;
+        println!("{} written", path.display());
+    }
+    println!(
+        "Copy them to /etc/systemd/system and run `systemctl enable --now {}` to sync daily",
+        TIMER_FILE
+    );
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use std::{cmp::max, sync::Mutex, time::Duration};
+use tokio::time::{self, Instant};
+
+/// Token bucket limiting the combined throughput of every task sharing it.
+///
+/// Each caller reserves the time its bytes take at the configured rate, and waits until the
+/// reservations made before it have passed. Unused time does not accumulate into a burst.
+pub struct Throttle {
+    bytes_per_sec: u64,
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            bytes_per_sec: max(1, bytes_per_sec),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until `bytes` can be sent without exceeding the rate limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = max(*next_free, Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            start
+        };
+        time::sleep_until(start).await;
+    }
+}
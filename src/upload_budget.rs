@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid size '{0}', expected bytes or a number with a K, M, G or T suffix like 500G")]
+pub struct InvalidSizeError(pub String);
+
+/// Parses a byte count, with an optional binary K, M, G or T suffix (`500G` is 500GiB).
+pub fn parse_byte_size(value: &str) -> Result<u64, InvalidSizeError> {
+    let error = || InvalidSizeError(value.to_string());
+    let value = value.trim();
+    let (amount, multiplier) = match value.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => {
+            let exponent = match unit.to_ascii_uppercase() {
+                'K' => 1,
+                'M' => 2,
+                'G' => 3,
+                'T' => 4,
+                _ => return Err(error()),
+            };
+            (&value[..i], 1024u64.pow(exponent))
+        }
+        _ => (value, 1),
+    };
+    amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(multiplier))
+        .ok_or_else(error)
+}
+
+/// Bytes a sync may upload, shared by the datasets uploading in parallel.
+///
+/// Uploads reserve their estimated size before they start, and settle the reservation with the
+/// bytes actually uploaded. Once an upload doesn't fit no further uploads are started, so backups
+/// aren't uploaded out of order.
+pub struct UploadBudget {
+    max_bytes: u64,
+    used: AtomicU64,
+    exhausted: AtomicBool,
+}
+
+impl UploadBudget {
+    pub fn new(max_bytes: u64) -> UploadBudget {
+        UploadBudget {
+            max_bytes,
+            used: AtomicU64::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Reserves `estimated_size` bytes, false if they don't fit. Without an estimate an upload is
+    /// started as long as some budget is left.
+    pub fn reserve(&self, estimated_size: Option<usize>) -> bool {
+        if self.exhausted.load(Ordering::SeqCst) {
+            return false;
+        }
+        let reserved = self
+            .used
+            .fetch_update(
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+                |used| match estimated_size {
+                    Some(estimated_size) => used
+                        .checked_add(estimated_size as u64)
+                        .filter(|total| *total <= self.max_bytes),
+                    None if used < self.max_bytes => Some(used),
+                    None => None,
+                },
+            )
+            .is_ok();
+        if !reserved {
+            self.exhausted.store(true, Ordering::SeqCst);
+        }
+        reserved
+    }
+
+    /// Replaces the reservation of an upload with the bytes it uploaded, None if it failed.
+    pub fn settle(&self, estimated_size: Option<usize>, bytes_uploaded: Option<u64>) {
+        let estimated_size = estimated_size.unwrap_or(0) as u64;
+        let bytes_uploaded = bytes_uploaded.unwrap_or(0);
+        if bytes_uploaded >= estimated_size {
+            self.used
+                .fetch_add(bytes_uploaded - estimated_size, Ordering::SeqCst);
+        } else {
+            self.used
+                .fetch_sub(estimated_size - bytes_uploaded, Ordering::SeqCst);
+        }
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Whether an upload didn't fit, and later ones weren't started.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::SeqCst)
+    }
+}
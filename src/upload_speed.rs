@@ -0,0 +1,79 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_UPLOAD_SPEED_PATH: &str = ".zfs-to-glacier-speed.json";
+
+/// Weight of the newest upload in the moving average.
+const SMOOTHING: f64 = 0.3;
+/// Uploads shorter than this are mostly request latency, they'd drag the average down.
+const MIN_RECORDED_DURATION: Duration = Duration::from_secs(10);
+/// How long an upload runs before its own rate is trusted over the one of earlier runs.
+const WARMUP: Duration = Duration::from_secs(60);
+
+/// Exponentially weighted moving average of the upload throughput of earlier runs, so the ETA of
+/// a new upload is stable from the start instead of following its first few parts.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UploadSpeed {
+    bytes_per_sec: Option<f64>,
+}
+
+impl UploadSpeed {
+    /// Reads the average at `path`, starting without one when it's missing or unreadable.
+    pub fn load(path: &Path) -> UploadSpeed {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                warn!("Ignoring upload speed {}: {}", path.display(), err);
+                UploadSpeed::default()
+            }),
+            Err(_) => UploadSpeed::default(),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        self.bytes_per_sec
+    }
+
+    /// Adds an upload of `bytes` that took `elapsed` to the average.
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        if elapsed < MIN_RECORDED_DURATION {
+            return;
+        }
+        let observed = bytes as f64 / elapsed.as_secs_f64();
+        self.bytes_per_sec = Some(match self.bytes_per_sec {
+            Some(average) => average + SMOOTHING * (observed - average),
+            None => observed,
+        });
+    }
+
+    /// Time left for an upload that uploaded `uploaded` bytes in `elapsed` and has `remaining`
+    /// bytes to go. Starts from the average, shifting to the upload's own rate over the first
+    /// minute. None while there is nothing to base it on.
+    pub fn eta(&self, uploaded: u64, remaining: u64, elapsed: Duration) -> Option<Duration> {
+        let weight = (elapsed.as_secs_f64() / WARMUP.as_secs_f64()).min(1.0);
+        let current = if elapsed.as_secs_f64() > 0.0 && uploaded > 0 {
+            Some(uploaded as f64 / elapsed.as_secs_f64())
+        } else {
+            None
+        };
+        let rate = match (self.bytes_per_sec, current) {
+            (Some(average), Some(current)) => average + weight * (current - average),
+            (Some(average), None) => average,
+            (None, Some(current)) => current,
+            (None, None) => return None,
+        };
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+
+    /// Writes the average through a temporary file, so an interrupted write doesn't lose it.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(&tmp_path, path)
+    }
+}
@@ -1,8 +1,14 @@
 use crate::cmd_execute::*;
 use chrono::prelude::*;
+use log::warn;
 use std::fmt;
 use std::str;
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io,
+};
+use thiserror::Error;
 
 #[derive(Hash, Clone, Eq, PartialEq, Debug)]
 pub struct ZfsSnapshot {
@@ -21,40 +27,176 @@ impl fmt::Display for ZfsSnapshot {
     }
 }
 
+pub const DEFAULT_ZFS_COMMAND: &str = "zfs";
+
 pub struct LocalZfsState {
     pub pools: HashMap<String, Vec<ZfsSnapshot>>,
+    /// Bookmarks per pool, named `pool#bookmark`. Only listed when requested.
+    pub bookmarks: HashMap<String, Vec<ZfsSnapshot>>,
+    /// Command used to run zfs, backups of these pools are sent with the same command.
+    pub zfs_command: String,
+    /// Encrypted filesystems and volumes, their backups are always sent with `-w`.
+    pub encrypted: HashSet<String>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Unable to parse zfs list output '{line}': {reason}")]
+pub struct ZfsListError {
+    pub line: String,
+    pub reason: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum ZfsUnavailableError {
+    #[error("'{command}' was not found, is zfs installed and on the PATH?")]
+    NotFound { command: String },
+    #[error("'{command} version' failed, is the zfs module loaded and are we permitted to run zfs? {reason}")]
+    Failed { command: String, reason: String },
 }
 
-pub fn get_local_zfs_state() -> Result<LocalZfsState, Box<dyn Error>> {
-    let pools = { ExecutorCommand("zfs list -Hp -o name".to_string()).execute_by_line() }?;
-
-    let snapshots = {
-        ExecutorCommand("zfs list -Hpt snapshot -o name,creation -s creation".to_string())
-            .execute_by_line()
-            .map(|lines| {
-                lines
-                    .iter()
-                    .map(|x| {
-                        let s: Vec<&str> = x.split("\t").collect();
-                        ZfsSnapshot {
-                            name: s[0].to_string(),
-                            creation: Local.timestamp(s[1].parse::<i64>().unwrap(), 0),
-                        }
-                    })
-                    .collect::<Vec<ZfsSnapshot>>()
-            })
-    }?;
+/// Runs `zfs version`, so a missing or unusable zfs is reported clearly before doing any work.
+pub fn check_zfs_available(zfs_command: &str) -> Result<String, ZfsUnavailableError> {
+    let command = ExecutorCommand(format!("{} version", zfs_command));
+    command.execute().map_err(|err| {
+        let program = command
+            .arguments()
+            .and_then(|arguments| arguments.into_iter().next())
+            .unwrap_or_default();
+        match err.downcast_ref::<io::Error>() {
+            Some(io_err) if io_err.kind() == io::ErrorKind::NotFound => {
+                ZfsUnavailableError::NotFound { command: program }
+            }
+            _ => ZfsUnavailableError::Failed {
+                command: zfs_command.to_string(),
+                reason: err.to_string(),
+            },
+        }
+    })
+}
 
+/// Parses a `name<TAB>creation` line of `zfs list -Hp -o name,creation`.
+pub fn parse_list_line(line: &str) -> Result<ZfsSnapshot, ZfsListError> {
+    let error = |reason| ZfsListError {
+        line: line.to_string(),
+        reason,
+    };
+    let s: Vec<&str> = line.split('\t').collect();
+    if s.len() != 2 || s[0].is_empty() {
+        return Err(error("expected a name and a creation time"));
+    }
+    let creation = s[1]
+        .parse::<i64>()
+        .map_err(|_| error("creation time is not a number"))?;
+    let creation = Local
+        .timestamp_opt(creation, 0)
+        .single()
+        .ok_or_else(|| error("creation time is out of range"))?;
+    Ok(ZfsSnapshot {
+        name: s[0].to_string(),
+        creation,
+    })
+}
+
+/// Lists snapshots or bookmarks (`list_type`) with their creation time, oldest first.
+fn list_by_creation(
+    zfs_command: &str,
+    list_type: &str,
+) -> Result<Vec<ZfsSnapshot>, Box<dyn Error>> {
+    let lines = ExecutorCommand(format!(
+        "{} list -Hpt {} -o name,creation -s creation",
+        zfs_command, list_type
+    ))
+    .execute_by_line()?;
+    Ok(lines
+        .iter()
+        .map(|x| parse_list_line(x))
+        .collect::<Result<Vec<ZfsSnapshot>, ZfsListError>>()?)
+}
+
+fn group_by_pool(
+    pools: &[String],
+    entries: &[ZfsSnapshot],
+    separator: char,
+) -> HashMap<String, Vec<ZfsSnapshot>> {
     let mut result: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
     for pool in pools {
         let mut pool_start = pool.to_owned();
-        pool_start.push_str("@");
-        let snapshots_for_pool: Vec<ZfsSnapshot> = snapshots
+        pool_start.push(separator);
+        let entries_for_pool: Vec<ZfsSnapshot> = entries
             .iter()
             .filter(|x| x.name.starts_with(&pool_start))
             .map(|x| x.to_owned())
             .collect();
-        result.insert(pool, snapshots_for_pool);
+        result.insert(pool.to_owned(), entries_for_pool);
+    }
+    result
+}
+
+/// `query_encryption` looks up the encrypted datasets, only needed when some snapshots are sent
+/// without `-w`.
+pub fn get_local_zfs_state(
+    zfs_command: &str,
+    list_bookmarks: bool,
+    query_encryption: bool,
+) -> Result<LocalZfsState, Box<dyn Error>> {
+    let pools = { ExecutorCommand(format!("{} list -Hp -o name", zfs_command)).execute_by_line() }?;
+    let snapshots = list_by_creation(zfs_command, "snapshot")?;
+    let bookmarks = if list_bookmarks {
+        group_by_pool(&pools, &list_by_creation(zfs_command, "bookmark")?, '#')
+    } else {
+        HashMap::new()
+    };
+    Ok(LocalZfsState {
+        pools: group_by_pool(&pools, &snapshots, '@'),
+        bookmarks,
+        zfs_command: zfs_command.to_string(),
+        encrypted: if query_encryption {
+            get_encrypted_datasets(zfs_command)?
+        } else {
+            HashSet::new()
+        },
+    })
+}
+
+/// Parses a `name<TAB>encryption` line of `zfs get -Hp -o name,value encryption`, returning the
+/// dataset and whether it's encrypted. Only ciphers count, zfs prints `-` where the property
+/// doesn't apply.
+pub fn parse_encryption_line(line: &str) -> Result<(String, bool), ZfsListError> {
+    match line.split('\t').collect::<Vec<&str>>().as_slice() {
+        [name, encryption] if !name.is_empty() && !encryption.is_empty() => {
+            Ok((name.to_string(), encryption.starts_with("aes-")))
+        }
+        _ => Err(ZfsListError {
+            line: line.to_string(),
+            reason: "expected a name and an encryption algorithm",
+        }),
+    }
+}
+
+/// Filesystems and volumes that are encrypted. None are on zfs without native encryption, which
+/// fails to get the property.
+pub fn get_encrypted_datasets(zfs_command: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let lines = match ExecutorCommand(format!(
+        "{} get -Hp -t filesystem,volume -o name,value encryption",
+        zfs_command
+    ))
+    .execute_by_line()
+    {
+        Ok(lines) => lines,
+        Err(err) => {
+            warn!(
+                "Unable to get the encryption of datasets, assuming none are encrypted: {}",
+                err
+            );
+            return Ok(HashSet::new());
+        }
+    };
+    let mut result = HashSet::new();
+    for line in lines {
+        let (name, encrypted) = parse_encryption_line(&line)?;
+        if encrypted {
+            result.insert(name);
+        }
     }
-    Ok(LocalZfsState { pools: result })
+    Ok(result)
 }
@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::fs;
+use zfs_to_glacier::{
+    cloudformation::{create_cloudformation, generate_cloudformation},
+    config::parse_config,
+};
+
+const CONFIG: &str = "
+configs:
+- pool_regex: \"rpool/.*\"
+  incremental:
+    snapshot_regex: \"daily\"
+    storage_class: \"StandardInfrequentAccess\"
+    expire_in_days: 40
+  full:
+    snapshot_regex: \"monthly\"
+    storage_class: \"DeepArchive\"
+    expire_in_days: 200
+  bucket: \"zfs-rpool\"
+";
+
+#[test]
+fn test_expiration_rules() -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(&parse_config(CONFIG)?);
+    assert!(cloudformation.contains("Id: DeleteFull"));
+    assert!(cloudformation.contains("ExpirationInDays: 200"));
+    assert!(cloudformation.contains("Id: DeleteIncremental"));
+    assert!(cloudformation.contains("ExpirationInDays: 40"));
+    Ok(())
+}
+
+#[test]
+fn test_never_expire_omits_rule() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&CONFIG.replace("expire_in_days: 200", "expire_in_days: 0"))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(!cloudformation.contains("Id: DeleteFull"));
+    assert!(!cloudformation.contains("ExpirationInDays: 0"));
+    assert!(cloudformation.contains("Id: DeleteIncremental"));
+    Ok(())
+}
+
+#[test]
+fn test_transition_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&CONFIG.replace(
+        "expire_in_days: 40",
+        "expire_in_days: 0\n    transition_to: \"DeepArchive\"\n    transition_after_days: 30",
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains(
+        "          - Id: TransitionIncremental
+            Prefix: 'incremental/'
+            Status: Enabled
+            Transitions:
+              - StorageClass: DEEP_ARCHIVE
+                TransitionInDays: 30
+"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_versioning() -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(&parse_config(CONFIG)?);
+    assert!(!cloudformation.contains("VersioningConfiguration"));
+    assert!(!cloudformation.contains("NoncurrentVersionExpirationInDays"));
+
+    let config = parse_config(&format!("{}  versioning: true\n", CONFIG))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains("      VersioningConfiguration:\n        Status: Enabled\n"));
+    assert!(cloudformation.contains("NoncurrentVersionExpirationInDays: 30"));
+    Ok(())
+}
+
+#[test]
+fn test_generate_cloudformation_existing_file() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    let path =
+        std::env::temp_dir().join(format!("cloudformation_test_{}.yaml", std::process::id()));
+    fs::write(&path, "existing")?;
+
+    assert!(generate_cloudformation(&config, Some(path.clone()), false).is_err());
+    assert_eq!(fs::read_to_string(&path)?, "existing");
+
+    generate_cloudformation(&config, Some(path.clone()), true)?;
+    assert_eq!(fs::read_to_string(&path)?, create_cloudformation(&config));
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_kms_policy() -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(&parse_config(CONFIG)?);
+    assert!(!cloudformation.contains("kms:GenerateDataKey"));
+
+    let config = parse_config(&format!(
+        "{}  sse: \"aws:kms\"\n  sse_kms_key_id: \"1234abcd-12ab-34cd-56ef-1234567890ab\"\n",
+        CONFIG
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains("kms:GenerateDataKey"));
+    assert!(cloudformation.contains(
+        "- !Sub 'arn:aws:kms:${AWS::Region}:${AWS::AccountId}:key/1234abcd-12ab-34cd-56ef-1234567890ab'"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_shared_bucket_created_once() -> Result<(), Box<dyn Error>> {
+    let second = CONFIG
+        .replace("configs:\n", "")
+        .replace("rpool/.*", "tank/.*");
+    let cloudformation = create_cloudformation(&parse_config(&format!("{}{}", CONFIG, second))?);
+    assert_eq!(cloudformation.matches("BucketName: 'zfs-rpool'").count(), 1);
+    assert_eq!(cloudformation.matches("'zfs-rpool/*'").count(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_key_prefix_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!("{}  key_prefix: \"host1/\"\n", CONFIG))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains("Prefix: 'host1/full/'"));
+    assert!(cloudformation.contains("Prefix: 'host1/incremental/'"));
+    assert!(!cloudformation.contains("Prefix: 'full/'"));
+    Ok(())
+}
+
+#[test]
+fn test_key_template_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  key_template: \"backups/{{type}}/{{year}}/{{name}}\"\n",
+        CONFIG
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains("Prefix: 'backups/full/'"));
+    assert!(cloudformation.contains("Prefix: 'backups/incremental/'"));
+    Ok(())
+}
+
+#[test]
+fn test_date_first_key_template_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  key_template: \"{{year}}/{{month}}/{{type}}/{{name}}\"\n",
+        CONFIG
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(!cloudformation.contains("Prefix:"));
+    assert!(cloudformation.contains("- Key: backup_type\n                Value: full\n"));
+    assert!(cloudformation.contains("- Key: backup_type\n                Value: incremental\n"));
+    Ok(())
+}
+
+#[test]
+fn test_abort_incomplete_uploads_rule() -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(&parse_config(CONFIG)?);
+    assert!(cloudformation.contains("DaysAfterInitiation: 7"));
+    let config = parse_config(&format!(
+        "{}  abort_incomplete_uploads_after_days: 30\n",
+        CONFIG
+    ))?;
+    assert!(create_cloudformation(&config).contains("DaysAfterInitiation: 30"));
+    let config = parse_config(&format!(
+        "{}  abort_incomplete_uploads_after_days: 0\n",
+        CONFIG
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(!cloudformation.contains("AbortIncompleteMultipartUpload"));
+    assert!(cloudformation.contains("Id: DeleteFull"));
+    Ok(())
+}
+
+#[test]
+fn test_no_lifecycle_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  abort_incomplete_uploads_after_days: 0\n",
+        CONFIG
+            .replace("expire_in_days: 200", "expire_in_days: 0")
+            .replace("expire_in_days: 40", "expire_in_days: 0")
+    ))?;
+    assert!(!create_cloudformation(&config).contains("LifecycleConfiguration"));
+    Ok(())
+}
+
+#[test]
+fn test_storage_class_comments() -> Result<(), Box<dyn Error>> {
+    let cloudformation = create_cloudformation(&parse_config(CONFIG)?);
+    assert!(cloudformation.contains(
+        "# Uploaded as DEEP_ARCHIVE. DEEP_ARCHIVE objects have to be restored with restore-object before they can be downloaded.\n          - Id: DeleteFull"
+    ));
+    assert!(
+        cloudformation.contains("# Uploaded as STANDARD_IA.\n          - Id: DeleteIncremental")
+    );
+    let config = parse_config(&CONFIG.replace(
+        "expire_in_days: 40",
+        "expire_in_days: 40\n    transition_to: \"Glacier\"\n    transition_after_days: 30",
+    ))?;
+    assert!(create_cloudformation(&config).contains(
+        "# Uploaded as STANDARD_IA, moved to GLACIER after 30 days. GLACIER objects have to be restored"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_intelligent_tiering_skips_transition() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&CONFIG.replace(
+        "storage_class: \"StandardInfrequentAccess\"\n    expire_in_days: 40",
+        "storage_class: \"IntelligentTiering\"\n    expire_in_days: 40\n    transition_to: \"DeepArchive\"\n    transition_after_days: 30",
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation
+        .contains("# Uploaded as INTELLIGENT_TIERING.\n          - Id: DeleteIncremental"));
+    assert!(!cloudformation.contains("Transitions:"));
+    Ok(())
+}
+
+#[test]
+fn test_incremental_tier_rules() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  incremental_tiers:\n  - snapshot_regex: \"hourly\"\n    storage_class: \"STANDARD\"\n    expire_in_days: 7\n",
+        CONFIG
+    ))?;
+    let cloudformation = create_cloudformation(&config);
+    assert!(cloudformation.contains(
+        "          - Id: DeleteIncremental1
+            Prefix: 'incremental-1/'
+            Status: Enabled
+            ExpirationInDays: 7
+"
+    ));
+    assert!(cloudformation.contains("Prefix: 'incremental/'"));
+    Ok(())
+}
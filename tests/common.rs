@@ -3,12 +3,15 @@ use log::info;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use rusoto_core::Region;
-use rusoto_s3::{CreateBucketRequest, GetObjectRequest, GetObjectTaggingRequest, S3, S3Client};
+use rusoto_s3::{CreateBucketRequest, GetObjectRequest, GetObjectTaggingRequest, S3Client, S3};
 use std::env;
 use std::error::Error;
-use std::{str};
-use zfs_to_glacier::{compute_backups::S3Backup, s3_utils::StorageClass, zfs_utils::ZfsSnapshot};
+use std::str;
 use tokio::io::AsyncReadExt;
+use zfs_to_glacier::{
+    compute_backups::S3Backup, key_template::KeyTemplate, s3_utils::StorageClass,
+    zfs_utils::ZfsSnapshot,
+};
 
 pub const ACCESS_KEY: &str = "minio";
 pub const SECRET_KEY: &str = "minio1234";
@@ -34,42 +37,39 @@ pub fn generate_unique_name() -> String {
 
 #[macro_export]
 macro_rules! execute_in_docker {
-    ($closure:tt) =>  {
-        {
-            use images::generic::WaitFor;
-            use testcontainers::*;
-            use std::env;
-            let docker = clients::Cli::default();
-            let image = images::generic::GenericImage::new(format!("{}:{}", "minio/minio", "latest"))
-                .with_mapped_port((9000, 9000))
-                .with_env_var("MINIO_ROOT_USER", ACCESS_KEY)
-                .with_env_var("MINIO_ROOT_PASSWORD", SECRET_KEY)
-                .with_wait_for(WaitFor::LogMessage {
-                    message: "Console:".to_string(),
-                    stream: images::generic::Stream::StdOut,
-                })
-                .with_args(vec!["server".to_string(), "/data".to_string()]);
-            env::set_var("AWS_ACCESS_KEY_ID", ACCESS_KEY);
-            env::set_var("AWS_SECRET_ACCESS_KEY", SECRET_KEY);
-            env::set_var("S3_ENDPOINT_URL", ENDPOINT);
-            let container = docker.run(image);
-            let result = $closure().await;
-            container.stop();
-            if result.is_err() {
-                let mut buffer = String::new();
-                container.logs().stdout.read_to_string(&mut buffer).unwrap();
-                println!(" - * - * - * - * - * - * - * - * - * - * - * - *");
-                println!("Error in stdout, dumping container logs:");
-                println!("Stdout: {}", buffer);
-                buffer.clear();
-                container.logs().stderr.read_to_string(&mut buffer).unwrap();
-                println!("stderr: {}", buffer);
-                println!(" - * - * - * - * - * - * - * - * - * - * - * - *");
-            }
-            result
+    ($closure:tt) => {{
+        use images::generic::WaitFor;
+        use std::env;
+        use testcontainers::*;
+        let docker = clients::Cli::default();
+        let image = images::generic::GenericImage::new(format!("{}:{}", "minio/minio", "latest"))
+            .with_mapped_port((9000, 9000))
+            .with_env_var("MINIO_ROOT_USER", ACCESS_KEY)
+            .with_env_var("MINIO_ROOT_PASSWORD", SECRET_KEY)
+            .with_wait_for(WaitFor::LogMessage {
+                message: "Console:".to_string(),
+                stream: images::generic::Stream::StdOut,
+            })
+            .with_args(vec!["server".to_string(), "/data".to_string()]);
+        env::set_var("AWS_ACCESS_KEY_ID", ACCESS_KEY);
+        env::set_var("AWS_SECRET_ACCESS_KEY", SECRET_KEY);
+        env::set_var("S3_ENDPOINT_URL", ENDPOINT);
+        let container = docker.run(image);
+        let result = $closure().await;
+        container.stop();
+        if result.is_err() {
+            let mut buffer = String::new();
+            container.logs().stdout.read_to_string(&mut buffer).unwrap();
+            println!(" - * - * - * - * - * - * - * - * - * - * - * - *");
+            println!("Error in stdout, dumping container logs:");
+            println!("Stdout: {}", buffer);
+            buffer.clear();
+            container.logs().stderr.read_to_string(&mut buffer).unwrap();
+            println!("stderr: {}", buffer);
+            println!(" - * - * - * - * - * - * - * - * - * - * - * - *");
         }
-        
-    };
+        result
+    }};
 }
 
 pub async fn create_client(bucket: &str) -> Result<S3Client, Box<dyn Error>> {
@@ -87,7 +87,11 @@ pub async fn create_client(bucket: &str) -> Result<S3Client, Box<dyn Error>> {
     Ok(client)
 }
 
-pub async fn download_file(bucket: &str, key: &str, client: &S3Client) -> Result<String, Box<dyn Error>> {
+pub async fn download_file(
+    bucket: &str,
+    key: &str,
+    client: &S3Client,
+) -> Result<String, Box<dyn Error>> {
     info!("Downloading file s3://{}/{}", bucket, key);
     let request = client
         .get_object(GetObjectRequest {
@@ -102,14 +106,20 @@ pub async fn download_file(bucket: &str, key: &str, client: &S3Client) -> Result
     Ok(str::from_utf8(&buffer)?.to_string())
 }
 
-pub async fn get_tags(bucket: &str, key: &str, client: &S3Client) -> Result<Vec<rusoto_s3::Tag>, Box<dyn Error>> {
-    let request = client.get_object_tagging(GetObjectTaggingRequest {
-        bucket: bucket.to_string(),
+pub async fn get_tags(
+    bucket: &str,
+    key: &str,
+    client: &S3Client,
+) -> Result<Vec<rusoto_s3::Tag>, Box<dyn Error>> {
+    let request = client
+        .get_object_tagging(GetObjectTaggingRequest {
+            bucket: bucket.to_string(),
             key: key.to_string(),
             ..Default::default()
-    }).await?;
+        })
+        .await?;
     let mut tagset = request.tag_set;
-    tagset.sort_by(|a,b| a.key.partial_cmp(&b.key).unwrap());    
+    tagset.sort_by(|a, b| a.key.partial_cmp(&b.key).unwrap());
     Ok(tagset)
 }
 
@@ -148,8 +158,14 @@ impl S3BackupTesting for S3Backup {
                 creation: Local::now().date().and_hms(0, 0, 0) - time_since_now,
             },
             parent: parent,
+            parent_key: None,
             storage_class: StorageClass::DeepArchive,
             bucket: bucket.to_string(),
+            zfs_command: "zfs".to_string(),
+            send_flags: "w".to_string(),
+            key_prefix: String::new(),
+            key_template: KeyTemplate::default(),
+            tier: 0,
         })
     }
 }
@@ -0,0 +1,630 @@
+use chrono::{Local, TimeZone, Utc};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+use zfs_to_glacier::{
+    compute_backups::{
+        creation_date_matches, get_pending_actions, get_pending_actions_since,
+        get_pending_actions_with_remote, is_truncated, parse_estimated_size, parse_since,
+        snapshot_age, S3Backup, S3BackupCommand,
+    },
+    config::parse_config,
+    s3_utils::StorageClass,
+    zfs_utils::{LocalZfsState, ZfsSnapshot},
+};
+
+const CONFIG: &str = "
+configs:
+- pool_regex: \"tank/.*\"
+  exclude_regex: \"tank/(tmp|scratch)\"
+  incremental:
+    snapshot_regex: \"daily\"
+    storage_class: \"StandardInfrequentAccess\"
+    expire_in_days: 40
+  full:
+    snapshot_regex: \"monthly\"
+    storage_class: \"DeepArchive\"
+    expire_in_days: 200
+  bucket: \"zfs-tank\"
+";
+
+fn local_state(pools: &[&str]) -> LocalZfsState {
+    let mut state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
+    for pool in pools {
+        state.insert(
+            pool.to_string(),
+            vec![ZfsSnapshot {
+                name: format!("{}@monthly", pool),
+                creation: Local::now(),
+            }],
+        );
+    }
+    LocalZfsState {
+        pools: state,
+        bookmarks: HashMap::new(),
+        zfs_command: "sudo zfs".to_string(),
+        encrypted: HashSet::new(),
+    }
+}
+
+#[test]
+fn test_parse_key() {
+    assert_eq!(
+        S3Backup::parse_key("full/rpool/data_AT_daily-2021-01-01"),
+        Some(("rpool/data@daily-2021-01-01".to_string(), false))
+    );
+    assert_eq!(
+        S3Backup::parse_key("incremental/rpool/data_AT_daily-2021-01-02"),
+        Some(("rpool/data@daily-2021-01-02".to_string(), true))
+    );
+}
+
+#[test]
+fn test_parse_key_with_underscores() {
+    assert_eq!(
+        S3Backup::parse_key("full/rpool/my_data_set_AT_zfs_auto_snap_daily"),
+        Some(("rpool/my_data_set@zfs_auto_snap_daily".to_string(), false))
+    );
+    assert_eq!(
+        S3Backup::parse_key("full/rpool/A_T_AT__AT"),
+        Some(("rpool/A_T@_AT".to_string(), false))
+    );
+}
+
+#[test]
+fn test_parse_key_percent_encoded() {
+    assert_eq!(
+        S3Backup::parse_key("full/rpool/my_AT_data%40snap_AT_1"),
+        Some(("rpool/my_AT_data@snap_AT_1".to_string(), false))
+    );
+    assert_eq!(
+        S3Backup::parse_key("incremental/rpool/data%40daily%202021"),
+        Some(("rpool/data@daily 2021".to_string(), true))
+    );
+    assert_eq!(S3Backup::parse_key("full/rpool/data%40a%40b"), None);
+    assert_eq!(S3Backup::parse_key("full/%40snap"), None);
+}
+
+#[test]
+fn test_parse_key_ambiguous() {
+    assert_eq!(S3Backup::parse_key("full/rpool/my_AT_data_AT_snap"), None);
+}
+
+#[test]
+fn test_parse_key_invalid() {
+    assert_eq!(S3Backup::parse_key("rpool/data_AT_snap"), None);
+    assert_eq!(S3Backup::parse_key("full/rpool/data"), None);
+    assert_eq!(S3Backup::parse_key("full/_AT_snap"), None);
+}
+
+#[test]
+fn test_pending_actions_exclude_regex() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    let state = local_state(&["tank/data", "tank/tmp", "tank/scratch"]);
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    let names: Vec<&str> = actions.iter().map(|x| x.snapshot.name.as_str()).collect();
+    assert_eq!(names, vec!["tank/data@monthly"]);
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_never_expire() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    for snapshot in state.pools.values_mut().flatten() {
+        snapshot.creation = Local::now() - chrono::Duration::days(1000);
+    }
+    let config = parse_config(CONFIG)?;
+    assert!(get_pending_actions(&state, &config.configs[0]).is_empty());
+    let config = parse_config(&CONFIG.replace("expire_in_days: 200", "expire_in_days: 0"))?;
+    assert_eq!(get_pending_actions(&state, &config.configs[0]).len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_future_creation() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let now = Local::now();
+    for snapshot in state.pools.values_mut().flatten() {
+        snapshot.creation = now + chrono::Duration::days(1000);
+        assert_eq!(snapshot_age(snapshot, now), chrono::Duration::zero());
+    }
+    let config = parse_config(CONFIG)?;
+    assert_eq!(get_pending_actions(&state, &config.configs[0]).len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_backup_cmd_uses_zfs_command() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    let actions = get_pending_actions(&local_state(&["tank/data"]), &config.configs[0]);
+    assert_eq!(
+        actions[0].backup_cmd(false),
+        "sudo zfs send -Pw tank/data@monthly"
+    );
+    assert_eq!(
+        actions[0].backup_cmd(true),
+        "sudo zfs send -Pwvn tank/data@monthly"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_backup_cmd_send_flags() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&CONFIG.replace(
+        "expire_in_days: 200",
+        "expire_in_days: 200\n    send_flags: \"Le\"",
+    ))?;
+    let actions = get_pending_actions(&local_state(&["tank/data"]), &config.configs[0]);
+    assert_eq!(
+        actions[0].backup_cmd(false),
+        "sudo zfs send -PLe tank/data@monthly"
+    );
+    assert_eq!(
+        actions[0].backup_cmd(true),
+        "sudo zfs send -PLevn tank/data@monthly"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_encrypted_sent_raw() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data", "tank/plain"]);
+    state.encrypted.insert("tank/data".to_string());
+    let config = parse_config(
+        &CONFIG.replace("expire_in_days: 200", "expire_in_days: 200\n    raw: false"),
+    )?;
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    assert_eq!(
+        actions[0].backup_cmd(false),
+        "sudo zfs send -Pw tank/data@monthly"
+    );
+    assert_eq!(
+        actions[1].backup_cmd(false),
+        "sudo zfs send -P tank/plain@monthly"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_recursive() -> Result<(), Box<dyn Error>> {
+    let state = local_state(&["tank/data", "tank/data/child", "tank/other"]);
+    let config = parse_config(CONFIG)?;
+    assert_eq!(get_pending_actions(&state, &config.configs[0]).len(), 3);
+
+    let config = parse_config(&CONFIG.replace(
+        "expire_in_days: 200",
+        "expire_in_days: 200\n    recursive: true",
+    ))?;
+    let mut actions = get_pending_actions(&state, &config.configs[0]);
+    actions.sort_by(|a, b| a.snapshot.name.cmp(&b.snapshot.name));
+    let commands: Vec<String> = actions.iter().map(|x| x.backup_cmd(false)).collect();
+    assert_eq!(
+        commands,
+        vec![
+            "sudo zfs send -PwR tank/data@monthly",
+            "sudo zfs send -PwR tank/other@monthly"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_bookmark_parent() -> Result<(), Box<dyn Error>> {
+    let snapshot = |name: &str, days: i64| ZfsSnapshot {
+        name: name.to_string(),
+        creation: Local::now() - chrono::Duration::days(days),
+    };
+    let mut state = local_state(&[]);
+    state.pools.insert(
+        "tank/data".to_string(),
+        vec![
+            snapshot("tank/data@monthly", 3),
+            snapshot("tank/data@daily2", 1),
+        ],
+    );
+    state.bookmarks.insert(
+        "tank/data".to_string(),
+        vec![
+            snapshot("tank/data#monthly", 3),
+            snapshot("tank/data#daily1", 2),
+        ],
+    );
+
+    let config = parse_config(CONFIG)?;
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    assert_eq!(actions[1].parent, Some("tank/data@monthly".to_string()));
+    assert_eq!(actions[0].parent_key, None);
+    assert_eq!(actions[1].parent_key, Some(actions[0].key()));
+
+    let config = parse_config(&format!("{}  use_bookmarks: true\n", CONFIG))?;
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    assert_eq!(actions.len(), 2);
+    assert_eq!(actions[1].parent, Some("tank/data#daily1".to_string()));
+    assert_eq!(
+        actions[1].parent_key,
+        Some("incremental/tank/data%40daily1".to_string())
+    );
+    assert_eq!(
+        actions[1].backup_cmd(false),
+        "sudo zfs send -Pw -i tank/data#daily1 tank/data@daily2"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_incremental_without_parent() -> Result<(), Box<dyn Error>> {
+    let snapshot = |name: &str, days: i64| ZfsSnapshot {
+        name: name.to_string(),
+        creation: Local::now() - chrono::Duration::days(days),
+    };
+    let mut state = local_state(&["tank/empty"]);
+    state.pools.insert(
+        "tank/new".to_string(),
+        vec![
+            snapshot("tank/new@daily1", 2),
+            snapshot("tank/new@daily2", 1),
+        ],
+    );
+    state.pools.insert("tank/empty".to_string(), Vec::new());
+    let config = parse_config(CONFIG)?;
+    assert!(get_pending_actions(&state, &config.configs[0]).is_empty());
+
+    state
+        .pools
+        .get_mut("tank/new")
+        .unwrap()
+        .push(snapshot("tank/new@monthly", 0));
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].snapshot.name, "tank/new@monthly");
+    assert_eq!(actions[0].parent, None);
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_sorted_by_pool() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    let state = local_state(&["tank/c", "tank/a", "tank/d", "tank/b"]);
+    let names: Vec<String> = get_pending_actions(&state, &config.configs[0])
+        .into_iter()
+        .map(|x| x.snapshot.name)
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "tank/a@monthly",
+            "tank/b@monthly",
+            "tank/c@monthly",
+            "tank/d@monthly"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_estimated_size_full() {
+    let output = "full\ttank/data@monthly1\t1337\nsize\t1337\n";
+    assert_eq!(parse_estimated_size(output), Ok(1337));
+}
+
+#[test]
+fn test_parse_estimated_size_incremental() {
+    let output = "incremental\ttank/data@daily1\ttank/data@daily2\t4096\nsize\t4096\n";
+    assert_eq!(parse_estimated_size(output), Ok(4096));
+}
+
+#[test]
+fn test_parse_estimated_size_recursive() {
+    let output = "full\ttank@monthly1\t100\nfull\ttank/data@monthly1\t200\nsize\t300\n";
+    assert_eq!(parse_estimated_size(output), Ok(300));
+}
+
+#[test]
+fn test_parse_estimated_size_missing() {
+    assert!(parse_estimated_size("full\ttank/data@monthly1\t1337\n").is_err());
+    assert!(parse_estimated_size("size\tlots\n").is_err());
+    assert!(parse_estimated_size("").is_err());
+}
+
+#[test]
+fn test_is_truncated() {
+    assert!(!is_truncated(1000, 1000));
+    assert!(!is_truncated(600, 1000));
+    assert!(is_truncated(400, 1000));
+    assert!(is_truncated(0, 1000));
+    assert!(!is_truncated(0, 0));
+}
+
+#[test]
+fn test_creation_date_matches() {
+    let snapshot = ZfsSnapshot {
+        name: "tank/data@daily1".to_string(),
+        creation: Utc.timestamp(1_600_000_000, 0).with_timezone(&Local),
+    };
+    assert!(creation_date_matches(
+        Some("2020-09-13T12:26:40+00:00"),
+        &snapshot
+    ));
+    assert!(creation_date_matches(
+        Some("2020-09-13T14:26:40+02:00"),
+        &snapshot
+    ));
+    assert!(!creation_date_matches(
+        Some("2020-09-14T12:26:40+00:00"),
+        &snapshot
+    ));
+    assert!(creation_date_matches(None, &snapshot));
+    assert!(creation_date_matches(Some("yesterday"), &snapshot));
+}
+
+#[test]
+fn test_pending_actions_since() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let snapshots = state.pools.get_mut("tank/data").unwrap();
+    snapshots[0].creation = Local::now() - chrono::Duration::days(20);
+    for (name, days) in &[("daily1", 15), ("daily2", 5)] {
+        snapshots.push(ZfsSnapshot {
+            name: format!("tank/data@{}", name),
+            creation: Local::now() - chrono::Duration::days(*days),
+        });
+    }
+    let config = parse_config(CONFIG)?;
+    let since = Local::now() - chrono::Duration::days(10);
+    // Nothing before --since was uploaded, so daily2 has nothing to be sent against.
+    let actions = get_pending_actions_since(&state, &config.configs[0], Some(since));
+    assert!(actions.is_empty());
+    assert_eq!(get_pending_actions(&state, &config.configs[0]).len(), 3);
+
+    let remote_keys: HashSet<&str> = ["full/tank/data%40monthly", "incremental/tank/data%40daily1"]
+        .iter()
+        .copied()
+        .collect();
+    let actions =
+        get_pending_actions_with_remote(&state, &config.configs[0], Some(since), &remote_keys);
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].snapshot.name, "tank/data@daily2");
+    assert_eq!(actions[0].parent.as_deref(), Some("tank/data@daily1"));
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_since_between_full_and_incrementals() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let snapshots = state.pools.get_mut("tank/data").unwrap();
+    snapshots[0].creation = Local::now() - chrono::Duration::days(20);
+    for (name, days) in &[
+        ("daily1", 15),
+        ("daily2", 5),
+        ("monthly2", 4),
+        ("daily3", 2),
+    ] {
+        snapshots.push(ZfsSnapshot {
+            name: format!("tank/data@{}", name),
+            creation: Local::now() - chrono::Duration::days(*days),
+        });
+    }
+    let config = parse_config(CONFIG)?;
+    let since = Local::now() - chrono::Duration::days(10);
+    let actions = get_pending_actions_since(&state, &config.configs[0], Some(since));
+    let names: Vec<(&str, Option<&str>)> = actions
+        .iter()
+        .map(|x| (x.snapshot.name.as_str(), x.parent.as_deref()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("tank/data@monthly2", None),
+            ("tank/data@daily3", Some("tank/data@monthly2"))
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_last_remote_base() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let snapshots = state.pools.get_mut("tank/data").unwrap();
+    snapshots[0].creation = Local::now() - chrono::Duration::days(3);
+    for (name, days) in &[("daily1", 2), ("daily2", 1)] {
+        snapshots.push(ZfsSnapshot {
+            name: format!("tank/data@{}", name),
+            creation: Local::now() - chrono::Duration::days(*days),
+        });
+    }
+    let config = parse_config(&format!("{}  incremental_base: LastRemote\n", CONFIG))?;
+    let config = &config.configs[0];
+
+    let actions = get_pending_actions_with_remote(&state, config, None, &HashSet::new());
+    let names: Vec<(&str, Option<&str>)> = actions
+        .iter()
+        .map(|x| (x.snapshot.name.as_str(), x.parent.as_deref()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("tank/data@monthly", None),
+            ("tank/data@daily2", Some("tank/data@monthly"))
+        ]
+    );
+
+    // Backups already in S3 are still returned, sync skips them.
+    let mut remote_keys: HashSet<&str> = ["full/tank/data%40monthly"].iter().copied().collect();
+    let actions = get_pending_actions_with_remote(&state, config, None, &remote_keys);
+    assert_eq!(actions.len(), 2);
+    assert_eq!(actions[1].snapshot.name, "tank/data@daily2");
+    assert_eq!(actions[1].parent.as_deref(), Some("tank/data@monthly"));
+
+    remote_keys.insert("incremental/tank/data%40daily1");
+    let actions = get_pending_actions_with_remote(&state, config, None, &remote_keys);
+    assert_eq!(actions.len(), 2);
+    assert_eq!(actions[1].parent.as_deref(), Some("tank/data@daily1"));
+
+    remote_keys.insert("incremental/tank/data%40daily2");
+    let actions = get_pending_actions_with_remote(&state, config, None, &remote_keys);
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].snapshot.name, "tank/data@monthly");
+    Ok(())
+}
+
+const TIERS: &str = "
+  incremental_tiers:
+  - snapshot_regex: \"daily\"
+    storage_class: \"STANDARD\"
+    expire_in_days: 10
+";
+
+#[test]
+fn test_pending_actions_incremental_tiers() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let snapshots = state.pools.get_mut("tank/data").unwrap();
+    snapshots[0].creation = Local::now() - chrono::Duration::days(6);
+    for (name, days) in &[("daily1", 5), ("weekly1", 4), ("daily2", 3), ("daily3", 2)] {
+        snapshots.push(ZfsSnapshot {
+            name: format!("tank/data@{}", name),
+            creation: Local::now() - chrono::Duration::days(*days),
+        });
+    }
+    let config = parse_config(&format!(
+        "{}{}",
+        CONFIG.replace("snapshot_regex: \"daily\"", "snapshot_regex: \"weekly\""),
+        TIERS
+    ))?;
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    let names: Vec<(String, Option<&str>)> = actions
+        .iter()
+        .map(|x| (x.key(), x.parent.as_deref()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("full/tank/data%40monthly".to_string(), None),
+            (
+                "incremental-1/tank/data%40daily1".to_string(),
+                Some("tank/data@monthly")
+            ),
+            (
+                "incremental/tank/data%40weekly1".to_string(),
+                Some("tank/data@monthly")
+            ),
+            (
+                "incremental-1/tank/data%40daily2".to_string(),
+                Some("tank/data@weekly1")
+            ),
+            (
+                "incremental-1/tank/data%40daily3".to_string(),
+                Some("tank/data@weekly1")
+            ),
+        ]
+    );
+    assert_eq!(actions[1].storage_class, StorageClass::STANDARD);
+    assert_eq!(
+        S3Backup::parse_key("incremental-1/tank/data%40daily1"),
+        Some(("tank/data@daily1".to_string(), true))
+    );
+    assert_eq!(
+        S3Backup::parse_key("incremental-x/tank/data%40daily1"),
+        None
+    );
+
+    let config = parse_config(&format!(
+        "{}{}  incremental_base: LastRemote\n",
+        CONFIG.replace("snapshot_regex: \"daily\"", "snapshot_regex: \"weekly\""),
+        TIERS
+    ))?;
+    let remote_keys: HashSet<&str> = ["full/tank/data%40monthly"].iter().copied().collect();
+    let actions = get_pending_actions_with_remote(&state, &config.configs[0], None, &remote_keys);
+    let names: Vec<(String, Option<&str>)> = actions
+        .iter()
+        .map(|x| (x.key(), x.parent.as_deref()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("full/tank/data%40monthly".to_string(), None),
+            (
+                "incremental/tank/data%40weekly1".to_string(),
+                Some("tank/data@monthly")
+            ),
+            (
+                "incremental-1/tank/data%40daily3".to_string(),
+                Some("tank/data@weekly1")
+            ),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_pending_actions_incremental_tiers_daily_parent() -> Result<(), Box<dyn Error>> {
+    let mut state = local_state(&["tank/data"]);
+    let snapshots = state.pools.get_mut("tank/data").unwrap();
+    snapshots[0].creation = Local::now() - chrono::Duration::days(10);
+    for (name, days) in &[
+        ("weekly1", 6),
+        ("daily1", 5),
+        ("daily2", 4),
+        ("daily3", 3),
+        ("daily4", 2),
+    ] {
+        snapshots.push(ZfsSnapshot {
+            name: format!("tank/data@{}", name),
+            creation: Local::now() - chrono::Duration::days(*days),
+        });
+    }
+    let config = parse_config(&format!(
+        "{}{}",
+        CONFIG.replace("snapshot_regex: \"daily\"", "snapshot_regex: \"weekly\""),
+        TIERS
+    ))?;
+    let actions = get_pending_actions(&state, &config.configs[0]);
+    let parents: Vec<(&str, Option<&str>)> = actions
+        .iter()
+        .map(|x| (x.snapshot.name.as_str(), x.parent.as_deref()))
+        .collect();
+    assert_eq!(
+        parents,
+        vec![
+            ("tank/data@monthly", None),
+            ("tank/data@weekly1", Some("tank/data@monthly")),
+            ("tank/data@daily1", Some("tank/data@weekly1")),
+            ("tank/data@daily2", Some("tank/data@weekly1")),
+            ("tank/data@daily3", Some("tank/data@weekly1")),
+            ("tank/data@daily4", Some("tank/data@weekly1")),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_since() {
+    let now = Local.ymd(2021, 3, 1).and_hms(12, 0, 0);
+    assert_eq!(
+        parse_since("30d", now),
+        Ok(now - chrono::Duration::days(30))
+    );
+    assert_eq!(
+        parse_since("12h", now),
+        Ok(now - chrono::Duration::hours(12))
+    );
+    assert_eq!(parse_since("2w", now), Ok(now - chrono::Duration::days(14)));
+    assert_eq!(
+        parse_since("2021-01-31", now),
+        Ok(Local.ymd(2021, 1, 31).and_hms(0, 0, 0))
+    );
+    assert_eq!(
+        parse_since("2021-01-31T10:00:00+00:00", now),
+        Ok(Utc.ymd(2021, 1, 31).and_hms(10, 0, 0).with_timezone(&Local))
+    );
+    for invalid in &["", "d", "30", "30y", "-5d", "yesterday", "2021-13-01"] {
+        assert!(parse_since(invalid, now).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_key_prefix() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!("{}  key_prefix: \"host1/\"\n", CONFIG))?;
+    let actions = get_pending_actions(&local_state(&["tank/data"]), &config.configs[0]);
+    assert_eq!(actions[0].key(), "host1/full/tank/data%40monthly");
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use std::{error::Error, path::Path, time::Duration};
+use zfs_to_glacier::config::*;
+use zfs_to_glacier::key_template::KeyTemplate;
+
+const CONFIG: &str = "
+configs:
+- pool_regex: \"rpool/.*\"
+  incremental:
+    snapshot_regex: \"daily\"
+    storage_class: \"StandardInfrequentAccess\"
+    expire_in_days: 40
+  full:
+    snapshot_regex: \"monthly\"
+    storage_class: \"DeepArchive\"
+    expire_in_days: 200
+  bucket: \"zfs-rpool\"
+";
+
+#[test]
+fn test_parse_config() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    assert_eq!(config.configs.len(), 1);
+    assert_eq!(config.configs[0].pool_regex, "rpool/.*");
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_invalid_regex() {
+    let err = parse_config(&CONFIG.replace("\"monthly\"", "\"monthly(\"")).unwrap_err();
+    let err = err.downcast::<ConfigError>().unwrap();
+    assert!(matches!(
+        *err,
+        ConfigError::InvalidRegex { ref field, ref pattern, .. }
+            if field == "configs[0].full.snapshot_regex" && pattern == "monthly("
+    ));
+}
+
+#[test]
+fn test_parse_config_transition_needs_days() {
+    let config = CONFIG.replace(
+        "expire_in_days: 200",
+        "expire_in_days: 200\n    transition_to: \"Glacier\"",
+    );
+    let err = parse_config(&config).unwrap_err();
+    assert!(matches!(
+        *err.downcast::<ConfigError>().unwrap(),
+        ConfigError::InvalidValue { ref field, .. } if field == "configs[0].full.transition_to"
+    ));
+}
+
+#[test]
+fn test_parse_config_json() -> Result<(), Box<dyn Error>> {
+    let json = r#"{
+        "configs": [{
+            "pool_regex": "rpool/.*",
+            "incremental": {"snapshot_regex": "daily", "storage_class": "StandardInfrequentAccess", "expire_in_days": 40},
+            "full": {"snapshot_regex": "monthly", "storage_class": "DeepArchive", "expire_in_days": 200},
+            "bucket": "zfs-rpool"
+        }]
+    }"#;
+    assert_eq!(
+        parse_config_as(json, ConfigFormat::Json)?,
+        parse_config(CONFIG)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_config_format_from_path() {
+    assert_eq!(
+        ConfigFormat::from_path(Path::new("config.json")),
+        ConfigFormat::Json
+    );
+    assert_eq!(
+        ConfigFormat::from_path(Path::new("config.yaml")),
+        ConfigFormat::Yaml
+    );
+    assert_eq!(
+        ConfigFormat::from_path(Path::new("config.yml")),
+        ConfigFormat::Yaml
+    );
+}
+
+#[test]
+fn test_parse_config_shared_bucket() -> Result<(), Box<dyn Error>> {
+    let second = CONFIG
+        .replace("configs:\n", "")
+        .replace("rpool/.*", "tank/.*");
+    let config = parse_config(&format!("{}{}", CONFIG, second))?;
+    assert_eq!(config.unique_buckets().len(), 1);
+
+    let conflicting = second.replace("expire_in_days: 200", "expire_in_days: 100");
+    let err = parse_config(&format!("{}{}", CONFIG, conflicting)).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "configs[0] and configs[1] both use bucket zfs-rpool, but with different full.expire_in_days"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_invalid_send_flags() {
+    let config = CONFIG.replace(
+        "expire_in_days: 200",
+        "expire_in_days: 200\n    send_flags: \"w -i\"",
+    );
+    assert!(parse_config(&config).is_err());
+}
+
+#[test]
+fn test_parse_config_raw() -> Result<(), Box<dyn Error>> {
+    let with_full = |settings: &str| {
+        CONFIG.replace(
+            "expire_in_days: 200",
+            &format!("expire_in_days: 200\n    {}", settings),
+        )
+    };
+    let config = parse_config(CONFIG)?;
+    assert_eq!(config.configs[0].full.send_flags(), "w");
+    assert!(!config.sends_decrypted());
+    let config = parse_config(&with_full("raw: false"))?;
+    assert_eq!(config.configs[0].full.send_flags(), "");
+    assert!(config.sends_decrypted());
+    let config = parse_config(&with_full("raw: false\n    send_flags: \"L\""))?;
+    assert_eq!(config.configs[0].full.send_flags(), "L");
+    let config = parse_config(&with_full("raw: true\n    send_flags: \"L\""))?;
+    assert_eq!(config.configs[0].full.send_flags(), "wL");
+
+    let err = parse_config(&with_full("raw: false\n    send_flags: \"wL\"")).unwrap_err();
+    let err = err.downcast::<ConfigError>().unwrap();
+    assert!(matches!(
+        *err,
+        ConfigError::InvalidValue { ref field, .. } if field == "configs[0].full.raw"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_pricing() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "pricing:\n  DeepArchive:\n    gb_month: 0.002\n    per_1000_requests: 0.06\n{}",
+        CONFIG
+    ))?;
+    let pricing = config.pricing.unwrap();
+    assert_eq!(
+        pricing[&zfs_to_glacier::s3_utils::StorageClass::DeepArchive].gb_month,
+        0.002
+    );
+    Ok(())
+}
+
+#[test]
+fn test_s3_region_endpoint() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  region: \"us-west-002\"\n  endpoint: \"https://s3.us-west-002.backblazeb2.com\"\n",
+        CONFIG
+    ))?;
+    assert_eq!(
+        config.configs[0].s3_region()?,
+        rusoto_core::Region::Custom {
+            name: "us-west-002".to_string(),
+            endpoint: "https://s3.us-west-002.backblazeb2.com".to_string(),
+        }
+    );
+    let config = parse_config(&format!("{}  region: \"eu-west-3\"\n", CONFIG))?;
+    assert_eq!(config.configs[0].s3_region()?, rusoto_core::Region::EuWest3);
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_key_template() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "{}  key_template: \"{{type}}/{{year}}/{{name}}\"\n",
+        CONFIG
+    ))?;
+    assert_eq!(
+        config.configs[0].key_template.as_deref(),
+        Some("{type}/{year}/{name}")
+    );
+    assert_eq!(
+        config.configs[0].key_template(),
+        &KeyTemplate::new("{type}/{year}/{name}")?
+    );
+    let err = parse_config(&format!(
+        "{}  key_template: \"{{name}}/{{type}}/\"\n",
+        CONFIG
+    ))
+    .unwrap_err();
+    let err = err.downcast::<ConfigError>().unwrap();
+    assert!(matches!(
+        *err,
+        ConfigError::InvalidValue { ref field, .. } if field == "configs[0].key_template"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_tags() -> Result<(), Box<dyn Error>> {
+    assert!(parse_config(CONFIG)?.configs[0].tags.is_empty());
+    let config = parse_config(&format!(
+        "{}  tags:\n    Environment: prod\n    CostCenter: backups\n",
+        CONFIG
+    ))?;
+    let options = config.upload_options(&config.configs[0]);
+    let tags: Vec<(&str, &str)> = options
+        .tags
+        .iter()
+        .map(|tag| (tag.key.as_str(), tag.value.as_str()))
+        .collect();
+    assert_eq!(
+        tags,
+        vec![("CostCenter", "backups"), ("Environment", "prod")]
+    );
+
+    let invalid = |tags: &str| -> String {
+        let err = parse_config(&format!("{}  tags:\n{}", CONFIG, tags)).unwrap_err();
+        match *err.downcast::<ConfigError>().unwrap() {
+            ConfigError::InvalidValue { field, .. } => field,
+            err => panic!("unexpected error {}", err),
+        }
+    };
+    assert_eq!(invalid("    parent: full\n"), "configs[0].tags.parent");
+    assert_eq!(
+        invalid("    \"aws:owner\": me\n"),
+        "configs[0].tags.aws:owner"
+    );
+    assert_eq!(
+        invalid(&format!("    long: {}\n", "x".repeat(257))),
+        "configs[0].tags.long"
+    );
+    assert_eq!(
+        invalid("    a: \"1\"\n    b: \"2\"\n    c: \"3\"\n"),
+        "configs[0].tags"
+    );
+    assert_eq!(
+        invalid("    backup_type: full\n"),
+        "configs[0].tags.backup_type"
+    );
+    // Date-first templates take one more tag for backup_type.
+    let err = parse_config(&format!(
+        "{}  key_template: \"{{date}}/{{type}}/{{name}}\"\n  tags:\n    a: \"1\"\n    b: \"2\"\n",
+        CONFIG
+    ))
+    .unwrap_err();
+    assert!(matches!(
+        *err.downcast::<ConfigError>().unwrap(),
+        ConfigError::InvalidValue { field, .. } if field == "configs[0].tags"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_http() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(CONFIG)?;
+    assert_eq!(config.http_read_buf_bytes(), 64 * 1024 * 1024);
+    assert_eq!(config.http_pool_idle_timeout(), Duration::from_secs(5));
+    let config = parse_config(&format!(
+        "http_read_buf_bytes: 1048576\nhttp_pool_idle_secs: 90\n{}",
+        CONFIG
+    ))?;
+    assert_eq!(config.http_read_buf_bytes(), 1024 * 1024);
+    assert_eq!(config.http_pool_idle_timeout(), Duration::from_secs(90));
+    assert!(parse_config(&format!("http_read_buf_bytes: 0\n{}", CONFIG)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_size_check() -> Result<(), Box<dyn Error>> {
+    assert_eq!(parse_config(CONFIG)?.size_check, None);
+    let config = parse_config(&format!("size_check: reupload\n{}", CONFIG))?;
+    assert_eq!(config.size_check, Some(SizeCheck::Reupload));
+    assert!(parse_config(&format!("size_check: maybe\n{}", CONFIG)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_upload_options_buffered_parts() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "upload_concurrency: 2\nbuffered_parts: 1\nmax_memory_mb: 512\n{}",
+        CONFIG
+    ))?;
+    assert_eq!(config.max_memory_mb, Some(512));
+    let options = config.upload_options(&config.configs[0]);
+    assert_eq!(options.buffered_parts, Some(1));
+    assert_eq!(options.peak_memory(1024 * 1024), 3 * 1024 * 1024);
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_aws_profile() -> Result<(), Box<dyn Error>> {
+    assert_eq!(parse_config(CONFIG)?.aws_profile, None);
+    let config = parse_config(&format!("aws_profile: backup\n{}", CONFIG))?;
+    assert_eq!(config.aws_profile.as_deref(), Some("backup"));
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_assume_role() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "assume_role_arn: \"arn:aws:iam::123456789012:role/backup\"\nexternal_id: \"secret\"\n{}",
+        CONFIG
+    ))?;
+    assert_eq!(
+        config.assume_role_arn.as_deref(),
+        Some("arn:aws:iam::123456789012:role/backup")
+    );
+    assert_eq!(config.external_id.as_deref(), Some("secret"));
+    let err = parse_config(&format!("external_id: \"secret\"\n{}", CONFIG)).unwrap_err();
+    let err = err.downcast::<ConfigError>().unwrap();
+    assert!(matches!(
+        *err,
+        ConfigError::InvalidValue { ref field, .. } if field == "external_id"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_notify_webhook() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&format!(
+        "notify_webhook: \"https://example.com/hook\"\nnotify_on_success: true\n{}",
+        CONFIG
+    ))?;
+    assert_eq!(
+        config.notify_webhook.as_deref(),
+        Some("https://example.com/hook")
+    );
+    assert!(config.notify_on_success);
+    assert!(!parse_config(CONFIG)?.notify_on_success);
+    let err = parse_config(&format!("notify_on_success: true\n{}", CONFIG)).unwrap_err();
+    let err = err.downcast::<ConfigError>().unwrap();
+    assert!(matches!(
+        *err,
+        ConfigError::InvalidValue { ref field, .. } if field == "notify_on_success"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_retention_warnings() -> Result<(), Box<dyn Error>> {
+    assert!(parse_config(CONFIG)?.retention_warnings().is_empty());
+    let config = parse_config(&CONFIG.replace(
+        "storage_class: \"DeepArchive\"\n    expire_in_days: 200",
+        "storage_class: \"DeepArchive\"\n    expire_in_days: 40",
+    ))?;
+    assert_eq!(
+        config.retention_warnings(),
+        vec!["configs[0].full.expire_in_days is 40, but DEEP_ARCHIVE is billed for at least 180 days, so backups are billed until day 180 regardless"]
+    );
+    let config = parse_config(&CONFIG.replace(
+        "expire_in_days: 40",
+        "expire_in_days: 100\n    transition_to: \"Glacier\"\n    transition_after_days: 30",
+    ))?;
+    assert_eq!(config.retention_warnings().len(), 1);
+    assert!(
+        config.retention_warnings()[0].starts_with("configs[0].incremental.expire_in_days is 100")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_glacier_instant_retrieval() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(&CONFIG.replace("DeepArchive", "GlacierInstantRetrieval"))?;
+    let storage_class = config.configs[0].full.storage_class;
+    assert_eq!(
+        storage_class,
+        zfs_to_glacier::s3_utils::StorageClass::GlacierInstantRetrieval
+    );
+    assert_eq!(storage_class.to_string(), "GLACIER_IR");
+    assert!(!storage_class.needs_restore());
+    let config = parse_config(
+        &CONFIG
+            .replace("DeepArchive", "GlacierInstantRetrieval")
+            .replace("expire_in_days: 200", "expire_in_days: 60"),
+    )?;
+    assert_eq!(config.retention_warnings().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_parse_config_aws_storage_class_names() -> Result<(), Box<dyn Error>> {
+    let config = parse_config(
+        &CONFIG
+            .replace("\"DeepArchive\"", "DEEP_ARCHIVE")
+            .replace("\"StandardInfrequentAccess\"", "standard_ia"),
+    )?;
+    assert_eq!(
+        config.configs[0].full.storage_class,
+        zfs_to_glacier::s3_utils::StorageClass::DeepArchive
+    );
+    assert_eq!(
+        config.configs[0].incremental.storage_class,
+        zfs_to_glacier::s3_utils::StorageClass::StandardInfrequentAccess
+    );
+    let err = parse_config(&CONFIG.replace("\"DeepArchive\"", "ARCHIVE")).unwrap_err();
+    assert!(err.to_string().contains("Unknown storage class 'ARCHIVE'"));
+    Ok(())
+}
+
+#[test]
+fn test_read_config_missing_file() {
+    let err = read_config_from(Path::new("does-not-exist.yaml")).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ConfigError>(),
+        Some(ConfigError::Read { .. })
+    ));
+    assert!(err
+        .to_string()
+        .starts_with("Failed to read does-not-exist.yaml: "));
+}
@@ -0,0 +1,77 @@
+use chrono::{Local, TimeZone};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fs,
+};
+use zfs_to_glacier::{
+    compute_backups::S3Backup,
+    estimate_cache::EstimateCache,
+    key_template::KeyTemplate,
+    s3_utils::StorageClass,
+    zfs_utils::{LocalZfsState, ZfsSnapshot},
+};
+
+fn backup(creation: i64) -> S3Backup {
+    S3Backup {
+        snapshot: ZfsSnapshot {
+            name: "tank/data@monthly".to_string(),
+            creation: Local.timestamp(creation, 0),
+        },
+        parent: None,
+        parent_key: None,
+        storage_class: StorageClass::DeepArchive,
+        bucket: "bucket".to_string(),
+        // Fails when run, so any estimate returned must come from the cache.
+        zfs_command: "false".to_string(),
+        send_flags: "w".to_string(),
+        key_prefix: String::new(),
+        key_template: KeyTemplate::default(),
+        tier: 0,
+    }
+}
+
+#[test]
+fn test_estimate_cache() -> Result<(), Box<dyn Error>> {
+    let path = env::temp_dir().join(format!(
+        "zfs_glacier_estimate_cache_{}.json",
+        std::process::id()
+    ));
+    fs::write(
+        &path,
+        r#"{"entries": {"false send -Pwvn tank/data@monthly": {"snapshot": "tank/data@monthly", "creation": 1600000000, "size": 1234}}}"#,
+    )?;
+    let mut cache = EstimateCache::load(&path);
+    assert_eq!(cache.get_estimated_size(&backup(1600000000))?, 1234);
+    // Same name, but a different snapshot.
+    assert!(cache.get_estimated_size(&backup(1600000001)).is_err());
+
+    cache.save(&path)?;
+    let mut cache = EstimateCache::load(&path);
+    fs::remove_file(&path)?;
+    assert_eq!(cache.get_estimated_size(&backup(1600000000))?, 1234);
+
+    let mut pools = HashMap::new();
+    pools.insert("tank/data".to_string(), vec![backup(1600000001).snapshot]);
+    cache.retain_existing(&LocalZfsState {
+        pools,
+        bookmarks: HashMap::new(),
+        zfs_command: "zfs".to_string(),
+        encrypted: HashSet::new(),
+    });
+    assert!(cache.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_estimate_cache_unreadable() {
+    let path = env::temp_dir().join(format!(
+        "zfs_glacier_estimate_cache_bad_{}.json",
+        std::process::id()
+    ));
+    fs::write(&path, "not json").unwrap();
+    let cache = EstimateCache::load(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(cache.is_empty());
+}
@@ -1,12 +1,16 @@
 use log::info;
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
 use zfs_to_glacier::{
-    cmd_execute::{Executor, ExecutorCommand},
+    cmd_execute::{Executor, ExecutorCommand, SpawnedCommand},
     compute_backups::{S3Backup, S3BackupCommand},
 };
 use zfs_to_glacier::{
     compute_backups::{get_pending_actions, FilterExistingFiles},
     config::*,
+    key_template::KeyTemplate,
 };
 use zfs_to_glacier::{
     s3_utils::*,
@@ -14,9 +18,9 @@ use zfs_to_glacier::{
 };
 mod common;
 use common::*;
-use pretty_assertions::assert_eq;
 use log::log;
 use log::Level::Info;
+use pretty_assertions::assert_eq;
 use testcontainers::*;
 
 macro_rules! test_step {
@@ -44,11 +48,14 @@ impl S3BackupCommand for S3TestBackup {
                 "echo -n zfs send -vPw{} -i {} {}",
                 dryrun_char, parent, self.inner.snapshot.name
             ),
-            None => format!("echo -n zfs send -vPw{} {}", dryrun_char, self.inner.snapshot.name),
+            None => format!(
+                "echo -n zfs send -vPw{} {}",
+                dryrun_char, self.inner.snapshot.name
+            ),
         }
     }
 
-    fn backup(&self, dryrun: bool) -> Result<std::process::Child, Box<dyn Error>> {
+    fn backup(&self, dryrun: bool) -> Result<SpawnedCommand, Box<dyn Error>> {
         Ok(ExecutorCommand(self.backup_cmd(dryrun)).spawn()?)
     }
 
@@ -60,207 +67,402 @@ impl S3BackupCommand for S3TestBackup {
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn basic_actions() -> Result<(), Box<dyn std::error::Error>> {
     log_init("integration_full");
-    execute_in_docker!((|| async {
-        let bucket = generate_unique_name();
-        let client = create_client(&bucket).await?;
-        let config = create_standard_config(&bucket);
+    execute_in_docker!(
+        (|| async {
+            let bucket = generate_unique_name();
+            let client = create_client(&bucket).await?;
+            let config = create_standard_config(&bucket);
 
-        test_step!("Synchronizing initial data");
-        let local_state = LocalZfsState {
-            pools: {
-                let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
-                pool_state.insert("backup_pool".to_string(), Vec::new());
-                pool_state.insert(
-                    "backup_pool/backup".to_string(),
-                    vec![
-                        ZfsSnapshot::new("backup_pool/backup@1_yearly", chrono::Duration::days(20))?,
-                        ZfsSnapshot::new("backup_pool/backup@2_monthly", chrono::Duration::days(19))?,
-                        ZfsSnapshot::new("backup_pool/backup@3_ignored", chrono::Duration::days(18))?,
-                        ZfsSnapshot::new("backup_pool/backup@4_daily", chrono::Duration::days(17))?,
-                    ],
+            test_step!("Synchronizing initial data");
+            let local_state = LocalZfsState {
+                pools: {
+                    let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
+                    pool_state.insert("backup_pool".to_string(), Vec::new());
+                    pool_state.insert(
+                        "backup_pool/backup".to_string(),
+                        vec![
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@1_yearly",
+                                chrono::Duration::days(20),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@2_monthly",
+                                chrono::Duration::days(19),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@3_ignored",
+                                chrono::Duration::days(18),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@4_daily",
+                                chrono::Duration::days(17),
+                            )?,
+                        ],
+                    );
+                    pool_state
+                },
+                bookmarks: HashMap::new(),
+                zfs_command: "zfs".to_string(),
+                encrypted: HashSet::new(),
+            };
+
+            info!("Getting pending actions");
+            let actions = get_pending_actions(&local_state, &config);
+            {
+                // Confirm actions are set up correctly
+                assert_eq!(
+                    &actions,
+                    &vec![
+                        S3Backup::new(
+                            "backup_pool/backup@1_yearly",
+                            &bucket,
+                            chrono::Duration::days(20),
+                            None
+                        )?,
+                        S3Backup::new(
+                            "backup_pool/backup@2_monthly",
+                            &bucket,
+                            chrono::Duration::days(19),
+                            None
+                        )?,
+                        S3Backup {
+                            parent_key: Some("full/backup_pool/backup%402_monthly".to_string()),
+                            ..S3Backup::new(
+                                "backup_pool/backup@4_daily",
+                                &bucket,
+                                chrono::Duration::days(17),
+                                Some("backup_pool/backup@2_monthly".to_string())
+                            )?
+                        }
+                    ]
                 );
-                pool_state
-            },
-        };
+            }
 
-        info!("Getting pending actions");
-        let actions = get_pending_actions(&local_state, &config);
-        {
-            // Confirm actions are set up correctly
-            assert_eq!(
-                &actions,
-                &vec![
-                    S3Backup::new("backup_pool/backup@1_yearly", &bucket, chrono::Duration::days(20), None)?,
-                    S3Backup::new("backup_pool/backup@2_monthly", &bucket, chrono::Duration::days(19), None)?,
-                    S3Backup::new(
-                        "backup_pool/backup@4_daily",
-                        &bucket,
-                        chrono::Duration::days(17),
-                        Some("backup_pool/backup@2_monthly".to_string())
-                    )?
-                ]
-            );
-        }
+            let local_actions: Vec<S3TestBackup> = actions
+                .into_iter()
+                .map(|x| S3TestBackup { inner: x })
+                .collect();
+            assert_eq!(local_actions.len(), 3);
 
-        let local_actions: Vec<S3TestBackup> = actions
-            .into_iter()
-            .map(|x| S3TestBackup { inner: x })
-            .collect();
-        assert_eq!(local_actions.len(), 3);
+            test_step!("Executing actions");
+            for action in local_actions {
+                info!("     upload {}", action.inner.key());
+                let child = ExecutorCommand(action.backup_cmd(false)).spawn()?;
+                upload_stdout(
+                    &client,
+                    Box::new(child),
+                    UploadTarget {
+                        bucket: &bucket,
+                        key: &action.inner.key(),
+                        tags: vec![],
+                        storage_class: StorageClass::STANDARD,
+                    },
+                    Some(0),
+                    &UploadOptions::default(),
+                    |_| {},
+                )
+                .await?;
+            }
 
-        test_step!("Executing actions");
-        for action in local_actions {
-            info!("     upload {}", action.inner.key());
-            let child = ExecutorCommand(action.backup_cmd(false)).spawn()?;
-            upload_stdout(
-                &client,
-                Box::new(child),
-                &bucket,
-                &action.inner.key(),
-                vec![],
-                StorageClass::STANDARD,
-                0,
-                |_| {}
-            ).await?;
-        }
+            info!("Confirming actions executed");
+            {
+                // Confirm uploads are now consistent
+                assert_eq!(
+                    download_file(&bucket, "full/backup_pool/backup%401_yearly", &client).await?,
+                    "zfs send -vPw backup_pool/backup@1_yearly"
+                );
+                assert_eq!(
+                    download_file(&bucket, "full/backup_pool/backup%402_monthly", &client).await?,
+                    "zfs send -vPw backup_pool/backup@2_monthly"
+                );
+                assert_eq!(
+                    download_file(&bucket, "incremental/backup_pool/backup%404_daily", &client)
+                        .await?,
+                    "zfs send -vPw -i backup_pool/backup@2_monthly backup_pool/backup@4_daily"
+                );
+            }
 
-        info!("Confirming actions executed");
-        {
-            // Confirm uploads are now consistent
-            assert_eq!(
-                download_file(&bucket, "full/backup_pool/backup_AT_1_yearly", &client).await?,
-                "zfs send -vPw backup_pool/backup@1_yearly"
-            );
-            assert_eq!(
-                download_file(&bucket, "full/backup_pool/backup_AT_2_monthly", &client).await?,
-                "zfs send -vPw backup_pool/backup@2_monthly"
-            );
-            assert_eq!(
-                download_file(
-                    &bucket,
-                    "incremental/backup_pool/backup_AT_4_daily",
-                    &client
-                ).await?,
-                "zfs send -vPw -i backup_pool/backup@2_monthly backup_pool/backup@4_daily"
-            );
-        }
+            test_step!("Testing upload of new day");
+            // Test upload of new day.
+            let local_state = LocalZfsState {
+                pools: {
+                    //@fixme : I can do a macro for this one, see https://doc.rust-lang.org/1.7.0/book/macros.html
+                    let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
+                    pool_state.insert("backup_pool".to_string(), Vec::new());
+                    pool_state.insert(
+                        "backup_pool/backup".to_string(),
+                        vec![
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@1_yearly",
+                                chrono::Duration::days(20),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@2_monthly",
+                                chrono::Duration::days(19),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@3_ignored",
+                                chrono::Duration::days(18),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@4_daily",
+                                chrono::Duration::days(17),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@5_daily",
+                                chrono::Duration::days(16),
+                            )?,
+                        ],
+                    );
+                    pool_state
+                },
+                bookmarks: HashMap::new(),
+                zfs_command: "zfs".to_string(),
+                encrypted: HashSet::new(),
+            };
+
+            info!("Getting remote s3 bucket state");
+            let remote_state =
+                get_all_files(&client, &config.bucket, DEFAULT_MAX_RETRIES, None).await?;
+
+            info!("Getting local actions");
+            let total_local_actions = get_pending_actions(&local_state, &config);
+            assert_eq!(total_local_actions.len(), 4);
+            let local_actions: Vec<S3TestBackup> = total_local_actions
+                .filter_existing_backups(&remote_state)
+                .into_iter()
+                .map(|x| S3TestBackup { inner: x })
+                .collect();
+            assert_eq!(local_actions.len(), 1);
 
-        test_step!("Testing upload of new day");
-        // Test upload of new day.
-        let local_state = LocalZfsState {
-            pools: {
-                //@fixme : I can do a macro for this one, see https://doc.rust-lang.org/1.7.0/book/macros.html
-                let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
-                pool_state.insert("backup_pool".to_string(), Vec::new());
-                pool_state.insert(
-                    "backup_pool/backup".to_string(),
-                    vec![
-                        ZfsSnapshot::new("backup_pool/backup@1_yearly", chrono::Duration::days(20))?,
-                        ZfsSnapshot::new("backup_pool/backup@2_monthly", chrono::Duration::days(19))?,
-                        ZfsSnapshot::new("backup_pool/backup@3_ignored", chrono::Duration::days(18))?,
-                        ZfsSnapshot::new("backup_pool/backup@4_daily", chrono::Duration::days(17))?,
-                        ZfsSnapshot::new("backup_pool/backup@5_daily", chrono::Duration::days(16))?,
-                    ],
+            info!("Executing actions");
+            for action in local_actions {
+                let child = ExecutorCommand(action.backup_cmd(false)).spawn()?;
+                upload_stdout(
+                    &client,
+                    Box::new(child),
+                    UploadTarget {
+                        bucket: &bucket,
+                        key: &action.inner.key(),
+                        tags: vec![],
+                        storage_class: StorageClass::STANDARD,
+                    },
+                    Some(0),
+                    &UploadOptions::default(),
+                    |_| {},
+                )
+                .await?;
+            }
+            {
+                // Confirm uploads are now consistent
+                assert_eq!(
+                    download_file(&bucket, "incremental/backup_pool/backup%405_daily", &client)
+                        .await?,
+                    "zfs send -vPw -i backup_pool/backup@4_daily backup_pool/backup@5_daily"
                 );
-                pool_state
+            }
+
+            Ok(())
+        })
+    )
+}
+
+/// Local state with one dataset, `backup_pool/backup`, holding `snapshots` of the given age in days.
+fn local_state_with(snapshots: &[(&str, i64)]) -> Result<LocalZfsState, Box<dyn Error>> {
+    let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
+    pool_state.insert("backup_pool".to_string(), Vec::new());
+    let mut dataset_snapshots = Vec::new();
+    for (name, days) in snapshots {
+        dataset_snapshots.push(ZfsSnapshot::new(
+            &format!("backup_pool/backup@{}", name),
+            chrono::Duration::days(*days),
+        )?);
+    }
+    pool_state.insert("backup_pool/backup".to_string(), dataset_snapshots);
+    Ok(LocalZfsState {
+        pools: pool_state,
+        bookmarks: HashMap::new(),
+        zfs_command: "zfs".to_string(),
+        encrypted: HashSet::new(),
+    })
+}
+
+async fn upload_actions(
+    client: &rusoto_s3::S3Client,
+    bucket: &str,
+    actions: Vec<S3Backup>,
+) -> Result<(), Box<dyn Error>> {
+    for action in actions {
+        let action = S3TestBackup { inner: action };
+        info!("     upload {}", action.inner.key());
+        let child = ExecutorCommand(action.backup_cmd(false)).spawn()?;
+        upload_stdout(
+            client,
+            Box::new(child),
+            UploadTarget {
+                bucket,
+                key: &action.inner.key(),
+                tags: vec![],
+                storage_class: StorageClass::STANDARD,
             },
-        };
+            Some(0),
+            &UploadOptions::default(),
+            |_| {},
+        )
+        .await?;
+    }
+    Ok(())
+}
 
-        info!("Getting remote s3 bucket state");
-        let remote_state = get_all_files(&client, &config.bucket).await?;
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn parent_removed_locally() -> Result<(), Box<dyn Error>> {
+    log_init("integration_full");
+    execute_in_docker!(
+        (|| async {
+            let bucket = generate_unique_name();
+            let client = create_client(&bucket).await?;
+            let config = create_standard_config(&bucket);
 
-        info!("Getting local actions");
-        let total_local_actions = get_pending_actions(&local_state, &config);
-        assert_eq!(total_local_actions.len(), 4);
-        let local_actions: Vec<S3TestBackup> = total_local_actions
-            .filter_existing_backups(&remote_state)
-            .into_iter()
-            .map(|x| S3TestBackup { inner: x })
-            .collect();
-        assert_eq!(local_actions.len(), 1);
+            test_step!("Uploading a chain of incrementals");
+            let local_state =
+                local_state_with(&[("1_monthly", 20), ("2_daily", 19), ("3_daily", 18)])?;
+            let actions = get_pending_actions(&local_state, &config);
+            assert_eq!(actions.len(), 3);
+            upload_actions(&client, &bucket, actions).await?;
 
-        info!("Executing actions");
-        for action in local_actions {
-            let child = ExecutorCommand(action.backup_cmd(false)).spawn()?;
-            upload_stdout(
-                &client,
-                Box::new(child),
-                &bucket,
-                &action.inner.key(),
-                vec![],
-                StorageClass::STANDARD,
-                0,
-                |_| {}
-            ).await?;
-        }
-        {
-            // Confirm uploads are now consistent
+            test_step!("Removing the parent of the next incremental locally");
+            let local_state =
+                local_state_with(&[("1_monthly", 20), ("2_daily", 19), ("4_daily", 17)])?;
+            let remote_state =
+                get_all_files(&client, &config.bucket, DEFAULT_MAX_RETRIES, None).await?;
+            let actions =
+                get_pending_actions(&local_state, &config).filter_existing_backups(&remote_state);
+            assert_eq!(actions.len(), 1);
+            // Sent against the newest snapshot that still exists locally, which is backed up.
             assert_eq!(
-                download_file(
-                    &bucket,
-                    "incremental/backup_pool/backup_AT_5_daily",
-                    &client
-                ).await?,
-                "zfs send -vPw -i backup_pool/backup@4_daily backup_pool/backup@5_daily"
+                actions[0].parent.as_deref(),
+                Some("backup_pool/backup@2_daily")
             );
-        }
+            assert_eq!(
+                actions[0].parent_key.as_deref(),
+                Some("incremental/backup_pool/backup%402_daily")
+            );
+            assert!(remote_state
+                .iter()
+                .any(|file| Some(file.key.as_str()) == actions[0].parent_key.as_deref()));
+            upload_actions(&client, &bucket, actions).await?;
+            assert_eq!(
+                download_file(&bucket, "incremental/backup_pool/backup%404_daily", &client).await?,
+                "zfs send -vPw -i backup_pool/backup@2_daily backup_pool/backup@4_daily"
+            );
+
+            test_step!("Removing every possible parent locally");
+            let local_state = local_state_with(&[("5_daily", 16)])?;
+            let remote_state =
+                get_all_files(&client, &config.bucket, DEFAULT_MAX_RETRIES, None).await?;
+            // No -i command without a local parent, the incremental is left out instead.
+            assert!(get_pending_actions(&local_state, &config)
+                .filter_existing_backups(&remote_state)
+                .is_empty());
 
-        Ok(())
-    }))
+            Ok(())
+        })
+    )
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 async fn ignore_expired() -> Result<(), Box<dyn Error>> {
     log_init("integration_full");
-    execute_in_docker!((|| async {
-        let bucket = generate_unique_name();
-        let config = create_standard_config(&bucket);
+    execute_in_docker!(
+        (|| async {
+            let bucket = generate_unique_name();
+            let config = create_standard_config(&bucket);
 
-        let local_state = LocalZfsState {
-            pools: {
-                let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
-                pool_state.insert("backup_pool".to_string(), Vec::new());
-                pool_state.insert(
-                    "backup_pool/backup".to_string(),
-                    vec![
-                        ZfsSnapshot::new("backup_pool/backup@1_yearly", chrono::Duration::days(365))?,
-                        ZfsSnapshot::new("backup_pool/backup@2_yearly", chrono::Duration::days(1))?,
-                    ],
-                );
-                pool_state
-            },
-        };
+            let local_state = LocalZfsState {
+                pools: {
+                    let mut pool_state: HashMap<String, Vec<ZfsSnapshot>> = HashMap::new();
+                    pool_state.insert("backup_pool".to_string(), Vec::new());
+                    pool_state.insert(
+                        "backup_pool/backup".to_string(),
+                        vec![
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@1_yearly",
+                                chrono::Duration::days(365),
+                            )?,
+                            ZfsSnapshot::new(
+                                "backup_pool/backup@2_yearly",
+                                chrono::Duration::days(1),
+                            )?,
+                        ],
+                    );
+                    pool_state
+                },
+                bookmarks: HashMap::new(),
+                zfs_command: "zfs".to_string(),
+                encrypted: HashSet::new(),
+            };
 
-        info!("Getting pending actions");
-        let actions = get_pending_actions(&local_state, &config);
-        {
-            // Confirm actions are set up correctly
-            assert_eq!(
-                &actions,
-                &vec![
-                    S3Backup::new("backup_pool/backup@2_yearly", &bucket, chrono::Duration::days(1), None)?,
-                ]
-            );
-        }
+            info!("Getting pending actions");
+            let actions = get_pending_actions(&local_state, &config);
+            {
+                // Confirm actions are set up correctly
+                assert_eq!(
+                    &actions,
+                    &vec![S3Backup::new(
+                        "backup_pool/backup@2_yearly",
+                        &bucket,
+                        chrono::Duration::days(1),
+                        None
+                    )?,]
+                );
+            }
 
-        Ok(())
-    }))
+            Ok(())
+        })
+    )
 }
 
-
 fn create_standard_config(bucket: &str) -> ZfsBackupConfig {
     ZfsBackupConfig {
         pool_regex: "backup_pool.*".to_string(),
+        exclude_regex: None,
         incremental: ZfsBackupConfigEntry {
             snapshot_regex: "daily.*".to_string(),
             storage_class: StorageClass::DeepArchive,
-            expire_in_days: 40
+            expire_in_days: 40,
+            transition_to: None,
+            transition_after_days: None,
+            send_flags: None,
+            raw: None,
+            recursive: false,
         },
+        incremental_tiers: Vec::new(),
         full: ZfsBackupConfigEntry {
             snapshot_regex: "(yearly|monthly).*".to_string(),
             storage_class: StorageClass::DeepArchive,
-            expire_in_days: 200
+            expire_in_days: 200,
+            transition_to: None,
+            transition_after_days: None,
+            send_flags: None,
+            raw: None,
+            recursive: false,
         },
         bucket: bucket.to_string(),
+        region: None,
+        endpoint: None,
+        key_prefix: None,
+        key_template: None,
+        parsed_key_template: KeyTemplate::default(),
+        resume_uploads: false,
+        part_size_mb: None,
+        sse: None,
+        sse_kms_key_id: None,
+        versioning: false,
+        use_bookmarks: false,
+        verify_creation_date: false,
+        incremental_base: IncrementalBase::LastLocal,
+        abort_incomplete_uploads_after_days: None,
+        noncurrent_version_expire_in_days: None,
+        tags: HashMap::new(),
     }
 }
@@ -1,10 +1,15 @@
+use futures::{stream, StreamExt, TryStreamExt};
+use rusoto_s3::{PutObjectRequest, S3};
 use std::io;
 use std::io::{Read, Write};
 use std::process::Command;
 use std::process::Stdio;
 use std::{error::Error, process::ExitStatus};
 use zfs_to_glacier::cmd_execute::CommandStreamActions;
-use zfs_to_glacier::s3_utils::{upload_stdout, upload_stdout_internal, StorageClass};
+use zfs_to_glacier::s3_utils::{
+    get_all_files, get_multipart_uploads, upload_stdout, upload_stdout_internal, S3Error,
+    StorageClass, UploadOptions, UploadTarget, DEFAULT_MAX_RETRIES,
+};
 mod common;
 use common::*;
 use testcontainers::*;
@@ -29,11 +34,14 @@ async fn test_upload_short_file() -> Result<(), Box<dyn Error>> {
             upload_stdout(
                 &client,
                 Box::new(child),
-                &bucket,
-                "test_key",
-                vec![test_tag],
-                StorageClass::STANDARD,
-                0,
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![test_tag],
+                    storage_class: StorageClass::STANDARD,
+                },
+                Some(0),
+                &UploadOptions::default(),
                 |_| {},
             )
             .await?;
@@ -49,6 +57,11 @@ async fn test_upload_short_file() -> Result<(), Box<dyn Error>> {
                         key: "buffer_size".to_string(),
                         value: "8388608".to_string(),
                     },
+                    rusoto_s3::Tag {
+                        key: "content_sha256".to_string(),
+                        value: "2e99758548972a8e8822ad47fa1017ff72f06f3ff6a016851f45c398732bc50c"
+                            .to_string(),
+                    },
                     rusoto_s3::Tag {
                         key: "test_tag".to_string(),
                         value: "test_tag_value".to_string(),
@@ -121,10 +134,13 @@ async fn test_upload_large_file() -> Result<(), Box<dyn Error>> {
                     iterations: TEST_ITERATIONS,
                     fail: false,
                 }),
-                &bucket,
-                "test_key",
-                vec![],
-                StorageClass::STANDARD,
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![],
+                    storage_class: StorageClass::STANDARD,
+                },
+                &UploadOptions::default(),
                 |_| {},
                 MIN_MULTIPART_SIZE,
             )
@@ -159,10 +175,13 @@ async fn test_very_upload_large_file() -> Result<(), Box<dyn Error>> {
                     iterations: 30,
                     fail: false,
                 }),
-                &bucket,
-                "test_key",
-                vec![],
-                StorageClass::STANDARD,
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![],
+                    storage_class: StorageClass::STANDARD,
+                },
+                &UploadOptions::default(),
                 |_| {},
                 MIN_MULTIPART_SIZE,
             )
@@ -194,15 +213,129 @@ async fn test_command_exit_failure() -> Result<(), Box<dyn Error>> {
                     iterations: TEST_ITERATIONS,
                     fail: true,
                 }),
-                &bucket,
-                "test_key",
-                vec![],
-                StorageClass::STANDARD,
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![],
+                    storage_class: StorageClass::STANDARD,
+                },
+                &UploadOptions::default(),
+                |_| {},
+                MIN_MULTIPART_SIZE,
+            )
+            .await;
+            assert!(matches!(r, Err(S3Error::CommandExited { .. })));
+            Ok(())
+        })
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_resume_upload() -> Result<(), Box<dyn Error>> {
+    log_init("integration_s3_utils");
+
+    execute_in_docker!(
+        (|| async {
+            let bucket = generate_unique_name();
+            let client = create_client(&bucket).await?;
+            let options = UploadOptions {
+                resume: true,
+                ..Default::default()
+            };
+            let r = upload_stdout_internal(
+                &client,
+                Box::new(LargeFile {
+                    iterations: TEST_ITERATIONS,
+                    fail: true,
+                }),
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![],
+                    storage_class: StorageClass::STANDARD,
+                },
+                &options,
                 |_| {},
                 MIN_MULTIPART_SIZE,
             )
             .await;
-            assert_eq!(r.is_err(), true);
+            assert!(r.is_err());
+            assert_eq!(
+                get_multipart_uploads(&client, &bucket, None, None)
+                    .await?
+                    .len(),
+                1
+            );
+
+            let total_bytes = upload_stdout_internal(
+                &client,
+                Box::new(LargeFile {
+                    iterations: TEST_ITERATIONS,
+                    fail: false,
+                }),
+                UploadTarget {
+                    bucket: &bucket,
+                    key: "test_key",
+                    tags: vec![],
+                    storage_class: StorageClass::STANDARD,
+                },
+                &options,
+                |_| {},
+                MIN_MULTIPART_SIZE,
+            )
+            .await?;
+            assert_eq!(
+                get_multipart_uploads(&client, &bucket, None, None)
+                    .await?
+                    .len(),
+                0
+            );
+
+            let content = common::download_file(&bucket, "test_key", &client).await?;
+            let content = content.replace(
+                &(0..TEST_MULTIPART_SIZE).map(|_| "x").collect::<String>(),
+                "x",
+            );
+            assert_eq!(
+                content,
+                "S09xE09 S08xE08 S07xE07 S06xE06 S05xE05 S04xE04 S03xE03 S02xE02 S01xE01 "
+            );
+            assert_eq!(total_bytes, (TEST_MULTIPART_SIZE as u64 + 7) * 9);
+            Ok(())
+        })
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_get_all_files_paginates() -> Result<(), Box<dyn Error>> {
+    log_init("integration_s3_utils");
+
+    execute_in_docker!(
+        (|| async {
+            let bucket = generate_unique_name();
+            let client = create_client(&bucket).await?;
+            // More than the 1000 keys returned per ListObjectsV2 page.
+            const OBJECT_COUNT: usize = 2345;
+            stream::iter(0..OBJECT_COUNT)
+                .map(|i| {
+                    client.put_object(PutObjectRequest {
+                        bucket: bucket.clone(),
+                        key: format!("key_{:05}", i),
+                        body: Some(Vec::new().into()),
+                        ..Default::default()
+                    })
+                })
+                .buffer_unordered(32)
+                .try_collect::<Vec<_>>()
+                .await?;
+
+            let files = get_all_files(&client, &bucket, DEFAULT_MAX_RETRIES, None).await?;
+            assert_eq!(files.len(), OBJECT_COUNT);
+            let mut keys: Vec<&str> = files.iter().map(|x| x.key.as_str()).collect();
+            keys.sort_unstable();
+            let expected: Vec<String> =
+                (0..OBJECT_COUNT).map(|i| format!("key_{:05}", i)).collect();
+            assert_eq!(keys, expected);
             Ok(())
         })
     )
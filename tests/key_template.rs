@@ -0,0 +1,83 @@
+use chrono::{TimeZone, Utc};
+use zfs_to_glacier::key_template::*;
+use zfs_to_glacier::zfs_utils::ZfsSnapshot;
+
+fn snapshot() -> ZfsSnapshot {
+    ZfsSnapshot {
+        name: "rpool/data@daily 1".to_string(),
+        creation: Utc.timestamp(1614859200, 0).into(),
+    }
+}
+
+#[test]
+fn test_default_template() {
+    let template = KeyTemplate::default();
+    assert_eq!(
+        template.render("full", &snapshot()),
+        "full/rpool/data%40daily%201"
+    );
+    assert_eq!(
+        template.render_legacy("incremental", &snapshot()),
+        "incremental/rpool/data_AT_daily 1"
+    );
+    assert_eq!(template.type_prefix("incremental-1"), "incremental-1/");
+}
+
+#[test]
+fn test_date_partitioned_template() {
+    let template = KeyTemplate::new("backups/{type}/{year}/{month}/{dataset}@{snapshot}").unwrap();
+    let key = template.render(&backup_type(true, 1), &snapshot());
+    assert_eq!(key, "backups/incremental-1/2021/03/rpool/data@daily%201");
+    assert_eq!(
+        template.parse(&key),
+        Some(("rpool/data@daily 1".to_string(), true))
+    );
+    assert_eq!(template.parse("backups/full/2021/3/rpool/data@daily"), None);
+    assert_eq!(template.type_prefix("full"), "backups/full/");
+    assert!(template.has_type_prefix("backups/full/junk"));
+    assert!(!template.has_type_prefix("full/junk"));
+}
+
+#[test]
+fn test_date_first_template() {
+    let template = KeyTemplate::new("backups/{year}/{month}/{type}/{dataset}@{snapshot}").unwrap();
+    let key = template.render("full", &snapshot());
+    assert_eq!(key, "backups/2021/03/full/rpool/data@daily%201");
+    assert_eq!(
+        template.parse(&key),
+        Some(("rpool/data@daily 1".to_string(), false))
+    );
+    assert!(template.needs_type_tag());
+    assert!(!KeyTemplate::default().needs_type_tag());
+    assert_eq!(template.type_prefix("full"), "backups/");
+    assert!(template.has_type_prefix("backups/2021/03/full/junk"));
+    assert!(!template.has_type_prefix("backups/2021/03/junk"));
+}
+
+#[test]
+fn test_name_template_round_trip() {
+    let template = KeyTemplate::new("{type}/{date}/{name}").unwrap();
+    let key = template.render("full", &snapshot());
+    assert_eq!(key, "full/2021-03-04/rpool/data%40daily%201");
+    assert_eq!(
+        template.parse(&key),
+        Some(("rpool/data@daily 1".to_string(), false))
+    );
+}
+
+#[test]
+fn test_invalid_templates() {
+    for template in &[
+        "{name}",
+        "{type}/{type}/{name}",
+        "{type}/{dataset}",
+        "{type}/{name}/{dataset}@{snapshot}",
+        "{name}/{type}/x",
+        "{year}/{dataset}/{type}/{snapshot}",
+        "{type}{name}",
+        "{type}/{host}/{name}",
+        "{type}/{name",
+    ] {
+        assert!(KeyTemplate::new(template).is_err(), "{}", template);
+    }
+}
@@ -0,0 +1,144 @@
+use chrono::prelude::*;
+use std::collections::HashSet;
+use zfs_to_glacier::compute_backups::S3Backup;
+use zfs_to_glacier::key_template::KeyTemplate;
+use zfs_to_glacier::listing::*;
+use zfs_to_glacier::s3_utils::{S3Key, StorageClass};
+use zfs_to_glacier::zfs_utils::ZfsSnapshot;
+
+fn backup(name: &str, creation: i64, parent: Option<&str>) -> S3Backup {
+    S3Backup {
+        snapshot: ZfsSnapshot {
+            name: name.to_string(),
+            creation: Local.timestamp(creation, 0),
+        },
+        parent: parent.map(|x| x.to_string()),
+        parent_key: None,
+        storage_class: StorageClass::DeepArchive,
+        bucket: "bucket".to_string(),
+        zfs_command: "zfs".to_string(),
+        send_flags: "w".to_string(),
+        key_prefix: String::new(),
+        key_template: KeyTemplate::default(),
+        tier: 0,
+    }
+}
+
+fn remote(key: &str) -> S3Key {
+    S3Key {
+        key: key.to_string(),
+        etag: None,
+        size: 1,
+        storage_class: "DEEP_ARCHIVE".to_string(),
+    }
+}
+
+#[test]
+fn test_dataset_status() {
+    let actions = vec![
+        backup("pool/a@1", 1, None),
+        backup("pool/a@2", 2, Some("pool/a@1")),
+        backup("pool/a@3", 3, Some("pool/a@2")),
+        backup("pool/b@1", 1, None),
+        backup("pool/b@2", 2, Some("pool/b@1")),
+        backup("pool/b@2", 2, None),
+    ];
+    let remote_files: HashSet<S3Key> = vec![
+        remote("full/pool/a_AT_1"),
+        remote("incremental/pool/a%402"),
+        remote("full/pool/b%402"),
+    ]
+    .into_iter()
+    .collect();
+    let status = get_dataset_status(&actions, &remote_files);
+    assert_eq!(status.len(), 2);
+    let a = &status["pool/a"];
+    assert_eq!((a.backed_up, a.pending), (2, 1));
+    assert_eq!(a.latest_snapshot.as_ref().unwrap().name, "pool/a@3");
+    assert!(!a.latest_backed_up);
+    let b = &status["pool/b"];
+    assert_eq!((b.backed_up, b.pending), (1, 2));
+    assert_eq!(b.latest_snapshot.as_ref().unwrap().name, "pool/b@2");
+    assert!(b.latest_backed_up);
+}
+
+fn remote_backup(dataset: &str, creation_date: Option<&str>) -> RemoteBackup {
+    RemoteBackup {
+        key: format!("full/{}%40snap", dataset),
+        dataset: dataset.to_string(),
+        snapshot: "snap".to_string(),
+        incremental: false,
+        size: 1,
+        storage_class: "DEEP_ARCHIVE".to_string(),
+        creation_date: creation_date.map(|x| x.to_string()),
+        parent: None,
+        parent_key: None,
+        source_host: None,
+    }
+}
+
+#[test]
+fn test_stale_datasets() {
+    let backups = vec![
+        remote_backup("pool/fresh", Some("2021-01-01T00:00:00+00:00")),
+        remote_backup("pool/fresh", Some("2021-01-10T00:00:00+02:00")),
+        remote_backup("pool/stale", Some("2021-01-05T00:00:00+00:00")),
+        remote_backup("pool/untagged", None),
+    ];
+    let oldest_allowed = Utc.ymd(2021, 1, 9).and_hms(0, 0, 0);
+    let stale = get_stale_datasets(
+        &["pool/fresh", "pool/stale", "pool/untagged", "pool/missing"],
+        &backups,
+        oldest_allowed,
+    );
+    assert_eq!(
+        stale,
+        vec![
+            (
+                "pool/stale".to_string(),
+                Some(DateTime::parse_from_rfc3339("2021-01-05T00:00:00+00:00").unwrap())
+            ),
+            ("pool/untagged".to_string(), None),
+            ("pool/missing".to_string(), None),
+        ]
+    );
+}
+
+#[test]
+fn test_config_backup_keys() -> Result<(), Box<dyn std::error::Error>> {
+    let config = zfs_to_glacier::config::parse_config(
+        "
+configs:
+- pool_regex: \"tank/.*\"
+  exclude_regex: \"tank/tmp\"
+  incremental:
+    snapshot_regex: \"daily\"
+    storage_class: \"StandardInfrequentAccess\"
+    expire_in_days: 40
+  full:
+    snapshot_regex: \"monthly\"
+    storage_class: \"DeepArchive\"
+    expire_in_days: 200
+  bucket: \"zfs-tank\"
+  key_prefix: \"host1/\"
+",
+    )?;
+    let remote_files: HashSet<S3Key> = vec![
+        remote("host1/full/tank/data%40monthly"),
+        remote("host1/incremental/tank/data%40daily"),
+        remote("host1/full/tank/tmp%40monthly"),
+        remote("host1/full/rpool/data%40monthly"),
+        remote("host1/manifest/tank/data.json"),
+        remote("host2/full/tank/data%40monthly"),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        get_config_backup_keys(&remote_files, &config.configs[0]),
+        vec![
+            "host1/full/tank/data%40monthly",
+            "host1/incremental/tank/data%40daily"
+        ]
+    );
+    Ok(())
+}
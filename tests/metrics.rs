@@ -0,0 +1,44 @@
+use std::{env, fs};
+use zfs_to_glacier::metrics::*;
+
+#[test]
+fn test_textfile_format() {
+    let metrics = SyncMetrics {
+        bytes_uploaded: 1024,
+        files_uploaded: 3,
+        files_failed: 1,
+        last_success_timestamp: Some(1600000000),
+    };
+    let textfile = metrics.to_textfile();
+    assert!(textfile
+        .contains("# TYPE zfs_glacier_bytes_uploaded gauge\nzfs_glacier_bytes_uploaded 1024\n"));
+    assert!(textfile.contains("\nzfs_glacier_files_uploaded 3\n"));
+    assert!(textfile.contains("\nzfs_glacier_files_failed 1\n"));
+    assert_eq!(parse_last_success_timestamp(&textfile), Some(1600000000));
+}
+
+#[test]
+fn test_failed_run_keeps_last_success() -> Result<(), Box<dyn std::error::Error>> {
+    let path = env::temp_dir().join(format!("zfs_glacier_metrics_{}.prom", std::process::id()));
+    write_metrics_file(
+        &path,
+        SyncMetrics {
+            files_uploaded: 2,
+            last_success_timestamp: Some(1600000000),
+            ..Default::default()
+        },
+    )?;
+    write_metrics_file(
+        &path,
+        SyncMetrics {
+            files_failed: 1,
+            ..Default::default()
+        },
+    )?;
+    let contents = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    assert!(contents.contains("\nzfs_glacier_files_failed 1\n"));
+    assert!(contents.contains("\nzfs_glacier_files_uploaded 0\n"));
+    assert_eq!(parse_last_success_timestamp(&contents), Some(1600000000));
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use zfs_to_glacier::notify::SyncSummary;
+
+#[test]
+fn test_should_notify() {
+    let summary = SyncSummary {
+        succeeded: 2,
+        ..Default::default()
+    };
+    assert!(!summary.should_notify(false));
+    assert!(summary.should_notify(true));
+    let summary = SyncSummary {
+        failed: 1,
+        ..Default::default()
+    };
+    assert!(summary.should_notify(false));
+}
+
+#[test]
+fn test_summary_json() -> Result<(), serde_json::Error> {
+    let summary = SyncSummary {
+        source_host: "backup1".to_string(),
+        succeeded: 3,
+        failed: 1,
+        skipped: 2,
+        bytes_uploaded: 1024,
+    };
+    assert_eq!(
+        serde_json::to_value(&summary)?,
+        serde_json::json!({
+            "source_host": "backup1",
+            "succeeded": 3,
+            "failed": 1,
+            "skipped": 2,
+            "bytes_uploaded": 1024,
+        })
+    );
+    Ok(())
+}
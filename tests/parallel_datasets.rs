@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zfs_to_glacier::parallel_datasets::{group_by_pool, run_groups};
+
+#[test]
+fn test_group_by_pool_keeps_order() {
+    let groups = group_by_pool(
+        vec!["tank/a@1", "tank/b@1", "tank/a@2", "tank@1", "tank/b@2"],
+        |name| name,
+    );
+    assert_eq!(
+        groups,
+        vec![
+            vec!["tank/a@1", "tank/a@2"],
+            vec!["tank/b@1", "tank/b@2"],
+            vec!["tank@1"],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_run_groups_limit() -> Result<(), Box<dyn Error>> {
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Mutex::new(Vec::new()));
+    let groups: Vec<Vec<usize>> = (0..6).map(|i| vec![i * 2, i * 2 + 1]).collect();
+    run_groups(groups, 2, {
+        let (active, max_active, done) = (active.clone(), max_active.clone(), done.clone());
+        move |group: Vec<usize>| {
+            let (active, max_active, done) = (active.clone(), max_active.clone(), done.clone());
+            async move {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                max_active.fetch_max(now, Ordering::SeqCst);
+                for item in group {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    done.lock().unwrap().push(item);
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    })
+    .await?;
+    assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    let done = done.lock().unwrap();
+    assert_eq!(done.len(), 12);
+    for i in 0..6 {
+        let first = done.iter().position(|&x| x == i * 2).unwrap();
+        let second = done.iter().position(|&x| x == i * 2 + 1).unwrap();
+        assert!(first < second, "group {} ran out of order", i);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_groups_awaits_all_after_failure() {
+    let done = Arc::new(AtomicUsize::new(0));
+    let groups: Vec<Vec<usize>> = (0..4).map(|i| vec![i]).collect();
+    let result = run_groups(groups, 4, {
+        let done = done.clone();
+        move |group: Vec<usize>| {
+            let done = done.clone();
+            async move {
+                if group[0] == 0 {
+                    panic!("group 0 failed");
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(done.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+use zfs_to_glacier::{pricing::*, s3_utils::StorageClass};
+
+#[test]
+fn test_estimate_cost() {
+    let pricing = StorageClassPricing {
+        gb_month: 0.01,
+        per_1000_requests: 0.05,
+    };
+    let cost = estimate_cost(100 * 1024 * 1024 * 1024, 998, pricing);
+    assert!((cost.storage_per_month - 1.0).abs() < 1e-9);
+    assert!((cost.requests - 0.05).abs() < 1e-9);
+}
+
+#[test]
+fn test_pricing_override() {
+    let custom = StorageClassPricing {
+        gb_month: 0.002,
+        per_1000_requests: 0.06,
+    };
+    let mut config: PricingConfig = HashMap::new();
+    config.insert(StorageClass::DeepArchive, custom);
+    assert_eq!(pricing(Some(&config), StorageClass::DeepArchive), custom);
+    assert_eq!(
+        pricing(Some(&config), StorageClass::Glacier),
+        default_pricing(StorageClass::Glacier)
+    );
+    assert_eq!(
+        pricing(None, StorageClass::DeepArchive),
+        default_pricing(StorageClass::DeepArchive)
+    );
+}
@@ -0,0 +1,75 @@
+use chrono::{Local, TimeZone};
+use std::{env, error::Error, fs};
+use zfs_to_glacier::{
+    compute_backups::S3Backup, key_template::KeyTemplate, report::*, s3_utils::StorageClass,
+    zfs_utils::ZfsSnapshot,
+};
+
+fn backup() -> S3Backup {
+    S3Backup {
+        snapshot: ZfsSnapshot {
+            name: "tank/data@monthly".to_string(),
+            creation: Local.timestamp(1600000000, 0),
+        },
+        parent: None,
+        parent_key: None,
+        storage_class: StorageClass::DeepArchive,
+        bucket: "bucket".to_string(),
+        zfs_command: "zfs".to_string(),
+        send_flags: "w".to_string(),
+        key_prefix: String::new(),
+        key_template: KeyTemplate::default(),
+        tier: 0,
+    }
+}
+
+fn report() -> SyncReport {
+    let backup = backup();
+    SyncReport {
+        source_host: "host".to_string(),
+        actions: vec![
+            ReportEntry {
+                bytes_uploaded: 1024,
+                duration_secs: 1.5,
+                retries: 2,
+                ..ReportEntry::new(&backup, ReportStatus::Uploaded)
+            },
+            ReportEntry {
+                error: Some("upload failed".to_string()),
+                ..ReportEntry::new(&backup, ReportStatus::Failed)
+            },
+            ReportEntry::new(&backup, ReportStatus::Deferred),
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_write_report_json() -> Result<(), Box<dyn Error>> {
+    let path = env::temp_dir().join(format!("zfs_glacier_report_{}.json", std::process::id()));
+    write_report(&path, &report())?;
+    let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    fs::remove_file(&path)?;
+    let actions = written["actions"].as_array().unwrap();
+    assert_eq!(actions[0]["key"], backup().key());
+    assert_eq!(actions[0]["storage_class"], "DEEP_ARCHIVE");
+    assert_eq!(actions[0]["bytes_uploaded"], 1024);
+    assert_eq!(actions[0]["retries"], 2);
+    assert_eq!(actions[0]["status"], "uploaded");
+    assert!(actions[0].get("error").is_none());
+    assert_eq!(actions[1]["status"], "failed");
+    assert_eq!(actions[1]["error"], "upload failed");
+    assert_eq!(actions[2]["status"], "deferred");
+    Ok(())
+}
+
+#[test]
+fn test_write_report_yaml() -> Result<(), Box<dyn Error>> {
+    let path = env::temp_dir().join(format!("zfs_glacier_report_{}.yaml", std::process::id()));
+    write_report(&path, &report())?;
+    let written: serde_yaml::Value = serde_yaml::from_str(&fs::read_to_string(&path)?)?;
+    fs::remove_file(&path)?;
+    assert_eq!(written["source_host"].as_str(), Some("host"));
+    assert_eq!(written["actions"][2]["status"].as_str(), Some("deferred"));
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use zfs_to_glacier::listing::RemoteBackup;
+use zfs_to_glacier::restore::*;
+
+fn backup(snapshot: &str, parent: Option<&str>, creation_date: &str) -> RemoteBackup {
+    let prefix = if parent.is_some() {
+        "incremental"
+    } else {
+        "full"
+    };
+    RemoteBackup {
+        key: format!("{}/pool/data_AT_{}", prefix, snapshot),
+        dataset: "pool/data".to_string(),
+        snapshot: snapshot.to_string(),
+        incremental: parent.is_some(),
+        size: 1,
+        storage_class: "DEEP_ARCHIVE".to_string(),
+        creation_date: Some(creation_date.to_string()),
+        parent: parent.map(|x| x.to_string()),
+        parent_key: None,
+        source_host: None,
+    }
+}
+
+fn backups() -> Vec<RemoteBackup> {
+    vec![
+        backup("monthly1", None, "2021-01-01T00:00:00+00:00"),
+        backup(
+            "daily2",
+            Some("pool/data@monthly1"),
+            "2021-01-02T00:00:00+00:00",
+        ),
+        backup(
+            "daily3",
+            Some("pool/data@daily2"),
+            "2021-01-03T00:00:00+00:00",
+        ),
+        backup(
+            "daily4",
+            Some("pool/data@daily3"),
+            "2021-01-04T00:00:00+00:00",
+        ),
+    ]
+}
+
+fn keys(plan: &[RestoreStep]) -> Vec<&str> {
+    plan.iter().map(|x| x.key.as_str()).collect()
+}
+
+#[test]
+fn test_restore_plan_newest() -> Result<(), RestoreError> {
+    let plan = get_restore_plan("bucket", &backups(), "pool/data", None)?;
+    assert_eq!(
+        keys(&plan),
+        vec![
+            "full/pool/data_AT_monthly1",
+            "incremental/pool/data_AT_daily2",
+            "incremental/pool/data_AT_daily3",
+            "incremental/pool/data_AT_daily4",
+        ]
+    );
+    assert_eq!(
+        plan[0].restore_cmd("zfs", "pool/restored", false),
+        "zfs receive -u pool/restored"
+    );
+    assert_eq!(
+        plan[0].restore_cmd("zfs", "pool/restored", true),
+        "zfs receive -u -nv pool/restored"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_plan_snapshot_prefers_full() -> Result<(), RestoreError> {
+    let mut backups = backups();
+    backups.push(backup("daily3", None, "2021-01-03T00:00:00+00:00"));
+    let plan = get_restore_plan("bucket", &backups, "pool/data", Some("daily3"))?;
+    assert_eq!(keys(&plan), vec!["full/pool/data_AT_daily3"]);
+    let plan = get_restore_plan("bucket", &backups, "pool/data", Some("daily4"))?;
+    assert_eq!(
+        keys(&plan),
+        vec![
+            "full/pool/data_AT_daily3",
+            "incremental/pool/data_AT_daily4"
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_plan_follows_parent_key() -> Result<(), RestoreError> {
+    let mut backups = backups();
+    backups.push(backup("daily3", None, "2021-01-03T00:00:00+00:00"));
+    backups[3].parent_key = Some("incremental/pool/data_AT_daily3".to_string());
+    backups[2].parent_key = Some("moved/pool/data_AT_daily2".to_string());
+    let plan = get_restore_plan("bucket", &backups, "pool/data", Some("daily4"))?;
+    assert_eq!(
+        keys(&plan),
+        vec![
+            "full/pool/data_AT_monthly1",
+            "incremental/pool/data_AT_daily2",
+            "incremental/pool/data_AT_daily3",
+            "incremental/pool/data_AT_daily4",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_plan_broken_chain() {
+    let mut backups = backups();
+    backups.remove(1);
+    assert_eq!(
+        get_restore_plan("bucket", &backups, "pool/data", None),
+        Err(RestoreError::MissingBackup("pool/data@daily2".to_string()))
+    );
+    assert_eq!(
+        get_restore_plan("bucket", &backups, "pool/other", None),
+        Err(RestoreError::NoBackups("pool/other".to_string()))
+    );
+}
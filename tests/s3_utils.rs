@@ -0,0 +1,827 @@
+use async_trait::async_trait;
+use chrono::{Local, TimeZone};
+use futures::TryStreamExt;
+use md5::Digest;
+use rusoto_core::{request::HttpDispatchError, ByteStream, Region, RusotoError};
+use rusoto_s3::*;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, Cursor};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::{cell::Cell, future, time::Duration};
+use zfs_to_glacier::cmd_execute::CommandStreamActions;
+use zfs_to_glacier::compute_backups::S3Backup;
+use zfs_to_glacier::key_template::KeyTemplate;
+use zfs_to_glacier::manifest::{get_manifest, manifest_key, record_backup};
+use zfs_to_glacier::restore::get_restore_plan;
+use zfs_to_glacier::s3_ops::S3Ops;
+use zfs_to_glacier::s3_utils::{
+    check_bucket, create_bucket, get_all_files, get_object_tags, merge_tags, multipart_etag,
+    part_size, retry_with, upload_stdout_internal, with_timeout, BucketError, InvalidPartSize,
+    S3Error, StorageClass, UploadOptions, UploadProgress, UploadTarget, DEFAULT_MAX_RETRIES,
+    UNKNOWN_SIZE_PART_SIZE,
+};
+use zfs_to_glacier::zfs_utils::ZfsSnapshot;
+
+const MIB: usize = 1024 * 1024;
+
+#[test]
+fn test_part_size_grows_with_estimate() -> Result<(), Box<dyn Error>> {
+    assert_eq!(part_size(Some(0), None)?, 8 * MIB);
+    assert_eq!(part_size(Some(100 * 1024 * MIB), None)?, 32 * MIB);
+    Ok(())
+}
+
+#[test]
+fn test_part_size_configured() -> Result<(), Box<dyn Error>> {
+    assert_eq!(part_size(Some(100 * 1024 * MIB), Some(16 * MIB))?, 16 * MIB);
+    Ok(())
+}
+
+#[test]
+fn test_part_size_unknown_size() -> Result<(), Box<dyn Error>> {
+    assert_eq!(part_size(None, None)?, UNKNOWN_SIZE_PART_SIZE);
+    assert_eq!(part_size(None, Some(16 * MIB))?, 16 * MIB);
+    assert!(part_size(None, Some(MIB)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_part_size_configured_invalid() {
+    assert!(matches!(
+        part_size(Some(0), Some(MIB)),
+        Err(InvalidPartSize { .. })
+    ));
+    assert!(matches!(
+        part_size(Some(100 * 1024 * MIB), Some(5 * MIB)),
+        Err(InvalidPartSize { .. })
+    ));
+}
+
+#[test]
+fn test_peak_memory() {
+    let options = UploadOptions {
+        concurrency: 4,
+        ..Default::default()
+    };
+    assert_eq!(options.peak_memory(8 * MIB), 64 * MIB);
+    let options = UploadOptions {
+        concurrency: 4,
+        buffered_parts: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(options.peak_memory(8 * MIB), 40 * MIB);
+}
+
+#[test]
+fn test_storage_class_from_str() {
+    for name in &["DeepArchive", "DEEP_ARCHIVE", "deep_archive", "deeparchive"] {
+        assert_eq!(name.parse(), Ok(StorageClass::DeepArchive));
+    }
+    for name in &["STANDARD_IA", "standard_ia", "StandardInfrequentAccess"] {
+        assert_eq!(name.parse(), Ok(StorageClass::StandardInfrequentAccess));
+    }
+    assert_eq!("standard".parse(), Ok(StorageClass::STANDARD));
+    assert_eq!(
+        "glacier_ir".parse(),
+        Ok(StorageClass::GlacierInstantRetrieval)
+    );
+    assert_eq!(
+        "Intelligent-Tiering".parse(),
+        Ok(StorageClass::IntelligentTiering)
+    );
+    assert!("archive".parse::<StorageClass>().is_err());
+}
+
+#[test]
+fn test_multipart_etag() {
+    let digests = vec![
+        md5::Md5::digest(b"part one").to_vec(),
+        md5::Md5::digest(b"part two").to_vec(),
+    ];
+    assert_eq!(
+        multipart_etag(&digests),
+        "\"0732917abc3288784e318ac0aab1757a-2\""
+    );
+}
+
+#[tokio::test]
+async fn test_with_timeout() {
+    let slow = async {
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        Ok::<(), rusoto_core::RusotoError<()>>(())
+    };
+    let r = with_timeout(Some(std::time::Duration::from_millis(10)), slow).await;
+    assert!(matches!(r, Err(rusoto_core::RusotoError::HttpDispatch(_))));
+
+    let fast = async { Ok::<u32, rusoto_core::RusotoError<()>>(1) };
+    assert_eq!(
+        with_timeout(Some(std::time::Duration::from_secs(10)), fast)
+            .await
+            .ok(),
+        Some(1)
+    );
+    let fast = async { Ok::<u32, rusoto_core::RusotoError<()>>(2) };
+    assert_eq!(with_timeout(None, fast).await.ok(), Some(2));
+}
+
+/// Runs `retry_with` on an operation failing `failures` times, returning the result along with
+/// how many times it was called and the delays slept.
+async fn retry_failing(failures: u32, max_retries: u32) -> (Result<u32, u32>, u32, Vec<Duration>) {
+    let calls = Cell::new(0);
+    let mut delays = Vec::new();
+    let result = retry_with(
+        max_retries,
+        || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call <= failures {
+                    Err(call)
+                } else {
+                    Ok(call)
+                }
+            }
+        },
+        |delay| {
+            delays.push(delay);
+            future::ready(())
+        },
+    )
+    .await;
+    (result, calls.get(), delays)
+}
+
+#[tokio::test]
+async fn test_retry_first_success() {
+    let (result, calls, delays) = retry_failing(0, 3).await;
+    assert_eq!(result, Ok(1));
+    assert_eq!(calls, 1);
+    assert!(delays.is_empty());
+}
+
+#[tokio::test]
+async fn test_retry_success_after_failures() {
+    let (result, calls, delays) = retry_failing(2, 3).await;
+    assert_eq!(result, Ok(3));
+    assert_eq!(calls, 3);
+    assert_eq!(delays.len(), 2);
+    assert!(delays[0] >= Duration::from_millis(500) && delays[0] <= Duration::from_secs(1));
+    assert!(delays[1] >= Duration::from_secs(1) && delays[1] <= Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_retry_exhausted() {
+    let (result, calls, delays) = retry_failing(10, 3).await;
+    assert_eq!(result, Err(4));
+    assert_eq!(calls, 4);
+    assert_eq!(delays.len(), 3);
+    assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(60)));
+}
+
+#[tokio::test]
+async fn test_retry_no_retries() {
+    let (result, calls, delays) = retry_failing(1, 0).await;
+    assert_eq!(result, Err(1));
+    assert_eq!(calls, 1);
+    assert!(delays.is_empty());
+}
+
+/// In-memory S3 holding a single bucket.
+#[derive(Clone, Default)]
+struct FakeS3(Arc<Mutex<FakeS3State>>);
+
+#[derive(Default)]
+struct FakeS3State {
+    objects: BTreeMap<String, (Vec<u8>, String)>,
+    tags: HashMap<String, Vec<Tag>>,
+    uploads: HashMap<String, (String, BTreeMap<i64, Vec<u8>>)>,
+    next_upload_id: usize,
+    aborted: Vec<String>,
+    /// Number of upload_part calls left to fail.
+    failing_parts: usize,
+    /// Keys returned per list_objects_v2 page.
+    page_size: usize,
+    /// Number of list_objects_v2 calls for pages after the first left to fail.
+    failing_pages: usize,
+    /// Continuation token of every list_objects_v2 call.
+    list_tokens: Vec<Option<String>>,
+    /// Fail listings with NoSuchBucket until the bucket is created.
+    missing_bucket: bool,
+    /// Give completed multipart uploads an ETag that doesn't match their parts.
+    corrupt_etags: bool,
+    /// `server_side_encryption` reported by head_object, like a bucket with default encryption.
+    server_side_encryption: Option<String>,
+    /// Never answer get_object_tagging.
+    stalled_tagging: bool,
+}
+
+fn injected_failure<E>() -> RusotoError<E> {
+    RusotoError::HttpDispatch(HttpDispatchError::new("injected failure".to_string()))
+}
+
+fn md5_etag(content: &[u8]) -> String {
+    format!("\"{:x}\"", md5::Md5::digest(content))
+}
+
+#[async_trait]
+impl S3Ops for FakeS3 {
+    async fn create_multipart_upload(
+        &self,
+        input: CreateMultipartUploadRequest,
+    ) -> Result<CreateMultipartUploadOutput, RusotoError<CreateMultipartUploadError>> {
+        let mut state = self.0.lock().unwrap();
+        state.next_upload_id += 1;
+        let upload_id = state.next_upload_id.to_string();
+        state
+            .uploads
+            .insert(upload_id.clone(), (input.key, BTreeMap::new()));
+        Ok(CreateMultipartUploadOutput {
+            upload_id: Some(upload_id),
+            ..Default::default()
+        })
+    }
+
+    async fn upload_part(
+        &self,
+        input: UploadPartRequest,
+    ) -> Result<UploadPartOutput, RusotoError<UploadPartError>> {
+        let body = input
+            .body
+            .unwrap()
+            .map_ok(|x| x.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        let mut state = self.0.lock().unwrap();
+        if state.failing_parts > 0 {
+            state.failing_parts -= 1;
+            return Err(injected_failure());
+        }
+        let e_tag = md5_etag(&body);
+        let (_, parts) = state.uploads.get_mut(&input.upload_id).unwrap();
+        parts.insert(input.part_number, body);
+        Ok(UploadPartOutput {
+            e_tag: Some(e_tag),
+            ..Default::default()
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        input: CompleteMultipartUploadRequest,
+    ) -> Result<CompleteMultipartUploadOutput, RusotoError<CompleteMultipartUploadError>> {
+        let mut state = self.0.lock().unwrap();
+        let (key, parts) = state.uploads.remove(&input.upload_id).unwrap();
+        let mut content = Vec::new();
+        let mut digests = Vec::new();
+        for part in input.multipart_upload.unwrap().parts.unwrap() {
+            let part = &parts[&part.part_number.unwrap()];
+            content.extend_from_slice(part);
+            digests.push(md5::Md5::digest(part).to_vec());
+        }
+        let e_tag = if state.corrupt_etags {
+            multipart_etag(&[])
+        } else {
+            multipart_etag(&digests)
+        };
+        state.objects.insert(key, (content, e_tag));
+        Ok(Default::default())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        input: AbortMultipartUploadRequest,
+    ) -> Result<AbortMultipartUploadOutput, RusotoError<AbortMultipartUploadError>> {
+        let mut state = self.0.lock().unwrap();
+        state.uploads.remove(&input.upload_id);
+        state.aborted.push(input.upload_id);
+        Ok(Default::default())
+    }
+
+    async fn list_multipart_uploads(
+        &self,
+        _input: ListMultipartUploadsRequest,
+    ) -> Result<ListMultipartUploadsOutput, RusotoError<ListMultipartUploadsError>> {
+        let state = self.0.lock().unwrap();
+        Ok(ListMultipartUploadsOutput {
+            uploads: Some(
+                state
+                    .uploads
+                    .iter()
+                    .map(|(upload_id, (key, _))| MultipartUpload {
+                        key: Some(key.clone()),
+                        upload_id: Some(upload_id.clone()),
+                        initiated: Some("2021-01-01T00:00:00Z".to_string()),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        })
+    }
+
+    async fn list_parts(
+        &self,
+        input: ListPartsRequest,
+    ) -> Result<ListPartsOutput, RusotoError<ListPartsError>> {
+        let state = self.0.lock().unwrap();
+        let (_, parts) = &state.uploads[&input.upload_id];
+        Ok(ListPartsOutput {
+            parts: Some(
+                parts
+                    .iter()
+                    .map(|(part_number, content)| Part {
+                        part_number: Some(*part_number),
+                        e_tag: Some(md5_etag(content)),
+                        size: Some(content.len() as i64),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        })
+    }
+
+    async fn list_objects_v2(
+        &self,
+        input: ListObjectsV2Request,
+    ) -> Result<ListObjectsV2Output, RusotoError<ListObjectsV2Error>> {
+        let mut state = self.0.lock().unwrap();
+        state.list_tokens.push(input.continuation_token.clone());
+        if state.missing_bucket {
+            return Err(RusotoError::Service(ListObjectsV2Error::NoSuchBucket(
+                input.bucket,
+            )));
+        }
+        if input.continuation_token.is_some() && state.failing_pages > 0 {
+            state.failing_pages -= 1;
+            return Err(injected_failure());
+        }
+        let page_size = state.page_size.max(1);
+        let start: usize = input.continuation_token.map_or(0, |x| x.parse().unwrap());
+        let end = (start + page_size).min(state.objects.len());
+        Ok(ListObjectsV2Output {
+            contents: Some(
+                state
+                    .objects
+                    .iter()
+                    .skip(start)
+                    .take(end - start)
+                    .map(|(key, (content, e_tag))| Object {
+                        key: Some(key.clone()),
+                        e_tag: Some(e_tag.clone()),
+                        size: Some(content.len() as i64),
+                        ..Default::default()
+                    })
+                    .collect(),
+            ),
+            is_truncated: Some(end < state.objects.len()),
+            next_continuation_token: Some(end.to_string()),
+            ..Default::default()
+        })
+    }
+
+    async fn head_object(
+        &self,
+        input: HeadObjectRequest,
+    ) -> Result<HeadObjectOutput, RusotoError<HeadObjectError>> {
+        let state = self.0.lock().unwrap();
+        let (content, e_tag) = &state.objects[&input.key];
+        Ok(HeadObjectOutput {
+            e_tag: Some(e_tag.clone()),
+            content_length: Some(content.len() as i64),
+            server_side_encryption: state.server_side_encryption.clone(),
+            ..Default::default()
+        })
+    }
+
+    async fn get_object_tagging(
+        &self,
+        input: GetObjectTaggingRequest,
+    ) -> Result<GetObjectTaggingOutput, RusotoError<GetObjectTaggingError>> {
+        if self.0.lock().unwrap().stalled_tagging {
+            return future::pending().await;
+        }
+        let state = self.0.lock().unwrap();
+        Ok(GetObjectTaggingOutput {
+            tag_set: state.tags.get(&input.key).cloned().unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn put_object_tagging(
+        &self,
+        input: PutObjectTaggingRequest,
+    ) -> Result<PutObjectTaggingOutput, RusotoError<PutObjectTaggingError>> {
+        let mut state = self.0.lock().unwrap();
+        state.tags.insert(input.key, input.tagging.tag_set);
+        Ok(Default::default())
+    }
+
+    async fn get_object(
+        &self,
+        input: GetObjectRequest,
+    ) -> Result<GetObjectOutput, RusotoError<GetObjectError>> {
+        let state = self.0.lock().unwrap();
+        match state.objects.get(&input.key) {
+            Some((content, e_tag)) => Ok(GetObjectOutput {
+                body: Some(ByteStream::from(content.clone())),
+                e_tag: Some(e_tag.clone()),
+                ..Default::default()
+            }),
+            None => Err(RusotoError::Service(GetObjectError::NoSuchKey(input.key))),
+        }
+    }
+
+    async fn create_bucket(
+        &self,
+        _input: CreateBucketRequest,
+    ) -> Result<CreateBucketOutput, RusotoError<CreateBucketError>> {
+        self.0.lock().unwrap().missing_bucket = false;
+        Ok(Default::default())
+    }
+
+    async fn put_object(
+        &self,
+        input: PutObjectRequest,
+    ) -> Result<PutObjectOutput, RusotoError<PutObjectError>> {
+        let body = input
+            .body
+            .unwrap()
+            .map_ok(|x| x.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        let e_tag = md5_etag(&body);
+        let mut state = self.0.lock().unwrap();
+        state.objects.insert(input.key, (body, e_tag));
+        Ok(Default::default())
+    }
+
+    async fn delete_object(
+        &self,
+        input: DeleteObjectRequest,
+    ) -> Result<DeleteObjectOutput, RusotoError<DeleteObjectError>> {
+        let mut state = self.0.lock().unwrap();
+        state.objects.remove(&input.key);
+        state.tags.remove(&input.key);
+        Ok(Default::default())
+    }
+}
+
+struct FakeCommand {
+    output: Vec<u8>,
+    exit_code: i32,
+}
+
+impl CommandStreamActions<Cursor<Vec<u8>>> for FakeCommand {
+    fn stdout(&mut self) -> Cursor<Vec<u8>> {
+        Cursor::new(std::mem::take(&mut self.output))
+    }
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        Ok(ExitStatus::from_raw(self.exit_code << 8))
+    }
+}
+
+async fn fake_upload(s3: &FakeS3, exit_code: i32, max_retries: u32) -> Result<u64, S3Error> {
+    fake_upload_with(
+        s3,
+        exit_code,
+        &UploadOptions {
+            max_retries,
+            concurrency: 2,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+async fn fake_upload_with(
+    s3: &FakeS3,
+    exit_code: i32,
+    options: &UploadOptions,
+) -> Result<u64, S3Error> {
+    upload_stdout_internal(
+        s3,
+        Box::new(FakeCommand {
+            output: b"0123456789".to_vec(),
+            exit_code,
+        }),
+        UploadTarget {
+            bucket: "bucket",
+            key: "key",
+            tags: vec![],
+            storage_class: StorageClass::STANDARD,
+        },
+        options,
+        |_| {},
+        4,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    assert_eq!(fake_upload(&s3, 0, 0).await?, 10);
+    let state = s3.0.lock().unwrap();
+    assert_eq!(state.objects["key"].0, b"0123456789");
+    assert!(state.uploads.is_empty());
+    let tags: Vec<&str> = state.tags["key"].iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(tags, vec!["buffer_size", "content_sha256"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_retries_part() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().failing_parts = 1;
+    let options = UploadOptions {
+        max_retries: 1,
+        concurrency: 2,
+        ..Default::default()
+    };
+    assert_eq!(fake_upload_with(&s3, 0, &options).await?, 10);
+    assert_eq!(s3.0.lock().unwrap().objects["key"].0, b"0123456789");
+    assert_eq!(options.retries.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_aborts_on_failed_part() {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().failing_parts = 1;
+    let r = fake_upload(&s3, 0, 0).await;
+    assert!(matches!(r, Err(S3Error::UploadPartFailed(_))));
+    let state = s3.0.lock().unwrap();
+    assert_eq!(state.aborted, vec!["1"]);
+    assert!(state.uploads.is_empty());
+    assert!(state.objects.is_empty());
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_aborts_on_command_failure() {
+    let s3 = FakeS3::default();
+    let r = fake_upload(&s3, 1, 0).await;
+    assert!(matches!(r, Err(S3Error::CommandExited { .. })));
+    let state = s3.0.lock().unwrap();
+    assert_eq!(state.aborted, vec!["1"]);
+    assert!(state.objects.is_empty());
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_deletes_on_etag_mismatch() {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().corrupt_etags = true;
+    let r = fake_upload(&s3, 0, 0).await;
+    assert!(matches!(r, Err(S3Error::ETagMismatch { .. })));
+    let state = s3.0.lock().unwrap();
+    assert!(state.objects.is_empty());
+    assert!(state.tags.is_empty());
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_keeps_kms_encrypted_object() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    {
+        let mut state = s3.0.lock().unwrap();
+        // KMS ETags aren't MD5s, they never match the expected one.
+        state.corrupt_etags = true;
+        state.server_side_encryption = Some("aws:kms".to_string());
+    }
+    fake_upload(&s3, 0, 0).await?;
+    assert!(s3.0.lock().unwrap().objects.contains_key("key"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_all_files_fake_s3_pages() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    {
+        let mut state = s3.0.lock().unwrap();
+        state.page_size = 2;
+        for key in &["a", "b", "c", "d", "e"] {
+            state
+                .objects
+                .insert(key.to_string(), (vec![0; 3], md5_etag(&[0; 3])));
+        }
+    }
+    let mut keys: Vec<String> = get_all_files(&s3, "bucket", DEFAULT_MAX_RETRIES, None)
+        .await?
+        .into_iter()
+        .map(|x| x.key)
+        .collect();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "b", "c", "d", "e"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_all_files_fake_s3_retries_page() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    {
+        let mut state = s3.0.lock().unwrap();
+        state.page_size = 2;
+        state.failing_pages = 1;
+        for key in &["a", "b", "c"] {
+            state
+                .objects
+                .insert(key.to_string(), (vec![0; 3], md5_etag(&[0; 3])));
+        }
+    }
+    assert_eq!(
+        get_all_files(&s3, "bucket", DEFAULT_MAX_RETRIES, None)
+            .await?
+            .len(),
+        3
+    );
+    // The failed second page is requested again with its token, not from the start.
+    assert_eq!(
+        s3.0.lock().unwrap().list_tokens,
+        vec![None, Some("2".to_string()), Some("2".to_string())]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_all_files_fake_s3_max_retries() {
+    let s3 = FakeS3::default();
+    {
+        let mut state = s3.0.lock().unwrap();
+        state.page_size = 2;
+        state.failing_pages = 1;
+        for key in &["a", "b", "c"] {
+            state
+                .objects
+                .insert(key.to_string(), (vec![0; 3], md5_etag(&[0; 3])));
+        }
+    }
+    assert!(get_all_files(&s3, "bucket", 0, None).await.is_err());
+    assert_eq!(
+        s3.0.lock().unwrap().list_tokens,
+        vec![None, Some("2".to_string())]
+    );
+}
+
+#[test]
+fn test_merge_tags() {
+    let tag = |key: &str, value: &str| Tag {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    let current: HashMap<String, String> = vec![
+        ("parent".to_string(), "full".to_string()),
+        ("CostCenter".to_string(), "old".to_string()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(merge_tags(&current, &[tag("CostCenter", "old")]), None);
+    assert_eq!(
+        merge_tags(
+            &current,
+            &[tag("Environment", "prod"), tag("CostCenter", "backups")]
+        ),
+        Some(vec![
+            tag("CostCenter", "backups"),
+            tag("Environment", "prod"),
+            tag("parent", "full"),
+        ])
+    );
+}
+
+fn backup(name: &str, creation: i64, parent: Option<&S3Backup>) -> S3Backup {
+    S3Backup {
+        snapshot: ZfsSnapshot {
+            name: name.to_string(),
+            creation: Local.timestamp(creation, 0),
+        },
+        parent: parent.map(|x| x.snapshot.name.clone()),
+        parent_key: parent.map(|x| x.key()),
+        storage_class: StorageClass::DeepArchive,
+        bucket: "bucket".to_string(),
+        zfs_command: "zfs".to_string(),
+        send_flags: "w".to_string(),
+        key_prefix: "host/".to_string(),
+        key_template: KeyTemplate::default(),
+        tier: 0,
+    }
+}
+
+#[tokio::test]
+async fn test_record_backup_fake_s3() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    let full = backup("tank/data@monthly", 1600000000, None);
+    let incremental = backup("tank/data@daily", 1600086400, Some(&full));
+    assert_eq!(
+        get_manifest(&s3, "bucket", &manifest_key("host/", "tank/data")).await?,
+        None
+    );
+    record_backup(
+        &s3,
+        &incremental,
+        StorageClass::StandardInfrequentAccess,
+        10,
+    )
+    .await?;
+    record_backup(&s3, &full, StorageClass::DeepArchive, 100).await?;
+    record_backup(
+        &s3,
+        &incremental,
+        StorageClass::StandardInfrequentAccess,
+        20,
+    )
+    .await?;
+
+    let manifest = get_manifest(&s3, "bucket", "host/manifest/tank/data.json")
+        .await?
+        .unwrap();
+    assert_eq!(manifest.dataset, "tank/data");
+    let sizes: Vec<u64> = manifest.backups.iter().map(|x| x.size).collect();
+    assert_eq!(sizes, vec![100, 20]);
+    let plan = get_restore_plan("bucket", &manifest.remote_backups(), "tank/data", None)?;
+    let keys: Vec<String> = plan.into_iter().map(|x| x.key).collect();
+    assert_eq!(keys, vec![full.key(), incremental.key()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_all_files_fake_s3_missing_bucket() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().missing_bucket = true;
+    let err = get_all_files(&s3, "bucket", DEFAULT_MAX_RETRIES, None)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BucketError>(),
+        Some(&BucketError::Missing("bucket".to_string()))
+    );
+    // Not retried, the bucket won't appear by waiting.
+    assert_eq!(s3.0.lock().unwrap().list_tokens.len(), 1);
+
+    create_bucket(&s3, "bucket", &Region::EuWest3).await?;
+    assert!(get_all_files(&s3, "bucket", DEFAULT_MAX_RETRIES, None)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_bucket_fake_s3() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().missing_bucket = true;
+    let err = check_bucket(&s3, "bucket", None).await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<BucketError>(),
+        Some(&BucketError::Missing("bucket".to_string()))
+    );
+
+    create_bucket(&s3, "bucket", &Region::EuWest3).await?;
+    check_bucket(&s3, "bucket", None).await?;
+    assert_eq!(s3.0.lock().unwrap().list_tokens, vec![None, None]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_fake_s3_reports_progress_within_part() -> Result<(), Box<dyn Error>> {
+    let s3 = FakeS3::default();
+    let callbacks = Cell::new(0);
+    let last = Cell::new(UploadProgress::default());
+    let r = upload_stdout_internal(
+        &s3,
+        Box::new(FakeCommand {
+            output: vec![0; 3 * MIB],
+            exit_code: 0,
+        }),
+        UploadTarget {
+            bucket: "bucket",
+            key: "key",
+            tags: vec![],
+            storage_class: StorageClass::STANDARD,
+        },
+        &UploadOptions::default(),
+        |progress| {
+            callbacks.set(callbacks.get() + 1);
+            last.set(progress);
+        },
+        8 * MIB,
+    )
+    .await?;
+    assert_eq!(r, 3 * MIB as u64);
+    // A single part, read in 1MiB chunks.
+    assert!(callbacks.get() >= 3);
+    assert_eq!(last.get().bytes_read, 3 * MIB as u64);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_object_tags_fake_s3_times_out() {
+    let s3 = FakeS3::default();
+    s3.0.lock().unwrap().stalled_tagging = true;
+    let err = get_object_tags(&s3, "bucket", "key", Some(Duration::from_millis(10)))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{}", err);
+}
@@ -0,0 +1,17 @@
+use std::{env, fs};
+use zfs_to_glacier::sync_lock::{LockError, SyncLock};
+
+#[test]
+fn test_lock_is_exclusive() -> Result<(), LockError> {
+    let path = env::temp_dir().join(format!("zfs_glacier_lock_{}", std::process::id()));
+    let lock = SyncLock::acquire(&path, false)?;
+    assert!(matches!(
+        SyncLock::acquire(&path, false),
+        Err(LockError::Held { .. })
+    ));
+    drop(lock);
+    let lock = SyncLock::acquire(&path, true)?;
+    drop(lock);
+    fs::remove_file(&path).unwrap();
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use zfs_to_glacier::systemd::{create_service, generate_systemd, SERVICE_FILE, TIMER_FILE};
+
+#[test]
+fn test_service_runs_sync() {
+    let service = create_service(
+        Path::new("/usr/local/bin/zfs_to_glacier"),
+        Path::new("/etc/zfs_to_glacier"),
+    );
+    assert!(service.contains("WorkingDirectory=/etc/zfs_to_glacier\n"));
+    assert!(service.contains("ExecStart=/usr/local/bin/zfs_to_glacier sync\n"));
+}
+
+#[test]
+fn test_generate_systemd_existing_file() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("systemd_test_{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let executable = Path::new("/usr/local/bin/zfs_to_glacier");
+    fs::write(dir.join(TIMER_FILE), "existing")?;
+    assert!(generate_systemd(executable, &dir, &dir, false).is_err());
+    assert_eq!(fs::read_to_string(dir.join(TIMER_FILE))?, "existing");
+    assert!(!dir.join(SERVICE_FILE).exists());
+
+    generate_systemd(executable, &dir, &dir, true)?;
+    assert!(fs::read_to_string(dir.join(TIMER_FILE))?.contains("OnCalendar=daily"));
+    assert_eq!(
+        fs::read_to_string(dir.join(SERVICE_FILE))?,
+        create_service(executable, &dir)
+    );
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,14 @@
+use std::time::{Duration, Instant};
+use zfs_to_glacier::throttle::Throttle;
+
+#[tokio::test]
+async fn test_throttle_limits_rate() {
+    let throttle = Throttle::new(1000);
+    let start = Instant::now();
+    for _ in 0..4 {
+        throttle.acquire(100).await;
+    }
+    // The first acquire is free, the remaining three wait 100ms each.
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(start.elapsed() < Duration::from_millis(1000));
+}
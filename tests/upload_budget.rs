@@ -0,0 +1,39 @@
+use zfs_to_glacier::upload_budget::*;
+
+#[test]
+fn test_parse_byte_size() {
+    assert_eq!(parse_byte_size("1000"), Ok(1000));
+    assert_eq!(parse_byte_size("2k"), Ok(2048));
+    assert_eq!(parse_byte_size("500G"), Ok(500 * 1024 * 1024 * 1024));
+    assert_eq!(parse_byte_size("1T"), Ok(1024 * 1024 * 1024 * 1024));
+    assert_eq!(
+        parse_byte_size("5P"),
+        Err(InvalidSizeError("5P".to_string()))
+    );
+    assert!(parse_byte_size("G").is_err());
+    assert!(parse_byte_size("-1G").is_err());
+    assert!(parse_byte_size("99999999999T").is_err());
+}
+
+#[test]
+fn test_budget_stops_at_first_upload_that_does_not_fit() {
+    let budget = UploadBudget::new(100);
+    assert!(budget.reserve(Some(60)));
+    // Uploaded less than estimated, the difference is available again.
+    budget.settle(Some(60), Some(50));
+    assert!(budget.reserve(Some(40)));
+    budget.settle(Some(40), Some(40));
+    assert!(!budget.is_exhausted());
+    assert!(!budget.reserve(Some(20)));
+    assert!(budget.is_exhausted());
+    // A smaller upload would fit, but isn't started after a larger one was held back.
+    assert!(!budget.reserve(Some(5)));
+}
+
+#[test]
+fn test_budget_without_estimates() {
+    let budget = UploadBudget::new(100);
+    assert!(budget.reserve(None));
+    budget.settle(None, Some(150));
+    assert!(!budget.reserve(None));
+}
@@ -0,0 +1,53 @@
+use std::{env, error::Error, fs, time::Duration};
+use zfs_to_glacier::upload_speed::UploadSpeed;
+
+const MIB: u64 = 1024 * 1024;
+
+#[test]
+fn test_upload_speed_average() -> Result<(), Box<dyn Error>> {
+    let mut speed = UploadSpeed::default();
+    assert_eq!(speed.eta(0, 100 * MIB, Duration::from_secs(0)), None);
+
+    // Too short to say anything about the throughput.
+    speed.record(MIB, Duration::from_secs(1));
+    assert_eq!(speed.bytes_per_sec(), None);
+
+    speed.record(100 * MIB, Duration::from_secs(100));
+    assert_eq!(speed.bytes_per_sec(), Some(MIB as f64));
+    speed.record(300 * MIB, Duration::from_secs(100));
+    assert!((speed.bytes_per_sec().unwrap() - 1.6 * MIB as f64).abs() < 1.0);
+
+    let path = env::temp_dir().join(format!(
+        "zfs_glacier_upload_speed_{}.json",
+        std::process::id()
+    ));
+    speed.save(&path)?;
+    let loaded = UploadSpeed::load(&path);
+    fs::remove_file(&path)?;
+    assert_eq!(loaded, speed);
+    Ok(())
+}
+
+#[test]
+fn test_upload_speed_eta() {
+    let mut speed = UploadSpeed::default();
+    // Without earlier uploads only the current rate counts.
+    assert_eq!(
+        speed.eta(10 * MIB, 100 * MIB, Duration::from_secs(10)),
+        Some(Duration::from_secs(100))
+    );
+
+    speed.record(200 * MIB, Duration::from_secs(100));
+    // At the start of an upload the average of earlier uploads is used as is...
+    assert_eq!(
+        speed.eta(0, 100 * MIB, Duration::from_secs(0)),
+        Some(Duration::from_secs(50))
+    );
+    let eta = speed.eta(MIB, 100 * MIB, Duration::from_secs(1)).unwrap();
+    assert!(eta > Duration::from_secs(50) && eta < Duration::from_secs(51));
+    // ...and after a minute only the upload's own rate.
+    assert_eq!(
+        speed.eta(60 * MIB, 100 * MIB, Duration::from_secs(60)),
+        Some(Duration::from_secs(100))
+    );
+}
@@ -1,7 +1,6 @@
-use std::{error::Error};
+use std::error::Error;
 use zfs_to_glacier::cmd_execute::*;
 
-
 #[test]
 fn test_execute() -> Result<(), Box<dyn Error>> {
     let result = ExecutorCommand("echo -n teststring".to_string()).execute()?;
@@ -10,7 +9,75 @@ fn test_execute() -> Result<(), Box<dyn Error>> {
 }
 #[test]
 fn test_execute_multiline() -> Result<(), Box<dyn Error>> {
-    let result = ExecutorCommand("echo -e teststring \n test".to_string()).execute_by_line()?;
+    let result = ExecutorCommand("echo -e 'teststring \\n test'".to_string()).execute_by_line()?;
     assert_eq!(result, vec!["teststring", "test"]);
     Ok(())
 }
+
+#[test]
+fn test_execute_quoted_arguments() -> Result<(), Box<dyn Error>> {
+    let result = ExecutorCommand("echo -n 'a  b' \"c d\"".to_string()).execute()?;
+    assert_eq!(result, "a  b c d");
+    Ok(())
+}
+
+#[test]
+fn test_arguments() {
+    assert_eq!(
+        ExecutorCommand("ssh \"user@host\" zfs send -Pw pool/data@snap".to_string()).arguments(),
+        Some(
+            vec!["ssh", "user@host", "zfs", "send", "-Pw", "pool/data@snap"]
+                .into_iter()
+                .map(String::from)
+                .collect()
+        )
+    );
+    assert_eq!(
+        ExecutorCommand("echo 'with space' escaped\\ space".to_string()).arguments(),
+        Some(vec![
+            "echo".to_string(),
+            "with space".to_string(),
+            "escaped space".to_string()
+        ])
+    );
+}
+
+#[test]
+fn test_execute_unterminated_quote() {
+    assert!(ExecutorCommand("echo 'unterminated".to_string())
+        .execute()
+        .is_err());
+}
+
+#[test]
+fn test_execute_error_includes_stderr() {
+    let err = ExecutorCommand("ls /nonexistent_zfs_to_glacier_path".to_string())
+        .execute()
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("nonexistent_zfs_to_glacier_path"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_spawned_command_stderr() -> Result<(), Box<dyn Error>> {
+    let mut command = ExecutorCommand("ls /nonexistent_zfs_to_glacier_path".to_string()).spawn()?;
+    command.stdout();
+    assert!(!command.wait()?.success());
+    assert!(command
+        .stderr()
+        .unwrap()
+        .contains("nonexistent_zfs_to_glacier_path"));
+    Ok(())
+}
+
+#[test]
+fn test_stderr_tail() {
+    let stderr: String = (1..=15).map(|i| format!("line {}\n", i)).collect();
+    let tail = stderr_tail(&stderr);
+    assert_eq!(tail.lines().count(), STDERR_TAIL_LINES);
+    assert!(tail.starts_with("line 6\n"));
+    assert!(tail.ends_with("line 15"));
+}
@@ -0,0 +1,56 @@
+use chrono::{Local, TimeZone};
+use zfs_to_glacier::zfs_utils::*;
+
+#[test]
+fn test_parse_list_line() {
+    let snapshot = parse_list_line("rpool/data@daily\t1609459200").unwrap();
+    assert_eq!(snapshot.name, "rpool/data@daily");
+    assert_eq!(
+        snapshot.creation,
+        Local.timestamp_opt(1609459200, 0).unwrap()
+    );
+}
+
+#[test]
+fn test_parse_list_line_malformed() {
+    for line in &[
+        "cannot open 'rpool/missing': dataset does not exist",
+        "rpool/data@daily\tnot a number",
+        "rpool/data@daily\t1609459200\textra",
+        "\t1609459200",
+    ] {
+        let err = parse_list_line(line).unwrap_err();
+        assert_eq!(err.line, *line);
+        assert!(err.to_string().contains(line));
+    }
+}
+
+#[test]
+fn test_parse_encryption_line() {
+    assert_eq!(
+        parse_encryption_line("rpool/secret\taes-256-gcm"),
+        Ok(("rpool/secret".to_string(), true))
+    );
+    assert_eq!(
+        parse_encryption_line("rpool/data\toff"),
+        Ok(("rpool/data".to_string(), false))
+    );
+    assert_eq!(
+        parse_encryption_line("rpool/snapshots\t-"),
+        Ok(("rpool/snapshots".to_string(), false))
+    );
+    assert!(parse_encryption_line("rpool/data").is_err());
+}
+
+#[test]
+fn test_check_zfs_available() {
+    assert!(check_zfs_available("echo").is_ok());
+    assert!(matches!(
+        check_zfs_available("zfs_to_glacier_nonexistent_command"),
+        Err(ZfsUnavailableError::NotFound { .. })
+    ));
+    assert!(matches!(
+        check_zfs_available("false"),
+        Err(ZfsUnavailableError::Failed { .. })
+    ));
+}